@@ -0,0 +1,54 @@
+//! Benchmarks of the code which runs for every output frame. The crate is a
+//! binary, so the modules are included directly, which also keeps the SPI bus
+//! out of the benchmarks.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+#[allow(dead_code)]
+#[path = "../src/apa102.rs"]
+mod apa102;
+#[allow(dead_code)]
+#[path = "../src/display.rs"]
+mod display;
+#[allow(dead_code)]
+#[path = "../src/transform.rs"]
+mod transform;
+
+use apa102::{Apa102, ChipType, ColorOrder, ARGB8};
+use transform::Transform;
+
+/// A frame with every channel varying, so nothing is trivially constant.
+fn frame(len: usize) -> Vec<ARGB8> {
+    (0..len)
+        .map(|i| ARGB8::new((i % 32) as u8, i as u8, (3 * i) as u8, (7 * i) as u8))
+        .collect()
+}
+
+fn apa102_update(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Apa102::update");
+    for &len in &[144u16, 300, 600] {
+        let mut leds = Apa102::new(len, ChipType::Apa102, ColorOrder::Bgr);
+        let frame = frame(len as usize);
+        group.bench_with_input(BenchmarkId::from_parameter(len), &frame, |b, frame| {
+            b.iter(|| leds.update(black_box(frame)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn transform_apply(c: &mut Criterion) {
+    let transform = Transform::new(
+        vec![144; 4],
+        vec![false, true, false, true],
+        vec![0, 2, 1, 3],
+    );
+    let frame = frame(4 * 144);
+    c.bench_function("Transform::apply 4x144", |b| {
+        b.iter(|| transform.apply(black_box(&frame)))
+    });
+    c.bench_function("Transform::apply_iter 4x144", |b| {
+        b.iter(|| transform.apply_iter(black_box(&frame)).count())
+    });
+}
+
+criterion_group!(benches, apa102_update, transform_apply);
+criterion_main!(benches);
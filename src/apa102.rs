@@ -1,4 +1,4 @@
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct ARGB8 {
     pub a: u8,
     pub r: u8,
@@ -10,41 +10,746 @@ impl ARGB8 {
     pub fn new(a: u8, r: u8, g: u8, b: u8) -> Self {
         Self { a, r, g, b }
     }
+
+    /// Color with the 5 bit global brightness folded into each channel.
+    pub fn scaled_rgb(self) -> (u8, u8, u8) {
+        let a = u8::min(self.a, 31) as u16;
+        let scale = |x: u8| ((x as u16 * a + 15) / 31) as u8;
+        (scale(self.r), scale(self.g), scale(self.b))
+    }
+}
+
+/// Pixel with floating point color channels in the range 0.0 to 255.0.
+#[derive(Copy, Clone, Debug)]
+pub struct ARGB8F {
+    pub a: u8,
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+}
+
+impl ARGB8F {
+    pub fn new(a: u8, r: f32, g: f32, b: f32) -> Self {
+        Self { a, r, g, b }
+    }
+}
+
+impl From<ARGB8> for ARGB8F {
+    fn from(c: ARGB8) -> Self {
+        Self::new(c.a, c.r as f32, c.g as f32, c.b as f32)
+    }
+}
+
+/// Lookup table mapping linear 8 bit values through the given gamma curve.
+pub fn gamma_table(gamma: f64) -> [u8; 256] {
+    let mut lut = [0u8; 256];
+    for (i, v) in lut.iter_mut().enumerate() {
+        *v = (255. * f64::powf(i as f64 / 255., gamma) + 0.5) as u8;
+    }
+    lut
+}
+
+/// Errors returned when updating the LED buffer.
+#[derive(Debug, PartialEq)]
+pub enum Apa102Error {
+    LengthMismatch { expected: usize, got: usize },
+}
+
+impl std::fmt::Display for Apa102Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Apa102Error::LengthMismatch { expected, got } => write!(
+                f,
+                "frame length mismatch: expected {} pixels, got {}",
+                expected, got
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Apa102Error {}
+
+/// An LED strip protocol which encodes frames into a buffer for the SPI bus.
+pub trait Strip {
+    fn get_buffer(&self) -> &[u8];
+
+    /// Update from a stream of pixels, encoding each straight into the buffer.
+    fn update_iter(&mut self, iter: &mut dyn Iterator<Item = ARGB8>) -> Result<(), Apa102Error>;
+
+    fn update(&mut self, frame: &[ARGB8]) -> Result<(), Apa102Error> {
+        self.update_iter(&mut frame.iter().copied())
+    }
+
+    /// Scaling applied to the last frame to keep within a power budget.
+    fn power_scale(&self) -> f32 {
+        1.0
+    }
+}
+
+/// Chipset variant, which determines how the end frame is latched.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ChipType {
+    Apa102,
+    Sk9822,
+}
+
+/// Order the color bytes are sent in after the global brightness byte.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColorOrder {
+    Rgb,
+    Rbg,
+    Grb,
+    Gbr,
+    Brg,
+    Bgr,
+}
+
+impl ColorOrder {
+    /// Byte offsets of the red, green and blue channels within an LED frame.
+    pub fn offsets(self) -> [usize; 3] {
+        match self {
+            ColorOrder::Rgb => [1, 2, 3],
+            ColorOrder::Rbg => [1, 3, 2],
+            ColorOrder::Grb => [2, 1, 3],
+            ColorOrder::Gbr => [3, 1, 2],
+            ColorOrder::Brg => [2, 3, 1],
+            ColorOrder::Bgr => [3, 2, 1],
+        }
+    }
+}
+
+impl std::str::FromStr for ColorOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rgb" => Ok(ColorOrder::Rgb),
+            "rbg" => Ok(ColorOrder::Rbg),
+            "grb" => Ok(ColorOrder::Grb),
+            "gbr" => Ok(ColorOrder::Gbr),
+            "brg" => Ok(ColorOrder::Brg),
+            "bgr" => Ok(ColorOrder::Bgr),
+            _ => Err(format!("unknown color order: {}", s)),
+        }
+    }
+}
+
+impl Default for ColorOrder {
+    fn default() -> Self {
+        ColorOrder::Bgr
+    }
 }
 
 /// Apa102 LED strip buffer
 pub struct Apa102 {
     length: usize,
     buffer: Vec<u8>,
+    // the next frame is written here, and swapped in once it's complete
+    staging: Vec<u8>,
+    order: [usize; 3],
+    // red, green and blue gamma tables, and the gammas they were made with
+    gamma: [[u8; 256]; 3],
+    gamma_exp: [f32; 3],
+    brightness: u16,
+    dithering: bool,
+    error: Vec<[f32; 3]>,
+    staging_error: Vec<[f32; 3]>,
+    power_limit: Option<u32>,
+    channel_milliamps: f32,
+    power_scale: f32,
 }
 
 impl Apa102 {
+    const FULL_BRIGHTNESS: u16 = 256;
+    /// Estimated current drawn by one color channel at full brightness.
+    const CHANNEL_MILLIAMPS: f32 = 20.0;
+
     /// Create a new Apa102 driver with the given length and SPI bus.
-    pub fn new(length: u16) -> Self {
-        let end_frame = (6 + length / 16) as usize;
-        let led_frame = (4 * (length + 1)) as usize;
-        let buffer_size = led_frame + end_frame;
-        let mut buffer = vec![0u8; buffer_size];
-        buffer[led_frame] = 0xff;
+    pub fn new(length: u16, chip: ChipType, order: ColorOrder) -> Self {
+        Self::with_gamma(length, chip, order, [1.0; 3])
+    }
+
+    /// Create a new Apa102 driver which gamma corrects the red, green and
+    /// blue channels by their own gammas.
+    pub fn with_gamma(length: u16, chip: ChipType, order: ColorOrder, gamma: [f64; 3]) -> Self {
+        let led_frame = Self::start_frame_len() + 4 * length as usize;
+        let mut buffer = vec![0u8; Self::buffer_len(length as usize, chip)];
+        if chip == ChipType::Apa102 {
+            // the end frame leads with a set byte, see `end_frame_len`
+            buffer[led_frame] = 0xff;
+        }
         Self {
             length: length as usize,
+            staging: buffer.clone(),
             buffer,
+            order: order.offsets(),
+            gamma: [
+                gamma_table(gamma[0]),
+                gamma_table(gamma[1]),
+                gamma_table(gamma[2]),
+            ],
+            gamma_exp: [gamma[0] as f32, gamma[1] as f32, gamma[2] as f32],
+            brightness: Self::FULL_BRIGHTNESS,
+            dithering: false,
+            error: vec![[0.; 3]; length as usize],
+            staging_error: vec![[0.; 3]; length as usize],
+            power_limit: None,
+            channel_milliamps: Self::CHANNEL_MILLIAMPS,
+            power_scale: 1.0,
+        }
+    }
+
+    /// Bytes of zeros sent before the LED frames.
+    pub fn start_frame_len() -> usize {
+        4
+    }
+
+    /// Bytes sent after the LED frames of a strip of `length` LEDs to clock
+    /// the data through to its end.
+    pub fn end_frame_len(length: usize, chip: ChipType) -> usize {
+        match chip {
+            // half a clock per LED, with the leading byte set so the first
+            // pulses can't be mistaken for the start of another LED frame
+            ChipType::Apa102 => 6 + length / 16,
+            // a 32 bit reset frame of zeros followed by the same half clock
+            // per LED needed to push data through to the end of the strip
+            ChipType::Sk9822 => 4 + 1 + length / 16,
+        }
+    }
+
+    /// Bytes in a whole transfer to a strip of `length` LEDs.
+    pub fn buffer_len(length: usize, chip: ChipType) -> usize {
+        Self::start_frame_len() + 4 * length + Self::end_frame_len(length, chip)
+    }
+
+    /// Set the master brightness from 0.0 to 1.0, which scales the global
+    /// brightness field of every LED.
+    pub fn set_brightness(&mut self, level: f32) {
+        let level = f32::max(f32::min(level, 1.0), 0.0);
+        self.brightness = (level * Self::FULL_BRIGHTNESS as f32 + 0.5) as u16;
+    }
+
+    /// Enable temporal dithering of the color channels written by `update_f32`,
+    /// which `update` also goes through while it's enabled so the fractions
+    /// left by the gamma curve aren't lost.
+    pub fn enable_dithering(&mut self, enabled: bool) {
+        self.dithering = enabled;
+        for e in self.error.iter_mut().chain(self.staging_error.iter_mut()) {
+            *e = [0.; 3];
+        }
+    }
+
+    /// Limit the estimated current drawn by the strip, scaling every pixel
+    /// down uniformly when a frame would exceed the budget.
+    pub fn set_power_limit(&mut self, max_milliamps: Option<u32>) {
+        self.power_limit = max_milliamps;
+    }
+
+    /// Set the estimated current of one color channel at full brightness.
+    pub fn set_channel_milliamps(&mut self, milliamps: f32) {
+        self.channel_milliamps = milliamps;
+    }
+
+    /// The scaling applied to the last frame by the power limit.
+    pub fn power_scale(&self) -> f32 {
+        self.power_scale
+    }
+
+    fn limit_power(&mut self) {
+        self.power_scale = 1.0;
+        let limit = match self.power_limit {
+            Some(limit) => limit as f32,
+            None => return,
+        };
+        let leds = &mut self.buffer[4..4 * (1 + self.length)];
+        let mut total = 0.;
+        for led in leds.chunks(4) {
+            let a = (led[0] & 0x1f) as f32 / 31.;
+            let rgb = led[1] as u32 + led[2] as u32 + led[3] as u32;
+            total += a * rgb as f32 / 255.;
+        }
+        let total = total * self.channel_milliamps;
+        if total <= limit {
+            return;
+        }
+        let scale = limit / total;
+        for led in leds.chunks_mut(4) {
+            for c in led[1..].iter_mut() {
+                *c = (*c as f32 * scale) as u8;
+            }
+        }
+        self.power_scale = scale;
+    }
+
+    fn global(&self, a: u8) -> u8 {
+        let a = u8::min(a, 31);
+        if self.brightness == Self::FULL_BRIGHTNESS {
+            a
+        } else {
+            ((a as u16 * self.brightness) >> 8) as u8
+        }
+    }
+
+    fn check_length(&self, got: usize) -> Result<(), Apa102Error> {
+        if got != self.length {
+            return Err(Apa102Error::LengthMismatch {
+                expected: self.length,
+                got,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn update(&mut self, frame: &[ARGB8]) -> Result<(), Apa102Error> {
+        self.update_from_iter(frame.iter().copied())
+    }
+
+    /// Update from a stream of pixels, so a frame needn't be collected first.
+    /// The buffer is left as it was unless there's exactly one pixel per LED.
+    pub fn update_from_iter<I: Iterator<Item = ARGB8>>(
+        &mut self,
+        mut iter: I,
+    ) -> Result<(), Apa102Error> {
+        let mut got = 0;
+        for (i, e) in iter.by_ref().take(self.length).enumerate() {
+            if self.dithering {
+                self.write_led_f32(i, ARGB8F::from(e));
+            } else {
+                self.write_led(i, e);
+            }
+            got = i + 1;
+        }
+        self.check_length(got + iter.count())?;
+        self.swap_staging();
+        self.limit_power();
+        Ok(())
+    }
+
+    /// Swap in the frame written to the staging buffer.
+    fn swap_staging(&mut self) {
+        std::mem::swap(&mut self.buffer, &mut self.staging);
+        if self.dithering {
+            std::mem::swap(&mut self.error, &mut self.staging_error);
         }
     }
 
-    pub fn update(&mut self, frame: &[ARGB8]) {
-        let buf = &mut self.buffer;
+    fn write_led(&mut self, i: usize, e: ARGB8) {
+        let idx = 4 * (1 + i);
+        let [lr, lg, lb] = &self.gamma;
+        let (r, g, b) = (lr[e.r as usize], lg[e.g as usize], lb[e.b as usize]);
+        let [ro, go, bo] = self.order;
+        self.staging[idx] = 0xE0 | self.global(e.a);
+        self.staging[idx + ro] = r;
+        self.staging[idx + go] = g;
+        self.staging[idx + bo] = b;
+    }
+
+    /// Update from floating point colors. When dithering is enabled, the
+    /// quantization error of each channel is carried into the next frame so
+    /// the average output over several frames matches the requested value.
+    pub fn update_f32(&mut self, frame: &[ARGB8F]) -> Result<(), Apa102Error> {
+        self.check_length(frame.len())?;
         for i in 0..self.length {
-            let idx = 4 * (1 + i);
-            let e = frame[i];
-            buf[idx] = 0xE0 | e.a;
-            buf[idx + 1] = e.b;
-            buf[idx + 2] = e.g;
-            buf[idx + 3] = e.r;
+            self.write_led_f32(i, frame[i]);
         }
+        self.swap_staging();
+        self.limit_power();
+        Ok(())
     }
 
-    pub fn get_buffer(&self) -> &Vec<u8> {
+    /// Write LED `i`, carrying its quantization error over from `self.error`
+    /// to `self.staging_error` while dithering.
+    fn write_led_f32(&mut self, i: usize, e: ARGB8F) {
+        let idx = 4 * (1 + i);
+        let mut c = [e.r, e.g, e.b];
+        for (k, v) in c.iter_mut().enumerate() {
+            let x = f32::max(f32::min(*v, 255.), 0.);
+            let x = if self.gamma_exp[k] == 1.0 {
+                x
+            } else {
+                255. * f32::powf(x / 255., self.gamma_exp[k])
+            };
+            *v = if self.dithering {
+                let x = x + self.error[i][k];
+                let q = f32::max(f32::min(x.round(), 255.), 0.);
+                self.staging_error[i][k] = x - q;
+                q
+            } else {
+                x.round()
+            };
+        }
+        let [ro, go, bo] = self.order;
+        self.staging[idx] = 0xE0 | self.global(e.a);
+        self.staging[idx + ro] = c[0] as u8;
+        self.staging[idx + go] = c[1] as u8;
+        self.staging[idx + bo] = c[2] as u8;
+    }
+
+    pub fn get_buffer(&self) -> &[u8] {
         &self.buffer
     }
 }
+
+impl Strip for Apa102 {
+    fn update(&mut self, frame: &[ARGB8]) -> Result<(), Apa102Error> {
+        Apa102::update(self, frame)
+    }
+
+    fn get_buffer(&self) -> &[u8] {
+        Apa102::get_buffer(self)
+    }
+
+    fn update_iter(&mut self, iter: &mut dyn Iterator<Item = ARGB8>) -> Result<(), Apa102Error> {
+        self.update_from_iter(iter)
+    }
+
+    fn power_scale(&self) -> f32 {
+        Apa102::power_scale(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Allocator which counts the allocations made by the current thread, so
+    /// tests running alongside don't interfere.
+    mod counting {
+        use std::alloc::{GlobalAlloc, Layout, System};
+        use std::cell::Cell;
+
+        struct Counting;
+
+        thread_local! {
+            static COUNT: Cell<Option<usize>> = Cell::new(None);
+        }
+
+        unsafe impl GlobalAlloc for Counting {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                let _ = COUNT.try_with(|c| c.set(c.get().map(|n| n + 1)));
+                System.alloc(layout)
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                System.dealloc(ptr, layout)
+            }
+        }
+
+        #[global_allocator]
+        static ALLOCATOR: Counting = Counting;
+
+        /// Number of allocations made while running `f`.
+        pub fn allocations<F: FnOnce()>(f: F) -> usize {
+            COUNT.with(|c| c.set(Some(0)));
+            f();
+            COUNT.with(|c| c.take()).unwrap_or(0)
+        }
+    }
+
+    #[test]
+    fn gamma_one_is_identity() {
+        let lut = gamma_table(1.0);
+        for (i, &v) in lut.iter().enumerate() {
+            assert_eq!(v as usize, i);
+        }
+    }
+
+    #[test]
+    fn gamma_2_2_darkens_midtones() {
+        let lut = gamma_table(2.2);
+        assert!((lut[128] as i32 - 55).abs() <= 1, "128 -> {}", lut[128]);
+        assert_eq!(lut[0], 0);
+        assert_eq!(lut[255], 255);
+    }
+
+    #[test]
+    fn update_applies_gamma() {
+        let mut leds = Apa102::with_gamma(1, ChipType::Apa102, ColorOrder::Rgb, [2.2; 3]);
+        leds.update(&[ARGB8::new(31, 128, 255, 0)]).unwrap();
+        let b = leds.get_buffer();
+        assert_eq!(&b[4..8], &[0xff, gamma_table(2.2)[128], 255, 0]);
+    }
+
+    #[test]
+    fn gamma_is_applied_per_channel() {
+        let gamma = [1.0, 2.2, 2.8];
+        let mut leds = Apa102::with_gamma(1, ChipType::Apa102, ColorOrder::Bgr, gamma);
+        leds.update(&[ARGB8::new(31, 128, 128, 128)]).unwrap();
+        let b = leds.get_buffer();
+        let [r, g, b] = [b[7], b[6], b[5]];
+        assert_eq!(r, 128);
+        assert_eq!(g, gamma_table(2.2)[128]);
+        assert_eq!(b, gamma_table(2.8)[128]);
+        assert!(b < g);
+
+        // the dithered path agrees on average
+        leds.enable_dithering(true);
+        let mut sums = [0u32; 3];
+        for _ in 0..8 {
+            leds.update(&[ARGB8::new(31, 128, 128, 128)]).unwrap();
+            for (sum, &c) in sums.iter_mut().zip(&leds.get_buffer()[5..8]) {
+                *sum += c as u32;
+            }
+        }
+        assert_eq!(sums[2], 8 * 128);
+        assert!((sums[1] as i32 - 8 * g as i32).abs() <= 8);
+        assert!((sums[0] as i32 - 8 * b as i32).abs() <= 8);
+    }
+
+    #[test]
+    fn end_frames_differ_between_chips() {
+        let apa = Apa102::new(144, ChipType::Apa102, ColorOrder::Bgr);
+        let sk = Apa102::new(144, ChipType::Sk9822, ColorOrder::Bgr);
+        let led_frames = 4 + 4 * 144;
+        let apa_end = &apa.get_buffer()[led_frames..];
+        let sk_end = &sk.get_buffer()[led_frames..];
+        assert_eq!(apa_end[0], 0xff);
+        assert!(apa_end[1..].iter().all(|&b| b == 0));
+        // sk9822 needs a zero reset frame before the extra clocks
+        assert_eq!(&sk_end[..4], &[0, 0, 0, 0]);
+        assert!(sk_end.iter().all(|&b| b == 0));
+        assert_ne!(apa_end, sk_end);
+    }
+
+    #[test]
+    fn brightness_halves_global_field() {
+        let frame = vec![ARGB8::new(31, 10, 20, 30), ARGB8::new(16, 200, 100, 0)];
+        let mut leds = Apa102::new(2, ChipType::Apa102, ColorOrder::Rgb);
+        leds.set_brightness(0.5);
+        leds.update(&frame).unwrap();
+        let b = leds.get_buffer();
+        assert_eq!(&b[4..8], &[0xE0 | 15, 10, 20, 30]);
+        assert_eq!(&b[8..12], &[0xE0 | 8, 200, 100, 0]);
+    }
+
+    #[test]
+    fn dithering_averages_sub_lsb_values() {
+        let frame = [ARGB8F::new(31, 0.25, 10.5, 100.0)];
+        let mut leds = Apa102::new(1, ChipType::Apa102, ColorOrder::Rgb);
+        leds.enable_dithering(true);
+        let mut sums = [0u32; 3];
+        for _ in 0..16 {
+            leds.update_f32(&frame).unwrap();
+            for (sum, &c) in sums.iter_mut().zip(&leds.get_buffer()[5..8]) {
+                *sum += c as u32;
+            }
+        }
+        assert_eq!(sums[0], 4);
+        assert_eq!(sums[1], 168);
+        assert_eq!(sums[2], 1600);
+    }
+
+    #[test]
+    fn without_dithering_sub_lsb_values_round() {
+        let mut leds = Apa102::new(1, ChipType::Apa102, ColorOrder::Rgb);
+        for _ in 0..16 {
+            leds.update_f32(&[ARGB8F::new(31, 0.25, 0., 0.)]).unwrap();
+            assert_eq!(leds.get_buffer()[5], 0);
+        }
+    }
+
+    #[test]
+    fn dithered_update_keeps_gamma_fractions() {
+        // 40 through gamma 2.2 is about 4.4, which rounds to 4 without dithering
+        let mut leds = Apa102::with_gamma(1, ChipType::Apa102, ColorOrder::Rgb, [2.2; 3]);
+        leds.enable_dithering(true);
+        let mut sum = 0;
+        for _ in 0..10 {
+            leds.update(&[ARGB8::new(31, 40, 0, 0)]).unwrap();
+            sum += leds.get_buffer()[5] as u32;
+        }
+        assert!((43..=45).contains(&sum), "sum of 10 frames: {}", sum);
+    }
+
+    #[test]
+    fn short_frame_is_an_error() {
+        let mut leds = Apa102::new(4, ChipType::Apa102, ColorOrder::Rgb);
+        let frame = vec![ARGB8::new(31, 1, 2, 3); 3];
+        assert_eq!(
+            leds.update(&frame),
+            Err(Apa102Error::LengthMismatch {
+                expected: 4,
+                got: 3
+            })
+        );
+        let mut leds = Apa102::new(4, ChipType::Apa102, ColorOrder::Rgb);
+        leds.enable_dithering(true);
+        assert!(leds.update(&frame).is_err());
+    }
+
+    #[test]
+    fn long_frame_is_an_error() {
+        let mut leds = Apa102::new(4, ChipType::Apa102, ColorOrder::Rgb);
+        let frame = vec![ARGB8::new(31, 1, 2, 3); 6];
+        assert_eq!(
+            leds.update(&frame),
+            Err(Apa102Error::LengthMismatch {
+                expected: 4,
+                got: 6
+            })
+        );
+        let f32_frame = vec![ARGB8F::new(31, 1., 2., 3.); 5];
+        assert!(leds.update_f32(&f32_frame).is_err());
+    }
+
+    #[test]
+    fn mismatched_frames_leave_the_buffer_alone() {
+        for &dithering in &[false, true] {
+            let mut leds = Apa102::with_gamma(4, ChipType::Apa102, ColorOrder::Rgb, [2.2; 3]);
+            leds.enable_dithering(dithering);
+            let frame = vec![ARGB8::new(31, 40, 80, 120); 4];
+            leds.update(&frame).unwrap();
+            let before = leds.get_buffer().to_vec();
+            let mut expected = Apa102::with_gamma(4, ChipType::Apa102, ColorOrder::Rgb, [2.2; 3]);
+            expected.enable_dithering(dithering);
+            expected.update(&frame).unwrap();
+
+            for &len in &[3, 5] {
+                let bad = vec![ARGB8::new(31, 255, 255, 255); len];
+                assert!(leds.update(&bad).is_err());
+                assert_eq!(leds.get_buffer(), &before[..], "{} pixels", len);
+            }
+            // nor does the dithering error carried into the next frame change
+            leds.update(&frame).unwrap();
+            expected.update(&frame).unwrap();
+            assert_eq!(leds.get_buffer(), expected.get_buffer());
+        }
+    }
+
+    #[test]
+    fn power_limit_scales_to_budget() {
+        let frame = vec![ARGB8::new(31, 255, 255, 255); 100];
+        let mut leds = Apa102::new(100, ChipType::Apa102, ColorOrder::Rgb);
+        leds.update(&frame).unwrap();
+        assert_eq!(leds.power_scale(), 1.0);
+
+        // 100 white LEDs at 20mA a channel draw 6A
+        leds.set_power_limit(Some(1500));
+        leds.update(&frame).unwrap();
+        assert!((leds.power_scale() - 0.25).abs() < 1e-6);
+        for led in leds.get_buffer()[4..4 + 400].chunks(4) {
+            assert_eq!(led, &[0xff, 63, 63, 63]);
+        }
+    }
+
+    #[test]
+    fn power_limit_leaves_frames_within_budget() {
+        let frame = vec![ARGB8::new(31, 255, 0, 0); 10];
+        let mut leds = Apa102::new(10, ChipType::Apa102, ColorOrder::Rgb);
+        leds.set_power_limit(Some(200));
+        leds.update(&frame).unwrap();
+        assert_eq!(leds.power_scale(), 1.0);
+        assert_eq!(&leds.get_buffer()[4..8], &[0xff, 255, 0, 0]);
+    }
+
+    #[test]
+    fn color_orders() {
+        let pixel = [ARGB8::new(31, 1, 2, 3)];
+        for (order, bytes) in [
+            (ColorOrder::Bgr, [3, 2, 1]),
+            (ColorOrder::Grb, [2, 1, 3]),
+            (ColorOrder::Rgb, [1, 2, 3]),
+        ]
+        .iter()
+        {
+            let mut leds = Apa102::new(1, ChipType::Apa102, *order);
+            leds.update(&pixel).unwrap();
+            assert_eq!(&leds.get_buffer()[5..8], bytes, "{:?}", order);
+        }
+        assert_eq!("GRB".parse(), Ok(ColorOrder::Grb));
+        assert!("rgbw".parse::<ColorOrder>().is_err());
+    }
+
+    #[test]
+    fn updates_reuse_the_buffer() {
+        use crate::sk6812::Sk6812Rgbw;
+        use crate::ws2812::Ws2812;
+
+        let mut dithered = Apa102::with_gamma(16, ChipType::Apa102, ColorOrder::Bgr, [2.2; 3]);
+        dithered.enable_dithering(true);
+        let strips: Vec<Box<dyn Strip>> = vec![
+            Box::new(Apa102::new(16, ChipType::Apa102, ColorOrder::Bgr)),
+            Box::new(dithered),
+            Box::new(Ws2812::new(16)),
+            Box::new(Sk6812Rgbw::new(16)),
+        ];
+        assert_eq!(counting::allocations(|| drop(vec![0u8; 16])), 1);
+        for mut strip in strips {
+            let mut buffers = vec![];
+            for n in 0..4u8 {
+                let mut pixels = (0..16).map(|i| ARGB8::new(31, n, i, 0));
+                let allocations = counting::allocations(|| strip.update_iter(&mut pixels).unwrap());
+                assert_eq!(allocations, 0);
+                let ptr = strip.get_buffer().as_ptr();
+                if !buffers.contains(&ptr) {
+                    buffers.push(ptr);
+                }
+            }
+            // at most double buffered
+            assert!(buffers.len() <= 2);
+        }
+    }
+
+    #[test]
+    fn update_from_iter_matches_update() {
+        let frame: Vec<ARGB8> = (0..8).map(|i| ARGB8::new(31, i, 2 * i, 3 * i)).collect();
+        let mut a = Apa102::new(8, ChipType::Apa102, ColorOrder::Bgr);
+        let mut b = Apa102::new(8, ChipType::Apa102, ColorOrder::Bgr);
+        a.update(&frame).unwrap();
+        b.update_from_iter(frame.iter().copied()).unwrap();
+        assert_eq!(a.get_buffer(), b.get_buffer());
+    }
+
+    #[test]
+    fn frame_sizes() {
+        assert_eq!(Apa102::start_frame_len(), 4);
+        // half a clock per LED, after the leading byte and reset frame
+        assert_eq!(Apa102::end_frame_len(15, ChipType::Apa102), 6);
+        assert_eq!(Apa102::end_frame_len(144, ChipType::Apa102), 15);
+        assert_eq!(Apa102::end_frame_len(144, ChipType::Sk9822), 14);
+        assert_eq!(Apa102::buffer_len(144, ChipType::Apa102), 4 + 576 + 15);
+        let strip = Apa102::new(144, ChipType::Sk9822, ColorOrder::Bgr);
+        assert_eq!(
+            strip.get_buffer().len(),
+            Apa102::buffer_len(144, ChipType::Sk9822)
+        );
+        assert_eq!(strip.get_buffer()[..4], [0, 0, 0, 0]);
+
+        // (length, apa102 end frame, sk9822 end frame) around the 16 LED steps
+        let sizes = [
+            (0, 6, 5),
+            (1, 6, 5),
+            (16, 7, 6),
+            (17, 7, 6),
+            (4095, 261, 260),
+        ];
+        for &(length, apa, sk) in &sizes {
+            for &(chip, end) in &[(ChipType::Apa102, apa), (ChipType::Sk9822, sk)] {
+                assert_eq!(
+                    Apa102::end_frame_len(length, chip),
+                    end,
+                    "{} {:?}",
+                    length,
+                    chip
+                );
+                let len = 4 + 4 * length + end;
+                assert_eq!(
+                    Apa102::buffer_len(length, chip),
+                    len,
+                    "{} {:?}",
+                    length,
+                    chip
+                );
+                let strip = Apa102::new(length as u16, chip, ColorOrder::Bgr);
+                assert_eq!(strip.get_buffer().len(), len, "{} {:?}", length, chip);
+            }
+        }
+    }
+
+    #[test]
+    fn alpha_past_five_bits_clamps_to_31() {
+        let mut strip = Apa102::new(1, ChipType::Apa102, ColorOrder::Rgb);
+        strip.update(&[ARGB8::new(40, 1, 2, 3)]).unwrap();
+        assert_eq!(strip.get_buffer()[4..8], [0xe0 | 31, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::VecDeque;
+
+/// Result of checking one frame for a beat.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Beat {
+    pub beat: bool,
+    /// Spectral flux relative to the threshold, above 1.0 on a beat.
+    pub strength: f64,
+}
+
+/// BeatDetector finds onsets in the spectral flux of the band energies, which
+/// is compared to an adaptive threshold of the recent mean flux plus a
+/// multiple of its standard deviation.
+pub struct BeatDetector {
+    prev: Vec<f64>,
+    history: VecDeque<f64>,
+    window: usize,
+    sensitivity: f64,
+    holdoff: usize,
+    since_beat: usize,
+}
+
+impl BeatDetector {
+    /// Detect beats against the flux of the last `window` frames, with no
+    /// more than one beat every `holdoff` frames.
+    pub fn new(window: usize, sensitivity: f64, holdoff: usize) -> Self {
+        Self {
+            prev: Vec::new(),
+            history: VecDeque::with_capacity(window + 1),
+            window,
+            sensitivity,
+            holdoff,
+            since_beat: holdoff,
+        }
+    }
+
+    pub fn defaults() -> Self {
+        Self::new(43, 1.5, 8)
+    }
+
+    pub fn process(&mut self, energy: &[f64]) -> Beat {
+        let flux: f64 = energy
+            .iter()
+            .zip(&self.prev)
+            .map(|(e, p)| f64::max(e - p, 0.))
+            .sum();
+        self.prev.clear();
+        self.prev.extend_from_slice(energy);
+
+        // wait for half a window of history before trusting the threshold
+        let warm = self.history.len() >= self.window / 2;
+        let n = self.history.len() as f64;
+        let mean = self.history.iter().sum::<f64>() / f64::max(n, 1.);
+        let var = self.history.iter().map(|f| (f - mean).powi(2)).sum::<f64>() / f64::max(n, 1.);
+        let threshold = mean + self.sensitivity * var.sqrt();
+
+        self.history.push_back(flux);
+        if self.history.len() > self.window {
+            self.history.pop_front();
+        }
+
+        let strength = if threshold > 0. { flux / threshold } else { 0. };
+        self.since_beat += 1;
+        let beat = warm && strength > 1. && self.since_beat > self.holdoff;
+        if beat {
+            self.since_beat = 0;
+        }
+        Beat { beat, strength }
+    }
+}
+
+/// TempoEstimator estimates the tempo in beats per minute from the median
+/// interval between recent beats.
+pub struct TempoEstimator {
+    frame_rate: f64,
+    intervals: VecDeque<usize>,
+    window: usize,
+    // frames counted so far, and the one the last beat was on
+    frame: usize,
+    last_beat: Option<usize>,
+}
+
+impl TempoEstimator {
+    /// Estimate from the last `window` intervals of frames arriving at
+    /// `frame_rate` per second.
+    pub fn new(frame_rate: f64, window: usize) -> Self {
+        Self {
+            frame_rate,
+            intervals: VecDeque::with_capacity(window + 1),
+            window,
+            frame: 0,
+            last_beat: None,
+        }
+    }
+
+    /// Count a frame, returning the tempo once there's been at least two
+    /// beats.
+    pub fn update(&mut self, beat: bool) -> Option<f64> {
+        if beat {
+            if let Some(last) = self.last_beat {
+                self.intervals.push_back(self.frame - last);
+                if self.intervals.len() > self.window {
+                    self.intervals.pop_front();
+                }
+            }
+            self.last_beat = Some(self.frame);
+        }
+        self.frame += 1;
+        self.bpm()
+    }
+
+    pub fn bpm(&self) -> Option<f64> {
+        let mut sorted: Vec<usize> = self.intervals.iter().copied().collect();
+        sorted.sort_unstable();
+        let median = *sorted.get(sorted.len() / 2)?;
+        Some(60. * self.frame_rate / median as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn beats_fire_at_envelope_peaks() {
+        let peaks = [30, 50, 70, 90];
+        let mut detector = BeatDetector::new(20, 1.5, 8);
+        let beats: Vec<usize> = (0..100)
+            .filter(|i| {
+                // a slightly uneven floor with a spike every 20 frames
+                let e = match peaks.contains(i) {
+                    true => 5.,
+                    false => 1. + (i % 2) as f64 * 0.1,
+                };
+                detector.process(&[e, e]).beat
+            })
+            .collect();
+        assert_eq!(beats, peaks);
+    }
+
+    #[test]
+    fn tempo_is_the_median_beat_interval() {
+        let mut tempo = TempoEstimator::new(43., 8);
+        let mut bpm = None;
+        for i in 0..200 {
+            bpm = tempo.update(i % 22 == 0);
+            if i < 22 {
+                assert_eq!(bpm, None);
+            }
+            if i == 22 {
+                // the first interval is the 22 frames between the beats
+                assert_eq!(tempo.intervals, [22]);
+            }
+        }
+        assert!((bpm.unwrap() - 117.27).abs() < 0.01, "{:?}", bpm);
+
+        // a single missed or doubled beat doesn't move the median
+        for (i, &interval) in [22, 44, 22, 11, 11, 22].iter().enumerate() {
+            tempo.intervals[i] = interval;
+        }
+        assert!((tempo.bpm().unwrap() - 117.27).abs() < 0.01);
+    }
+}
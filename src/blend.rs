@@ -0,0 +1,59 @@
+use crate::apa102::ARGB8;
+
+/// Blend `layers` bottom to top with the "over" operator, treating each
+/// pixel's 5 bit alpha as its opacity. Layers shorter than the longest are
+/// transparent past their end.
+pub fn composite(layers: &[&[ARGB8]]) -> Vec<ARGB8> {
+    let length = layers.iter().map(|l| l.len()).max().unwrap_or(0);
+    (0..length)
+        .map(|i| {
+            layers
+                .iter()
+                .filter_map(|l| l.get(i))
+                .fold(ARGB8::new(0, 0, 0, 0), |dst, &src| over(src, dst))
+        })
+        .collect()
+}
+
+/// Porter-Duff `src` over `dst`, with the result's alpha encoded back into 5
+/// bits so the strip's global brightness gives the blended intensity.
+pub fn over(src: ARGB8, dst: ARGB8) -> ARGB8 {
+    let opacity = |p: ARGB8| f64::from(u8::min(p.a, 31)) / 31.;
+    let (sa, da) = (opacity(src), opacity(dst));
+    let a = sa + da * (1. - sa);
+    if a == 0. {
+        return ARGB8::new(0, 0, 0, 0);
+    }
+    let mix = |s: u8, d: u8| {
+        let c = (f64::from(s) * sa + f64::from(d) * da * (1. - sa)) / a;
+        (c + 0.5) as u8
+    };
+    ARGB8::new(
+        (31. * a + 0.5) as u8,
+        mix(src.r, dst.r),
+        mix(src.g, dst.g),
+        mix(src.b, dst.b),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_alpha_red_over_blue_mixes_evenly() {
+        let red = ARGB8::new(16, 255, 0, 0);
+        let blue = ARGB8::new(31, 0, 0, 255);
+        assert_eq!(over(red, blue), ARGB8::new(31, 132, 0, 123));
+    }
+
+    #[test]
+    fn transparent_layers_composite_to_black() {
+        let red = [ARGB8::new(31, 255, 0, 0)];
+        let clear = [ARGB8::new(0, 9, 9, 9); 2];
+        assert_eq!(
+            composite(&[&red, &clear]),
+            vec![ARGB8::new(31, 255, 0, 0), ARGB8::new(0, 0, 0, 0)]
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::{anyhow, Result};
+use audio::bucketer::Bucketer;
+use audio::frequency_sensor::{Features, FrequencySensor, FrequencySensorParams};
+use audio::sfft::SlidingFFT;
+
+/// Spacing of the bucket edges across the frequency range.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BucketScale {
+    Log,
+    Linear,
+    Mel,
+}
+
+impl std::str::FromStr for BucketScale {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "log" => Ok(BucketScale::Log),
+            "linear" => Ok(BucketScale::Linear),
+            "mel" => Ok(BucketScale::Mel),
+            _ => Err(anyhow!("unknown bucket scale: {}", s)),
+        }
+    }
+}
+
+/// Build a bucketer over `f_min..f_max` for `size` FFT bins from 0Hz up to
+/// the Nyquist frequency of `sample_rate`. The bucketer places its own edges
+/// for 44.1kHz input, so they're always replaced.
+pub fn bucketer(
+    size: usize,
+    buckets: usize,
+    f_min: f64,
+    f_max: f64,
+    scale: BucketScale,
+    sample_rate: f64,
+) -> Bucketer {
+    let mut bucketer = Bucketer::new(size, buckets, f_min, f_max);
+    let n = bucketer.indices.len();
+    bucketer.indices = match scale {
+        BucketScale::Log => log_indices(size, n, f_min, f_max, sample_rate),
+        BucketScale::Linear => linear_indices(size, n, f_min, f_max, sample_rate),
+        BucketScale::Mel => mel_indices(size, n, f_min, f_max, sample_rate),
+    };
+    bucketer
+}
+
+/// BucketAnalyzer turns hops of samples into features like `audio::Analyzer`,
+/// but with its bands from `bucketer` so their range and spacing can be
+/// chosen. Unlike the analyzer it has no gain control.
+pub struct BucketAnalyzer {
+    sfft: SlidingFFT,
+    bucketer: Bucketer,
+    fs: FrequencySensor,
+}
+
+impl BucketAnalyzer {
+    pub fn new(
+        fft_size: usize,
+        buckets: usize,
+        length: usize,
+        range: (f64, f64),
+        scale: BucketScale,
+        sample_rate: f64,
+        params: FrequencySensorParams,
+    ) -> Self {
+        let sfft = SlidingFFT::new(fft_size);
+        let (f_min, f_max) = range;
+        let bucketer = bucketer(
+            sfft.output_size(),
+            buckets,
+            f_min,
+            f_max,
+            scale,
+            sample_rate,
+        );
+        Self {
+            sfft,
+            bucketer,
+            fs: FrequencySensor::new(buckets, length, params),
+        }
+    }
+
+    /// Features after the next hop of samples.
+    pub fn process(&mut self, data: &[f64]) -> Features {
+        self.sfft.push_input(data);
+        let bins = self.bucketer.bucket(self.sfft.process());
+        self.fs.process(bins);
+        self.fs.get_features().clone()
+    }
+
+    pub fn indices(&self) -> &[usize] {
+        &self.bucketer.indices
+    }
+}
+
+/// `n` of `size` FFT bin indices at `sample_rate` spaced logarithmically from
+/// `f_min` to `f_max`.
+pub fn log_indices(size: usize, n: usize, f_min: f64, f_max: f64, sample_rate: f64) -> Vec<usize> {
+    spaced_indices(size, n, (f_min, f_max), sample_rate, f64::ln, f64::exp)
+}
+
+/// `n` of `size` FFT bin indices at `sample_rate` evenly spaced in frequency
+/// from `f_min` to `f_max`.
+pub fn linear_indices(
+    size: usize,
+    n: usize,
+    f_min: f64,
+    f_max: f64,
+    sample_rate: f64,
+) -> Vec<usize> {
+    spaced_indices(size, n, (f_min, f_max), sample_rate, |f| f, |f| f)
+}
+
+/// `n` of `size` FFT bin indices at `sample_rate` evenly spaced in mels from
+/// `f_min` to `f_max`.
+pub fn mel_indices(size: usize, n: usize, f_min: f64, f_max: f64, sample_rate: f64) -> Vec<usize> {
+    spaced_indices(size, n, (f_min, f_max), sample_rate, hz_to_mel, mel_to_hz)
+}
+
+pub fn hz_to_mel(f: f64) -> f64 {
+    2595. * f64::log10(1. + f / 700.)
+}
+
+pub fn mel_to_hz(m: f64) -> f64 {
+    700. * (f64::powf(10., m / 2595.) - 1.)
+}
+
+/// Indices evenly spaced on the scale given by `to` and its inverse `from`.
+fn spaced_indices<T, F>(
+    size: usize,
+    n: usize,
+    (f_min, f_max): (f64, f64),
+    sample_rate: f64,
+    to: T,
+    from: F,
+) -> Vec<usize>
+where
+    T: Fn(f64) -> f64,
+    F: Fn(f64) -> f64,
+{
+    let (lo, hi) = (to(f_min), to(f_max));
+    let step = (hi - lo) / usize::max(n.saturating_sub(1), 1) as f64;
+    let bin_width = sample_rate / 2. / size as f64;
+    (0..n)
+        .map(|i| {
+            let f = from(lo + step * i as f64);
+            usize::min((f / bin_width).round() as usize, size - 1)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f64 = 44100.;
+    const NYQUIST: f64 = RATE / 2.;
+
+    #[test]
+    fn linear_indices_are_evenly_spaced() {
+        // 512 bins up to 22050Hz are 43.07Hz apart
+        let indices = linear_indices(512, 5, 0., 17226.5625, RATE);
+        assert_eq!(indices, vec![0, 100, 200, 300, 400]);
+        let bin_width = NYQUIST / 512.;
+        assert_eq!(
+            linear_indices(512, 3, 10. * bin_width, 30. * bin_width, RATE),
+            vec![10, 20, 30]
+        );
+    }
+
+    #[test]
+    fn edges_are_monotonic_and_in_range() {
+        for indices in &[
+            linear_indices(512, 16, 32., 16000., RATE),
+            mel_indices(512, 16, 32., 16000., RATE),
+            linear_indices(64, 16, 0., NYQUIST, RATE),
+        ] {
+            assert!(indices.windows(2).all(|w| w[0] <= w[1]), "{:?}", indices);
+            assert!(indices.iter().all(|&i| i < 512));
+        }
+        assert_eq!(
+            *linear_indices(64, 16, 0., NYQUIST, RATE).last().unwrap(),
+            63
+        );
+    }
+
+    #[test]
+    fn mel_edges_match_the_mel_formula() {
+        // the mel scale is defined so that 1000Hz is about 1000 mels
+        assert!((hz_to_mel(1000.) - 1000.).abs() < 0.1);
+        for &f in &[32., 440., 16000.] {
+            assert!((mel_to_hz(hz_to_mel(f)) - f).abs() < 1e-6);
+        }
+
+        let indices = mel_indices(512, 16, 32., 16000., RATE);
+        let bin_width = NYQUIST / 512.;
+        assert_eq!(indices[0], (32. / bin_width).round() as usize);
+        assert_eq!(indices[15], (16000. / bin_width).round() as usize);
+        assert_eq!((indices[0], indices[15]), (1, 372));
+        // the middle edge falls at the middle of the range in mels
+        let mid = mel_to_hz((hz_to_mel(32.) + hz_to_mel(16000.)) / 2.);
+        let mel = mel_indices(512, 3, 32., 16000., RATE);
+        assert_eq!(mel[1], (mid / bin_width).round() as usize);
+    }
+
+    #[test]
+    fn edges_follow_the_sample_rate() {
+        // at 48kHz the bins are wider, so the same frequencies land on lower bins
+        let bin_width: f64 = 24000. / 512.;
+        for indices in &[
+            log_indices(512, 16, 32., 16000., 48000.),
+            linear_indices(512, 16, 32., 16000., 48000.),
+            mel_indices(512, 16, 32., 16000., 48000.),
+        ] {
+            assert_eq!(indices[0], (32. / bin_width).round() as usize);
+            assert_eq!(indices[15], (16000. / bin_width).round() as usize);
+            assert_eq!(indices[15], 341);
+        }
+        assert_eq!(mel_indices(512, 16, 32., 16000., RATE)[15], 372);
+
+        // log edges are evenly spaced in log frequency
+        let log = log_indices(512, 3, 100., 10000., 48000.);
+        assert_eq!(log[1], (1000. / bin_width).round() as usize);
+    }
+}
@@ -0,0 +1,106 @@
+use std::io::BufRead;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use crate::apa102::ARGB8;
+use crate::config::{self, Config};
+use crate::display::{Display, Frame};
+use crate::visualizer::Params;
+
+/// Full brightness red, green, blue and white frames of `length` pixels, with
+/// the white balance gains applied.
+pub fn frames(length: usize, white_balance: (f64, f64, f64)) -> Vec<Vec<ARGB8>> {
+    let (r, g, b) = white_balance;
+    let c = |gain: f64| (255.5 * f64::min(f64::max(gain, 0.), 1.)) as u8;
+    [(1., 0., 0.), (0., 1., 0.), (0., 0., 1.), (1., 1., 1.)]
+        .iter()
+        .map(|&(dr, dg, db)| vec![ARGB8::new(31, c(r * dr), c(g * dg), c(b * db)); length])
+        .collect()
+}
+
+/// Cycle the strip through the calibration frames every `step`, while
+/// reading white balance gains like `1.0 0.9 0.8` from stdin. `save` writes
+/// them to the config and `quit` stops.
+pub fn run(
+    display: &Display<ARGB8>,
+    params: Arc<RwLock<Params>>,
+    length: usize,
+    step: Duration,
+) -> Result<()> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let sink = display.sink();
+    let cycle = {
+        let (params, stop) = (params.clone(), stop.clone());
+        thread::spawn(move || {
+            for i in 0.. {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let wb = params.read().unwrap().white_balance();
+                let frame = frames(length, wb).swap_remove(i % 4);
+                if sink.send(Frame::new(frame)).is_err() {
+                    break;
+                }
+                thread::sleep(step);
+            }
+        })
+    };
+
+    println!("enter white balance gains as `r g b`, `save` or `quit`");
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        match line.trim() {
+            "" => continue,
+            "quit" => break,
+            "save" => {
+                config::save_visualizer(&params)?;
+                println!("saved {}", Config::CONFIG_FILE);
+            }
+            gains => {
+                let values: Vec<f64> = match gains.split_whitespace().map(str::parse).collect() {
+                    Ok(values) => values,
+                    Err(e) => {
+                        println!("bad gains {:?}: {}", gains, e);
+                        continue;
+                    }
+                };
+                match params.write().unwrap().set("white_balance", &values) {
+                    Ok(()) => println!("white balance: {:?}", values),
+                    Err(e) => println!("{}", e),
+                }
+            }
+        }
+    }
+
+    stop.store(true, Ordering::SeqCst);
+    cycle
+        .join()
+        .map_err(|_| anyhow!("calibration thread panicked"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn four_calibration_frames() {
+        let frames = frames(3, (1., 0.9, 0.8));
+        let firsts: Vec<ARGB8> = frames.iter().map(|f| f[0]).collect();
+        assert_eq!(
+            firsts,
+            vec![
+                ARGB8::new(31, 255, 0, 0),
+                ARGB8::new(31, 0, 229, 0),
+                ARGB8::new(31, 0, 0, 204),
+                ARGB8::new(31, 255, 229, 204),
+            ]
+        );
+        assert!(frames
+            .iter()
+            .all(|f| f.len() == 3 && f.iter().all(|&p| p == f[0])));
+    }
+}
@@ -0,0 +1,176 @@
+use std::path::Path;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+
+use audio::frequency_sensor::FrequencySensorParams;
+
+use crate::visualizer;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Config {
+    pub audio: FrequencySensorParams,
+    pub visualizer: visualizer::Params,
+}
+
+impl Config {
+    pub const CONFIG_FILE: &'static str = ".ledconfig.yaml";
+
+    pub fn default() -> Self {
+        Self {
+            audio: FrequencySensorParams::defaults(),
+            visualizer: visualizer::Params::defaults(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let f = std::fs::File::open(path)?;
+        Ok(serde_yaml::from_reader(f)?)
+    }
+
+    /// Write the config back to the config file.
+    pub fn save(&self) -> Result<()> {
+        self.save_to(Self::CONFIG_FILE)
+    }
+
+    /// Write the config to a temp file next to `path` and rename it into
+    /// place, so a crash mid-write can't leave a truncated config behind.
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let f = std::fs::File::create(&tmp)?;
+        serde_yaml::to_writer(&f, self)?;
+        f.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Live config shared between the visualizer and the interfaces controlling it.
+#[derive(Clone)]
+pub struct Shared {
+    pub visualizer: Arc<RwLock<visualizer::Params>>,
+    pub audio: Arc<RwLock<FrequencySensorParams>>,
+}
+
+impl Shared {
+    pub fn new(config: Config) -> Self {
+        Self {
+            visualizer: Arc::new(RwLock::new(config.visualizer)),
+            audio: Arc::new(RwLock::new(config.audio)),
+        }
+    }
+
+    /// Copy of the current config.
+    pub fn get(&self) -> Config {
+        Config {
+            audio: *self.audio.read().unwrap(),
+            visualizer: self.visualizer.read().unwrap().clone(),
+        }
+    }
+}
+
+/// Save the visualizer params, keeping the rest of the config as it is on
+/// disk.
+pub fn save_visualizer(params: &RwLock<visualizer::Params>) -> Result<()> {
+    let mut config = Config::load(Config::CONFIG_FILE).unwrap_or_else(|_| Config::default());
+    config.visualizer = params.read().unwrap().clone();
+    config.save()
+}
+
+/// Watch the config file and apply changes to the running visualizer. Audio
+/// params are also sent on `audio_tx` since the analyzer has to be rebuilt.
+pub fn watch(shared: Shared, audio_tx: Sender<FrequencySensorParams>) -> Result<()> {
+    let (tx, rx) = channel();
+    let mut watcher = watcher(tx, Duration::from_millis(500))?;
+    // watch the directory since editors often replace the file when saving
+    watcher.watch(".", RecursiveMode::NonRecursive)?;
+
+    thread::spawn(move || {
+        // keep the watcher alive for as long as this thread
+        let _watcher = watcher;
+        while let Ok(event) = rx.recv() {
+            let path = match event {
+                DebouncedEvent::Write(path) | DebouncedEvent::Create(path) => path,
+                DebouncedEvent::Rename(_, path) => path,
+                _ => continue,
+            };
+            if path.file_name() != Some(Config::CONFIG_FILE.as_ref()) {
+                continue;
+            }
+            match reload(&shared, Config::CONFIG_FILE) {
+                Ok(audio) => {
+                    info!("reloaded {}", Config::CONFIG_FILE);
+                    if audio_tx.send(audio).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => warn!("failed to reload {}: {}", Config::CONFIG_FILE, e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Load the config at `path` into `shared`, leaving it as it was if the
+/// visualizer params are invalid. Returns the new audio params.
+fn reload<P: AsRef<Path>>(shared: &Shared, path: P) -> Result<FrequencySensorParams> {
+    let config = Config::load(path)?;
+    config
+        .visualizer
+        .validate()
+        .map_err(|e| anyhow!("invalid visualizer params: {}", e))?;
+    *shared.visualizer.write().unwrap() = config.visualizer;
+    *shared.audio.write().unwrap() = config.audio;
+    Ok(config.audio)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_config_round_trip() {
+        let path = std::env::temp_dir().join(format!("ledconfig-{}.yaml", std::process::id()));
+        let mut config = Config::default();
+        config.visualizer.set("gamma", &[1.8]).unwrap();
+        config
+            .visualizer
+            .set("band_hues", &[0., 120., 240.])
+            .unwrap();
+        config.save_to(&path).unwrap();
+        let read_back = Config::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            serde_yaml::to_string(&read_back).unwrap(),
+            serde_yaml::to_string(&config).unwrap()
+        );
+        assert_eq!(read_back.visualizer.gamma(), 1.8);
+    }
+
+    #[test]
+    fn reload_round_trip_keeps_params_when_invalid() {
+        let path = std::env::temp_dir().join(format!("ledreload-{}.yaml", std::process::id()));
+        let shared = Shared::new(Config::default());
+        let mut config = Config::default();
+        config.visualizer.set("gamma", &[1.8]).unwrap();
+        config.save_to(&path).unwrap();
+        reload(&shared, &path).unwrap();
+        assert_eq!(shared.visualizer.read().unwrap().gamma(), 1.8);
+
+        config.visualizer.set("gamma", &[2.2]).unwrap();
+        config.visualizer.set("max_alpha", &[5.]).unwrap();
+        config.save_to(&path).unwrap();
+        let err = reload(&shared, &path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("max_alpha"));
+        assert_eq!(shared.visualizer.read().unwrap().gamma(), 1.8);
+    }
+}
@@ -0,0 +1,258 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+
+use crate::apa102::ARGB8;
+use crate::display::{Display, Frame, FrameLimiter};
+use crate::fade;
+use crate::pattern::{self, Pattern};
+use crate::visualizer::{Clut, Params};
+
+/// Command sent to the daemon as a line on its socket, like `set 255 0 0`.
+/// `set` and `off` can end with `fade <ms>` to crossfade from what's shown.
+#[derive(Clone, Debug)]
+pub enum Request {
+    /// `set <red> <green> <blue> [alpha]`, with alpha from 0 to 31
+    Set(ARGB8, Duration),
+    /// `pattern <name> [speed]`
+    Pattern(Pattern, f64),
+    /// `breathe <red> <green> <blue> [period]`
+    Breathe(ARGB8, f64),
+    /// `visualizer`
+    Visualizer,
+    /// `off`
+    Off(Duration),
+}
+
+impl std::str::FromStr for Request {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut words: Vec<&str> = s.split_whitespace().collect();
+        let mut fade = Duration::from_millis(0);
+        if let [.., "fade", ms] = words.as_slice() {
+            fade = Duration::from_millis(ms.parse()?);
+            words.truncate(words.len() - 2);
+        }
+        let color = |args: &[&str]| -> Result<ARGB8> {
+            let c = |i: usize| -> Result<u8> {
+                let word = args
+                    .get(i)
+                    .ok_or_else(|| anyhow!("expected red, green and blue"))?;
+                Ok(word.parse()?)
+            };
+            Ok(ARGB8::new(31, c(0)?, c(1)?, c(2)?))
+        };
+        let number = |arg: Option<&&str>, default: f64| -> Result<f64> {
+            Ok(arg.map(|w| w.parse()).transpose()?.unwrap_or(default))
+        };
+        match words.as_slice() {
+            ["set", args @ ..] if args.len() == 3 || args.len() == 4 => {
+                let mut color = color(args)?;
+                if let Some(alpha) = args.get(3) {
+                    color.a = match alpha.parse()? {
+                        alpha @ 0..=31 => alpha,
+                        alpha => return Err(anyhow!("alpha {} is out of range 0 to 31", alpha)),
+                    };
+                }
+                Ok(Request::Set(color, fade))
+            }
+            ["pattern", name, args @ ..] if args.len() <= 1 => {
+                Ok(Request::Pattern(name.parse()?, number(args.get(0), 1.)?))
+            }
+            ["breathe", args @ ..] if args.len() == 3 || args.len() == 4 => {
+                Ok(Request::Breathe(color(args)?, number(args.get(3), 4.)?))
+            }
+            ["visualizer"] => Ok(Request::Visualizer),
+            ["off"] => Ok(Request::Off(fade)),
+            _ => Err(anyhow!("unknown command: {:?}", s.trim())),
+        }
+    }
+}
+
+impl Request {
+    /// Frame of `length` pixels for a request which shows a single color.
+    pub fn frame(&self, length: usize) -> Option<Vec<ARGB8>> {
+        match self {
+            Request::Set(color, _) => Some(vec![*color; length]),
+            Request::Off(_) => Some(fade::blackout(length)),
+            _ => None,
+        }
+    }
+}
+
+/// Listen for requests on a unix socket at `path`, replacing any stale
+/// socket left there, and send them on `tx`. Each line gets an `ok` or
+/// `error: ...` reply.
+pub fn listen<P: AsRef<Path>>(path: P, tx: Sender<Request>) -> Result<()> {
+    let path = path.as_ref();
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(anyhow!("{} exists and isn't a socket", path.display()));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("listening on {}", path.display());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let tx = tx.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(stream, tx) {
+                            warn!("control connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("failed to accept control connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve(stream: UnixStream, tx: Sender<Request>) -> Result<()> {
+    let mut reply = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match line.parse() {
+            Ok(request) => {
+                tx.send(request)?;
+                writeln!(reply, "ok")?;
+            }
+            Err(e) => writeln!(reply, "error: {}", e)?,
+        }
+    }
+    Ok(())
+}
+
+/// Show whatever was last requested on the display, rendering animations at
+/// `fps` in a `width x height` frame. Visualizer frames are taken from
+/// `visualizer`, once `start_visualizer` has been called on the first
+/// request for them.
+pub fn run<F: FnOnce()>(
+    display: &Display<ARGB8>,
+    requests: Receiver<Request>,
+    params: Arc<RwLock<Params>>,
+    size: (usize, usize),
+    fps: f64,
+    visualizer: Receiver<Frame<ARGB8>>,
+    start_visualizer: F,
+) -> Result<()> {
+    let (width, height) = size;
+    let mut start_visualizer = Some(start_visualizer);
+    let mut current = Request::Off(Duration::from_millis(0));
+    // last frame written, and what was shown when the request changed
+    let mut shown = Vec::new();
+    let mut from = Vec::new();
+    // whether a set or off request has finished fading in
+    let mut settled = false;
+    let mut clut = None;
+    let mut start = Instant::now();
+    let mut limiter = FrameLimiter::new(fps);
+    loop {
+        match requests.try_recv() {
+            Ok(request) => {
+                info!("request: {:?}", request);
+                if let Request::Visualizer = request {
+                    if let Some(start) = start_visualizer.take() {
+                        start();
+                    }
+                }
+                if let Request::Pattern(pattern, _) = request {
+                    let params = params.read().unwrap();
+                    let palette = pattern
+                        .palette()
+                        .unwrap_or_else(|| params.palette().clone());
+                    clut = Some(Clut::cached(&palette, params.gamma(), params.color_space()));
+                }
+                current = request;
+                from = shown.clone();
+                settled = false;
+                start = Instant::now();
+            }
+            Err(TryRecvError::Empty) => (),
+            Err(TryRecvError::Disconnected) => return Ok(()),
+        }
+
+        // keep the visualizer from backing up while something else is shown
+        let mut latest = None;
+        while let Ok(frame) = visualizer.try_recv() {
+            latest = Some(frame);
+        }
+
+        let t = start.elapsed().as_secs_f64();
+        let frame = match &current {
+            Request::Set(_, fade) | Request::Off(fade) if !settled => {
+                let fade = fade.as_secs_f64();
+                let progress = if fade > 0. { t / fade } else { 1. };
+                settled = progress >= 1.;
+                current
+                    .frame(width * height)
+                    .map(|to| Frame::new(fade::crossfade(&from, &to, progress)))
+            }
+            Request::Set(..) | Request::Off(_) => None,
+            Request::Pattern(pattern, speed) => clut
+                .as_ref()
+                .map(|clut| Frame::new(pattern.render(clut, width, height, speed * t, 31))),
+            Request::Breathe(color, period) => {
+                let level = pattern::breathe_level(t, *period);
+                Some(Frame::new(pattern::breathe_frame(
+                    *color,
+                    width * height,
+                    level,
+                )))
+            }
+            Request::Visualizer => latest,
+        };
+        if let Some(frame) = frame {
+            shown.clear();
+            shown.extend_from_slice(&frame.pixels);
+            display.send(frame)?;
+        }
+        limiter.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_request_gives_a_solid_frame() {
+        let request: Request = "set 255 0 0".parse().unwrap();
+        assert_eq!(request.frame(3), Some(vec![ARGB8::new(31, 255, 0, 0); 3]));
+        let request: Request = "set 0 0 255 20 fade 250".parse().unwrap();
+        match request {
+            Request::Set(color, fade) => {
+                assert_eq!(color, ARGB8::new(20, 0, 0, 255));
+                assert_eq!(fade, Duration::from_millis(250));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!("set 255 0".parse::<Request>().is_err());
+        assert!("set 0 0 255 40".parse::<Request>().is_err());
+    }
+
+    #[test]
+    fn listen_refuses_to_replace_a_regular_file() {
+        let path = std::env::temp_dir().join(format!("daemon-{}.sock", std::process::id()));
+        std::fs::write(&path, "keep me").unwrap();
+        let (tx, _rx) = std::sync::mpsc::channel();
+        assert!(listen(&path, tx).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::{anyhow, Result};
+use cpal::traits::{DeviceTrait, HostTrait};
+
+/// Pick the device whose name contains `query`, ignoring case. An exact
+/// match is preferred, otherwise the query must match exactly one device.
+pub fn match_device<'a>(names: &'a [String], query: &str) -> Result<&'a str> {
+    let query = query.to_lowercase();
+    if let Some(name) = names.iter().find(|n| n.to_lowercase() == query) {
+        return Ok(name);
+    }
+    let matches: Vec<&String> = names
+        .iter()
+        .filter(|n| n.to_lowercase().contains(&query))
+        .collect();
+    match matches.as_slice() {
+        [name] => Ok(name),
+        [] => Err(anyhow!(
+            "no input device matches {:?}, available: {:?}",
+            query,
+            names
+        )),
+        _ => Err(anyhow!(
+            "input device {:?} is ambiguous, matches: {:?}",
+            query,
+            matches
+        )),
+    }
+}
+
+/// Resolve part of an input device name to the full name of the device.
+pub fn resolve(query: &str) -> Result<String> {
+    let names: Vec<String> = cpal::default_host()
+        .input_devices()?
+        .filter_map(|d| d.name().ok())
+        .collect();
+    match_device(&names, query).map(String::from)
+}
+
+/// A range of sample rates supported with a number of channels.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RateRange {
+    pub channels: u16,
+    pub min: u32,
+    pub max: u32,
+}
+
+/// The supported sample rate closest to `rate` with the given channels.
+pub fn closest_rate(ranges: &[RateRange], channels: u16, rate: u32) -> Option<u32> {
+    ranges
+        .iter()
+        .filter(|r| r.channels == channels)
+        .map(|r| u32::min(u32::max(rate, r.min), r.max))
+        .min_by_key(|&r| (r as i64 - rate as i64).abs())
+}
+
+/// Find the sample rate closest to `rate` which the named device, or the
+/// default device, supports. Errors with the supported ranges if the device
+/// can't record with that many channels.
+pub fn negotiate_rate(device: Option<&str>, channels: u16, rate: u32) -> Result<u32> {
+    let host = cpal::default_host();
+    let device = match device {
+        Some(name) => host
+            .input_devices()?
+            .find(|d| d.name().map(|n| n == name).unwrap_or(false)),
+        None => host.default_input_device(),
+    }
+    .ok_or_else(|| anyhow!("input device not found"))?;
+    let ranges: Vec<RateRange> = device
+        .supported_input_configs()?
+        .map(|c| RateRange {
+            channels: c.channels(),
+            min: c.min_sample_rate().0,
+            max: c.max_sample_rate().0,
+        })
+        .collect();
+    closest_rate(&ranges, channels, rate).ok_or_else(|| {
+        anyhow!(
+            "no supported config with {} channels, supported: {:?}",
+            channels,
+            ranges
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names() -> Vec<String> {
+        vec![
+            "Built-in Microphone",
+            "USB Audio Device",
+            "USB Audio Device 2",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect()
+    }
+
+    #[test]
+    fn matches_device_names() {
+        let names = names();
+        assert_eq!(
+            match_device(&names, "built-in").unwrap(),
+            "Built-in Microphone"
+        );
+        // an exact match wins over the longer name it's part of
+        assert_eq!(
+            match_device(&names, "usb audio device").unwrap(),
+            "USB Audio Device"
+        );
+        assert!(match_device(&names, "usb").is_err());
+        assert!(match_device(&names, "bluetooth").is_err());
+    }
+
+    #[test]
+    fn picks_the_closest_supported_rate() {
+        let ranges = [
+            RateRange {
+                channels: 1,
+                min: 8000,
+                max: 22050,
+            },
+            RateRange {
+                channels: 2,
+                min: 44100,
+                max: 48000,
+            },
+            RateRange {
+                channels: 1,
+                min: 96000,
+                max: 96000,
+            },
+        ];
+        // within a range the rate is used as is
+        assert_eq!(closest_rate(&ranges, 2, 44800), Some(44800));
+        // otherwise it's clamped to the nearest range with those channels
+        assert_eq!(closest_rate(&ranges, 1, 44100), Some(22050));
+        assert_eq!(closest_rate(&ranges, 1, 80000), Some(96000));
+        assert_eq!(closest_rate(&ranges, 2, 8000), Some(44100));
+        assert_eq!(closest_rate(&ranges, 4, 44100), None);
+    }
+}
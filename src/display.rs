@@ -1,4 +1,7 @@
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
 
@@ -22,27 +25,556 @@ where
     fn write_pixel(&self, _: &mut Vec<Color>, _: usize, _: usize, _: Color) {}
 }
 
+/// Applies a sequence of transforms in order.
+pub struct ChainTransform<Color> {
+    stages: Vec<Box<dyn Transform<Color> + Send>>,
+}
+
+impl<Color> ChainTransform<Color> {
+    pub fn new(stages: Vec<Box<dyn Transform<Color> + Send>>) -> Self {
+        Self { stages }
+    }
+
+    pub fn push(&mut self, stage: Box<dyn Transform<Color> + Send>) {
+        self.stages.push(stage);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+}
+
+impl<Color> Transform<Color> for ChainTransform<Color>
+where
+    Color: Clone,
+{
+    fn transform(&self, frame: &Vec<Color>) -> Vec<Color> {
+        let mut frame = frame.to_vec();
+        for stage in self.stages.iter() {
+            frame = stage.transform(&frame);
+        }
+        frame
+    }
+
+    /// Pixels are written through the last stage only, since the earlier
+    /// stages may not preserve its coordinates.
+    fn write_pixel(&self, frame: &mut Vec<Color>, x: usize, y: usize, color: Color) {
+        if let Some(stage) = self.stages.last() {
+            stage.write_pixel(frame, x, y, color);
+        }
+    }
+}
+
+/// Apply `transform` to each frame from `frame_rx` in another thread,
+/// returning the receiver of the transformed frames.
+pub fn spawn_transform<Color, T>(
+    frame_rx: Receiver<Frame<Color>>,
+    transform: T,
+) -> Receiver<Frame<Color>>
+where
+    Color: Send + 'static,
+    T: Transform<Color> + Send + 'static,
+{
+    let (tx, rx) = sync_channel(0);
+    thread::spawn(move || {
+        while let Ok(mut frame) = frame_rx.recv() {
+            frame.set_pixels(transform.transform(&frame.pixels));
+            if tx.send(frame).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Frame of pixels stamped with the time it was created, so consumers can
+/// measure how long it spent in the pipeline.
+pub struct Frame<Color> {
+    pub pixels: Vec<Color>,
+    pub created_at: Instant,
+    /// Number given by the renderer, so the output can tell when frames were
+    /// dropped on the way.
+    pub seq: Option<u64>,
+    /// Checksum of the pixels as rendered, if they're being checked.
+    pub checksum: Option<u32>,
+    // dropped along with the frame, to wake a writer waiting for it
+    done: Option<Done>,
+}
+
+/// Handle which disconnects a receiver once every copy of it is dropped, so
+/// a frame split across several outputs is done when they all are.
+#[derive(Clone)]
+pub struct Done(Arc<Sender<()>>);
+
+impl<Color> Frame<Color> {
+    pub fn new(pixels: Vec<Color>) -> Self {
+        Self {
+            pixels,
+            created_at: Instant::now(),
+            seq: None,
+            checksum: None,
+            done: None,
+        }
+    }
+
+    /// Frame which disconnects `done_tx` once the output has finished with it.
+    pub fn with_done(pixels: Vec<Color>, done_tx: Sender<()>) -> Self {
+        Self {
+            done: Some(Done(Arc::new(done_tx))),
+            ..Self::new(pixels)
+        }
+    }
+
+    /// Handle to keep until the output has finished with this frame, for
+    /// outputs which pass the pixels on without the frame.
+    pub fn done(&self) -> Option<Done> {
+        self.done.clone()
+    }
+
+    /// Replace the pixels, dropping the checksum since it no longer
+    /// describes them.
+    pub fn set_pixels(&mut self, pixels: Vec<Color>) {
+        self.pixels = pixels;
+        self.checksum = None;
+    }
+
+    /// Time from creation until the frame was consumed at `consumed_at`.
+    pub fn latency(&self, consumed_at: Instant) -> Duration {
+        consumed_at.saturating_duration_since(self.created_at)
+    }
+}
+
+/// Copies don't hold up a writer waiting for the frame, only the original does.
+impl<Color: Clone> Clone for Frame<Color> {
+    fn clone(&self) -> Self {
+        Self {
+            pixels: self.pixels.clone(),
+            created_at: self.created_at,
+            seq: self.seq,
+            checksum: self.checksum,
+            done: None,
+        }
+    }
+}
+
 /// Display manages a display buffer.
 pub struct Display<Color> {
-    sender: SyncSender<Vec<Color>>,
+    sender: SyncSender<Frame<Color>>,
 }
 
 impl<Color> Display<Color>
 where
     Color: Copy + Clone,
 {
-    pub fn new() -> (Self, Receiver<Vec<Color>>) {
-        let (sender, receiver) = sync_channel(0);
+    /// Create a display where writes block until the frame is consumed.
+    pub fn new() -> (Self, Receiver<Frame<Color>>) {
+        Self::with_capacity(0)
+    }
+
+    /// Create a display which can queue up to `depth` frames before blocking.
+    pub fn with_capacity(depth: usize) -> (Self, Receiver<Frame<Color>>) {
+        let (sender, receiver) = sync_channel(depth);
         (Self { sender }, receiver)
     }
 
     pub fn write(&self, frame: &Vec<Color>) -> Result<()> {
+        self.send(Frame::new(frame.clone()))
+    }
+
+    /// Write a frame the caller won't reuse, without copying it.
+    pub fn write_owned(&self, frame: Vec<Color>) -> Result<()> {
+        self.send(Frame::new(frame))
+    }
+
+    /// Write a frame and block until the output has finished with it.
+    pub fn write_and_wait(&self, frame: &Vec<Color>) -> Result<()> {
+        let (done_tx, done_rx) = channel();
+        self.send(Frame::with_done(frame.clone(), done_tx))?;
+        // nothing is ever sent, this returns once the frame has been dropped
+        let _ = done_rx.recv();
+        Ok(())
+    }
+
+    /// Write a frame which was already stamped.
+    pub fn send(&self, frame: Frame<Color>) -> Result<()> {
         self.sender
-            .send(frame.clone())
+            .send(frame)
             .map_err(|_| anyhow!("failed to send frame"))
     }
 
-    pub fn sink(&self) -> SyncSender<Vec<Color>> {
+    pub fn sink(&self) -> SyncSender<Frame<Color>> {
         self.sender.clone()
     }
 }
+
+/// FanOut copies each frame to several sinks, so a full or disconnected sink
+/// doesn't hold up delivery to the others.
+pub struct FanOut<Color> {
+    sinks: Vec<SyncSender<Frame<Color>>>,
+}
+
+impl<Color> FanOut<Color>
+where
+    Color: Clone,
+{
+    pub fn new(sinks: Vec<SyncSender<Frame<Color>>>) -> Self {
+        Self { sinks }
+    }
+
+    /// Send the frame to every sink, returning the index and error of each
+    /// sink which didn't accept it.
+    pub fn write(&self, frame: &Frame<Color>) -> Vec<(usize, TrySendError<()>)> {
+        let mut errors = vec![];
+        for (i, sink) in self.sinks.iter().enumerate() {
+            if let Err(e) = sink.try_send(frame.clone()) {
+                let e = match e {
+                    TrySendError::Full(_) => TrySendError::Full(()),
+                    TrySendError::Disconnected(_) => TrySendError::Disconnected(()),
+                };
+                errors.push((i, e));
+            }
+        }
+        errors
+    }
+}
+
+/// Copy each frame from `frame_rx` to the sinks of `fan_out` in another
+/// thread, returning the receiver of the frames themselves. Sinks which are
+/// full just miss frames, so they never hold up the output.
+pub fn spawn_fan_out<Color>(
+    frame_rx: Receiver<Frame<Color>>,
+    fan_out: FanOut<Color>,
+) -> Receiver<Frame<Color>>
+where
+    Color: Clone + Send + 'static,
+{
+    let (tx, rx) = sync_channel(0);
+    thread::spawn(move || {
+        while let Ok(frame) = frame_rx.recv() {
+            fan_out.write(&frame);
+            if tx.send(frame).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+struct Slot<Color> {
+    frame: Option<Frame<Color>>,
+    closed: bool,
+}
+
+/// LatestDisplay holds only the most recently written frame, so writers never
+/// block and a slow consumer always gets the newest frame.
+pub struct LatestDisplay<Color> {
+    slot: Arc<(Mutex<Slot<Color>>, Condvar)>,
+}
+
+/// Consumer side of a `LatestDisplay`.
+pub struct LatestReceiver<Color> {
+    slot: Arc<(Mutex<Slot<Color>>, Condvar)>,
+}
+
+impl<Color> LatestDisplay<Color> {
+    pub fn new() -> (Self, LatestReceiver<Color>) {
+        let slot = Arc::new((
+            Mutex::new(Slot {
+                frame: None,
+                closed: false,
+            }),
+            Condvar::new(),
+        ));
+        (Self { slot: slot.clone() }, LatestReceiver { slot })
+    }
+
+    /// Replace the pending frame, if any, with this one.
+    pub fn write(&self, frame: Frame<Color>) {
+        let (lock, cvar) = &*self.slot;
+        lock.lock().unwrap().frame = Some(frame);
+        cvar.notify_one();
+    }
+}
+
+impl<Color> Drop for LatestDisplay<Color> {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.slot;
+        if let Ok(mut slot) = lock.lock() {
+            slot.closed = true;
+        }
+        cvar.notify_one();
+    }
+}
+
+impl<Color> LatestReceiver<Color> {
+    /// Block until a frame is available and take it.
+    pub fn recv(&self) -> Result<Frame<Color>> {
+        let (lock, cvar) = &*self.slot;
+        let mut slot = lock.lock().map_err(|_| anyhow!("display lock poisoned"))?;
+        loop {
+            if let Some(frame) = slot.frame.take() {
+                return Ok(frame);
+            }
+            if slot.closed {
+                return Err(anyhow!("display closed"));
+            }
+            slot = cvar
+                .wait(slot)
+                .map_err(|_| anyhow!("display lock poisoned"))?;
+        }
+    }
+}
+
+/// Keep only the newest of the frames from `frame_rx`, so whatever sends them
+/// never blocks waiting for the receiver of the returned frames.
+pub fn spawn_latest<Color>(frame_rx: Receiver<Frame<Color>>) -> Receiver<Frame<Color>>
+where
+    Color: Send + 'static,
+{
+    let (latest, latest_rx) = LatestDisplay::new();
+    thread::spawn(move || {
+        while let Ok(frame) = frame_rx.recv() {
+            latest.write(frame);
+        }
+    });
+    let (tx, rx) = sync_channel(0);
+    thread::spawn(move || {
+        while let Ok(frame) = latest_rx.recv() {
+            if tx.send(frame).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// FrameLimiter caps the rate frames are output at. Frames are scheduled a
+/// fixed interval after the previous deadline rather than after the previous
+/// frame, so sleep overshoot doesn't accumulate into drift.
+pub struct FrameLimiter {
+    interval: Duration,
+    next: Option<Instant>,
+}
+
+impl FrameLimiter {
+    pub fn new(max_fps: f64) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1. / max_fps),
+            next: None,
+        }
+    }
+
+    /// How long to wait at time `now` before the next frame may be output.
+    pub fn delay(&mut self, now: Instant) -> Duration {
+        let next = match self.next {
+            // don't try to catch up after falling more than a frame behind
+            Some(next) if next + self.interval > now => next,
+            _ => now,
+        };
+        self.next = Some(next + self.interval);
+        next.saturating_duration_since(now)
+    }
+
+    /// Block until the next frame may be output.
+    pub fn wait(&mut self) {
+        let delay = self.delay(Instant::now());
+        if delay > Duration::from_secs(0) {
+            thread::sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Reverse;
+
+    impl Transform<u8> for Reverse {
+        fn transform(&self, frame: &Vec<u8>) -> Vec<u8> {
+            frame.iter().rev().copied().collect()
+        }
+
+        fn write_pixel(&self, frame: &mut Vec<u8>, x: usize, _: usize, color: u8) {
+            let i = frame.len() - 1 - x;
+            frame[i] = color;
+        }
+    }
+
+    #[test]
+    fn chain_applies_stages_in_order() {
+        let chain: ChainTransform<u8> =
+            ChainTransform::new(vec![Box::new(Identity), Box::new(Reverse)]);
+        assert_eq!(chain.transform(&vec![1, 2, 3, 4]), vec![4, 3, 2, 1]);
+
+        let mut frame = vec![0; 4];
+        chain.write_pixel(&mut frame, 0, 0, 9);
+        assert_eq!(frame, vec![0, 0, 0, 9]);
+    }
+
+    #[test]
+    fn spawned_transform_relays_frames() {
+        let (tx, rx) = sync_channel(1);
+        let rx = spawn_transform(rx, Reverse);
+        tx.send(Frame::new(vec![1, 2, 3])).unwrap();
+        assert_eq!(rx.recv().unwrap().pixels, vec![3, 2, 1]);
+        drop(tx);
+        assert!(rx.recv().is_err());
+    }
+
+    #[test]
+    fn limiter_enforces_minimum_interval() {
+        let interval = Duration::from_millis(10);
+        let mut limiter = FrameLimiter::new(100.);
+        let t0 = Instant::now();
+        assert_eq!(limiter.delay(t0), Duration::from_secs(0));
+        // frames arriving early wait for the interval
+        assert_eq!(
+            limiter.delay(t0 + Duration::from_millis(4)),
+            Duration::from_millis(6)
+        );
+        // the next is scheduled from the deadline, not when the frame came
+        assert_eq!(
+            limiter.delay(t0 + Duration::from_millis(15)),
+            Duration::from_millis(5)
+        );
+        // after falling behind it starts over rather than catching up
+        assert_eq!(limiter.delay(t0 + 10 * interval), Duration::from_secs(0));
+        assert_eq!(limiter.delay(t0 + 10 * interval), interval);
+    }
+
+    #[test]
+    fn buffered_display_takes_writes_without_blocking() {
+        let (display, rx) = Display::with_capacity(2);
+        display.write(&vec![1u8]).unwrap();
+        display.write_owned(vec![2u8]).unwrap();
+        assert_eq!(rx.try_recv().unwrap().pixels, vec![1]);
+        assert_eq!(rx.try_recv().unwrap().pixels, vec![2]);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn fan_out_survives_a_dropped_receiver() {
+        let (a_tx, a_rx) = sync_channel(1);
+        let (b_tx, b_rx) = sync_channel(1);
+        let fan_out = FanOut::new(vec![a_tx, b_tx]);
+        let frame = Frame::new(vec![1u8, 2, 3]);
+        assert!(fan_out.write(&frame).is_empty());
+        assert_eq!(a_rx.recv().unwrap().pixels, vec![1, 2, 3]);
+        assert_eq!(b_rx.recv().unwrap().pixels, vec![1, 2, 3]);
+
+        drop(a_rx);
+        let errors = fan_out.write(&Frame::new(vec![4]));
+        assert_eq!(errors, vec![(0, TrySendError::Disconnected(()))]);
+        assert_eq!(b_rx.recv().unwrap().pixels, vec![4]);
+
+        // b is now full, which doesn't stop it getting later frames
+        fan_out.write(&Frame::new(vec![5]));
+        let errors = fan_out.write(&Frame::new(vec![6]));
+        assert_eq!(errors[1], (1, TrySendError::Full(())));
+        assert_eq!(b_rx.recv().unwrap().pixels, vec![5]);
+        fan_out.write(&Frame::new(vec![7]));
+        assert_eq!(b_rx.recv().unwrap().pixels, vec![7]);
+    }
+
+    #[test]
+    fn latest_display_keeps_only_the_last_write() {
+        let (display, rx) = LatestDisplay::new();
+        for n in 1..=3u8 {
+            display.write(Frame::new(vec![n]));
+        }
+        assert_eq!(rx.recv().unwrap().pixels, vec![3]);
+        display.write(Frame::new(vec![4]));
+        drop(display);
+        assert_eq!(rx.recv().unwrap().pixels, vec![4]);
+        assert!(rx.recv().is_err());
+    }
+
+    #[test]
+    fn spawned_latest_skips_stale_frames() {
+        let (tx, rx) = sync_channel(0);
+        let rx = spawn_latest(rx);
+        // none of these block, although nothing is receiving yet
+        for n in 1..=3u8 {
+            tx.send(Frame::new(vec![n])).unwrap();
+        }
+        drop(tx);
+        let received: Vec<u8> = rx.iter().map(|f| f.pixels[0]).collect();
+        assert_eq!(received.last(), Some(&3));
+        assert!(received.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn write_owned_moves_the_allocation() {
+        let (display, rx) = Display::with_capacity(1);
+        let frame = vec![7u8; 64];
+        let (ptr, capacity) = (frame.as_ptr(), frame.capacity());
+        display.write_owned(frame).unwrap();
+        let received = rx.recv().unwrap().pixels;
+        assert_eq!(received.as_ptr(), ptr);
+        assert_eq!(received.capacity(), capacity);
+    }
+
+    #[test]
+    fn write_and_wait_returns_once_the_frame_is_done() {
+        let (display, rx) = Display::new();
+        let (done_tx, done_rx) = channel();
+        let output = thread::spawn(move || {
+            let frame = rx.recv().unwrap();
+            thread::sleep(Duration::from_millis(20));
+            done_tx.send(frame.pixels.clone()).unwrap();
+        });
+        display.write_and_wait(&vec![1u8, 2]).unwrap();
+        // the output had finished with it before the write returned
+        assert_eq!(done_rx.try_recv().unwrap(), vec![1, 2]);
+        output.join().unwrap();
+    }
+
+    #[test]
+    fn latency_is_consumed_at_minus_created_at() {
+        let frame = Frame::new(vec![0u8]);
+        let consumed_at = frame.created_at + Duration::from_millis(12);
+        assert_eq!(frame.latency(consumed_at), Duration::from_millis(12));
+        // a clock read before the frame was made doesn't underflow
+        let earlier = frame.created_at - Duration::from_millis(1);
+        assert_eq!(frame.latency(earlier), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn fanned_out_copies_dont_hold_up_write_and_wait() {
+        let (display, rx) = Display::new();
+        let (preview_tx, preview_rx) = sync_channel(1);
+        let rx = spawn_fan_out(rx, FanOut::new(vec![preview_tx]));
+        let output = thread::spawn(move || drop(rx.recv().unwrap()));
+        // returns although the preview never takes its copy
+        display.write_and_wait(&vec![1u8]).unwrap();
+        output.join().unwrap();
+        assert_eq!(preview_rx.recv().unwrap().pixels, vec![1]);
+    }
+
+    #[test]
+    fn done_handle_survives_every_stage() {
+        let (tx, rx) = sync_channel(0);
+        let (preview_tx, _preview_rx) = sync_channel(1);
+        let rx = spawn_transform(rx, Reverse);
+        let rx = spawn_fan_out(rx, FanOut::new(vec![preview_tx]));
+        let rx = spawn_latest(rx);
+        let (done_tx, done_rx) = channel();
+        tx.send(Frame::with_done(vec![1u8, 2], done_tx)).unwrap();
+        let frame = rx.recv().unwrap();
+        assert_eq!(frame.pixels, vec![2, 1]);
+        // split across outputs, it isn't done until every part is
+        let part = frame.done();
+        drop(frame);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(
+            done_rx.try_recv(),
+            Err(std::sync::mpsc::TryRecvError::Empty)
+        );
+        drop(part);
+        assert_eq!(
+            done_rx.try_recv(),
+            Err(std::sync::mpsc::TryRecvError::Disconnected)
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+
+use crate::features::Spectrum;
+
+/// Envelope follows the amplitude of each band, rising towards it with an
+/// attack time constant and falling with a decay one, and keeps the recent
+/// results for rendering along the strips.
+pub struct Envelope {
+    depth: usize,
+    // newest first
+    history: VecDeque<Vec<f64>>,
+}
+
+impl Envelope {
+    /// Keep the last `depth` frames of levels.
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth: usize::max(depth, 1),
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Follow `amplitudes` over a frame of `dt` seconds, with the (attack,
+    /// decay) time constants in seconds of each band. The first frame is
+    /// taken as is.
+    pub fn update(&mut self, amplitudes: &[f64], time_constants: &[(f64, f64)], dt: f64) {
+        let levels = match self.history.front() {
+            Some(last) if last.len() == amplitudes.len() => amplitudes
+                .iter()
+                .zip(last)
+                .enumerate()
+                .map(|(k, (&x, &prev))| {
+                    let (attack, decay) = time_constants.get(k).copied().unwrap_or((0., 0.));
+                    let w = coefficient(if x > prev { attack } else { decay }, dt);
+                    w * prev + (1. - w) * x
+                })
+                .collect(),
+            _ => amplitudes.to_vec(),
+        };
+        self.history.push_front(levels);
+        self.history.truncate(self.depth);
+    }
+
+    /// Levels of each band `i` frames ago.
+    pub fn levels(&self, i: usize) -> &[f64] {
+        let last = self.history.len().saturating_sub(1);
+        self.history.get(usize::min(i, last)).map_or(&[], |l| l)
+    }
+}
+
+/// Spectrum with the amplitudes replaced by the levels of an envelope
+/// following them.
+pub struct Enveloped<'a, F> {
+    features: &'a F,
+    envelope: &'a Envelope,
+}
+
+impl<'a, F> Enveloped<'a, F> {
+    pub fn new(features: &'a F, envelope: &'a Envelope) -> Self {
+        Self { features, envelope }
+    }
+}
+
+impl<'a, F: Spectrum> Spectrum for Enveloped<'a, F> {
+    fn scales(&self) -> &[f64] {
+        self.features.scales()
+    }
+
+    fn energy(&self) -> &[f64] {
+        self.features.energy()
+    }
+
+    fn amplitudes(&self, i: usize) -> &[f64] {
+        self.envelope.levels(i)
+    }
+}
+
+/// Weight of the previous level when following over `dt` seconds with time
+/// constant `tau`, so a step is approached by 1 - 1/e after `tau` seconds.
+fn coefficient(tau: f64, dt: f64) -> f64 {
+    if tau <= 0. || dt <= 0. {
+        return 0.;
+    }
+    f64::exp(-dt / tau)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn impulse_rises_within_the_attack_and_falls_at_the_decay_rate() {
+        let (attack, decay, dt) = (0.05, 0.5, 0.01);
+        let mut envelope = Envelope::new(4);
+        envelope.update(&[0.], &[(attack, decay)], dt);
+        // held for the attack time, it gets at least 1 - 1/e of the way up
+        for _ in 0..5 {
+            envelope.update(&[1.], &[(attack, decay)], dt);
+        }
+        let peak = envelope.levels(0)[0];
+        assert!(peak >= 1. - 1. / std::f64::consts::E - 1e-9, "{}", peak);
+        assert!(peak < 1.);
+
+        // then each frame keeps the same fraction, exp(-dt / decay)
+        let mut levels = vec![peak];
+        for _ in 0..50 {
+            envelope.update(&[0.], &[(attack, decay)], dt);
+            levels.push(envelope.levels(0)[0]);
+        }
+        let rate = f64::exp(-dt / decay);
+        assert!(levels.windows(2).all(|w| (w[1] / w[0] - rate).abs() < 1e-9));
+        // down by 1/e after the decay time
+        assert!((levels[50] / peak - 1. / std::f64::consts::E).abs() < 1e-9);
+    }
+
+    #[test]
+    fn history_is_kept_newest_first() {
+        let mut envelope = Envelope::new(2);
+        assert!(envelope.levels(0).is_empty());
+        for x in 1..=3 {
+            envelope.update(&[x as f64], &[(0., 0.)], 0.01);
+        }
+        assert_eq!(envelope.levels(0), [3.]);
+        assert_eq!(envelope.levels(1), [2.]);
+        // older frames than are kept repeat the oldest
+        assert_eq!(envelope.levels(5), [2.]);
+    }
+}
@@ -0,0 +1,264 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::apa102::ARGB8;
+use crate::display::{Display, Frame, FrameLimiter};
+
+/// Interval the last frame is repeated at while fading with no new frames.
+const TICK: Duration = Duration::from_millis(20);
+
+/// Fader ramps the brightness of output linearly between two levels.
+pub struct Fader {
+    start: Instant,
+    duration: Duration,
+    from: f64,
+    to: f64,
+}
+
+impl Fader {
+    /// Start fading in from black.
+    pub fn fade_in(duration: Duration, now: Instant) -> Self {
+        Self {
+            start: now,
+            duration,
+            from: 0.,
+            to: 1.,
+        }
+    }
+
+    /// Fade out to black from the current level.
+    pub fn fade_out(&mut self, now: Instant) {
+        self.from = self.level(now);
+        self.to = 0.;
+        self.start = now;
+    }
+
+    /// Fade back in from the current level.
+    pub fn fade_in_from(&mut self, now: Instant) {
+        self.from = self.level(now);
+        self.to = 1.;
+        self.start = now;
+    }
+
+    pub fn level(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= self.duration {
+            return self.to;
+        }
+        let t = elapsed.as_secs_f64() / self.duration.as_secs_f64();
+        self.from + t * (self.to - self.from)
+    }
+
+    pub fn is_fading(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.start) < self.duration
+    }
+
+    pub fn is_fading_out(&self) -> bool {
+        self.to == 0.
+    }
+
+    /// Whether a fade out has finished and output is black.
+    pub fn is_out(&self, now: Instant) -> bool {
+        self.is_fading_out() && !self.is_fading(now)
+    }
+}
+
+/// Frame with every color channel scaled by `level`.
+pub fn scale(frame: &[ARGB8], level: f64) -> Vec<ARGB8> {
+    let s = |c: u8| (c as f64 * level + 0.5) as u8;
+    frame
+        .iter()
+        .map(|p| ARGB8::new(p.a, s(p.r), s(p.g), s(p.b)))
+        .collect()
+}
+
+/// An all black frame of `length` pixels.
+pub fn blackout(length: usize) -> Vec<ARGB8> {
+    vec![ARGB8::new(0, 0, 0, 0); length]
+}
+
+/// Frame `t` of the way from `from` to `to`, mixing the colors with the
+/// brightness folded in. Pixels missing from `from` start black, and `t` of
+/// 1.0 or more gives exactly `to`.
+pub fn crossfade(from: &[ARGB8], to: &[ARGB8], t: f64) -> Vec<ARGB8> {
+    if t >= 1. {
+        return to.to_vec();
+    }
+    let t = f64::max(t, 0.);
+    let mix = |a: u8, b: u8| (a as f64 + t * (b as f64 - a as f64) + 0.5) as u8;
+    to.iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let (r0, g0, b0) = from.get(i).map_or((0, 0, 0), |p| p.scaled_rgb());
+            let (r1, g1, b1) = p.scaled_rgb();
+            ARGB8::new(31, mix(r0, r1), mix(g0, g1), mix(b0, b1))
+        })
+        .collect()
+}
+
+/// Write frames crossfading from `from` to `to` over `duration` at `fps`,
+/// finishing on `to`.
+pub fn transition(
+    display: &Display<ARGB8>,
+    from: &[ARGB8],
+    to: &[ARGB8],
+    duration: Duration,
+    fps: f64,
+) -> Result<()> {
+    let mut limiter = FrameLimiter::new(fps);
+    let start = Instant::now();
+    loop {
+        let elapsed = start.elapsed();
+        if elapsed >= duration {
+            return display.write(&to.to_vec());
+        }
+        let t = elapsed.as_secs_f64() / duration.as_secs_f64();
+        display.write_owned(crossfade(from, to, t))?;
+        limiter.wait();
+    }
+}
+
+/// Fade frames from `frame_rx` in over `duration`, and out once `shutdown` is
+/// set, returning the receiver of the faded frames. Frames received after
+/// `shutdown` only keep the sink drained, unless nothing has been shown yet.
+/// `done_tx` is disconnected once the output has finished with the black
+/// frame at the end.
+pub fn spawn(
+    frame_rx: Receiver<Frame<ARGB8>>,
+    duration: Duration,
+    shutdown: Arc<AtomicBool>,
+    done_tx: Sender<()>,
+) -> Receiver<Frame<ARGB8>> {
+    let (tx, rx) = sync_channel(0);
+    thread::spawn(move || {
+        let mut fader = Fader::fade_in(duration, Instant::now());
+        // copy of the last frame, so the ramp doesn't depend on new frames arriving
+        let mut last: Vec<ARGB8> = Vec::new();
+        loop {
+            let received = match frame_rx.recv_timeout(TICK) {
+                Ok(frame) => Some(frame),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            let now = Instant::now();
+            if shutdown.load(Ordering::SeqCst) && !fader.is_fading_out() {
+                fader.fade_out(now);
+            }
+
+            if fader.is_fading_out() {
+                if let (true, Some(frame)) = (last.is_empty(), received) {
+                    last = frame.pixels;
+                }
+                if last.is_empty() {
+                    continue;
+                }
+                if tx.send(Frame::new(scale(&last, fader.level(now)))).is_err() {
+                    break;
+                }
+                if fader.is_out(now) {
+                    let _ = tx.send(Frame::with_done(blackout(last.len()), done_tx));
+                    break;
+                }
+                continue;
+            }
+
+            let mut frame = match received {
+                Some(frame) => {
+                    last.clear();
+                    last.extend_from_slice(&frame.pixels);
+                    frame
+                }
+                None if fader.is_fading(now) && !last.is_empty() => Frame::new(last.clone()),
+                None => continue,
+            };
+            if fader.is_fading(now) {
+                frame.set_pixels(scale(&frame.pixels, fader.level(now)));
+            }
+            if tx.send(frame).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fade_ramps_monotonically() {
+        let duration = Duration::from_millis(500);
+        let t0 = Instant::now();
+        let step = |k: u32| t0 + duration * k / 20;
+        let mut fader = Fader::fade_in(duration, t0);
+        let levels: Vec<f64> = (0..=20).map(|k| fader.level(step(k))).collect();
+        assert_eq!(levels[0], 0.);
+        assert_eq!(levels[20], 1.);
+        assert!(levels.windows(2).all(|w| w[0] < w[1]));
+
+        let white = vec![ARGB8::new(31, 255, 255, 255)];
+        let reds: Vec<u8> = levels.iter().map(|&l| scale(&white, l)[0].r).collect();
+        assert!(reds.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!((reds[0], reds[20]), (0, 255));
+
+        fader.fade_out(step(20));
+        let levels: Vec<f64> = (20..=40).map(|k| fader.level(step(k))).collect();
+        assert!(levels.windows(2).all(|w| w[0] > w[1]));
+        assert!(fader.is_out(step(40)));
+    }
+
+    #[test]
+    fn blackout_is_black_for_its_length() {
+        let frame = blackout(5);
+        assert_eq!(frame, vec![ARGB8::new(0, 0, 0, 0); 5]);
+        assert!(blackout(0).is_empty());
+    }
+
+    #[test]
+    fn crossfade_midpoint_is_halfway() {
+        let from = vec![ARGB8::new(31, 200, 0, 100); 2];
+        let to = vec![ARGB8::new(31, 0, 200, 100); 2];
+        assert_eq!(crossfade(&from, &to, 0.), from);
+        assert_eq!(
+            crossfade(&from, &to, 0.5),
+            vec![ARGB8::new(31, 100, 100, 100); 2]
+        );
+        assert_eq!(crossfade(&from, &to, 1.), to);
+        // missing pixels fade in from black
+        assert_eq!(
+            crossfade(&[], &to, 0.5),
+            vec![ARGB8::new(31, 0, 100, 50); 2]
+        );
+    }
+
+    #[test]
+    fn shutdown_ends_on_a_black_frame_holding_the_done_handle() {
+        let (tx, rx) = sync_channel(1);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let rx = spawn(rx, Duration::from_millis(0), shutdown.clone(), done_tx);
+        tx.send(Frame::new(vec![ARGB8::new(31, 255, 255, 255); 3]))
+            .unwrap();
+        rx.recv().unwrap();
+        shutdown.store(true, Ordering::SeqCst);
+        let last = rx.iter().last().unwrap();
+        assert_eq!(last.pixels, blackout(3));
+        // still waiting until the output drops the black frame
+        assert_eq!(
+            done_rx.try_recv(),
+            Err(std::sync::mpsc::TryRecvError::Empty)
+        );
+        drop(last);
+        assert_eq!(
+            done_rx.recv_timeout(Duration::from_secs(1)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+}
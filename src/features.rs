@@ -0,0 +1,171 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use audio::frequency_sensor::Features;
+use serde::{Deserialize, Serialize};
+
+/// Spectrum is what the visualizer renders from, either live features or
+/// ones replayed from a recording.
+pub trait Spectrum {
+    fn scales(&self) -> &[f64];
+    fn energy(&self) -> &[f64];
+    /// Amplitudes of each bin `i` frames ago.
+    fn amplitudes(&self, i: usize) -> &[f64];
+}
+
+impl Spectrum for Features {
+    fn scales(&self) -> &[f64] {
+        self.get_scales()
+    }
+
+    fn energy(&self) -> &[f64] {
+        self.get_energy()
+    }
+
+    fn amplitudes(&self, i: usize) -> &[f64] {
+        self.get_amplitudes(i)
+    }
+}
+
+/// Serializable snapshot of the features of one audio channel.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FeatureFrame {
+    pub frame: usize,
+    pub channel: usize,
+    pub scales: Vec<f64>,
+    pub energy: Vec<f64>,
+    /// Amplitudes of each bin over the last `length` frames.
+    pub amplitudes: Vec<Vec<f64>>,
+}
+
+impl FeatureFrame {
+    pub fn new(features: &Features, channel: usize, length: usize) -> Self {
+        Self {
+            frame: features.get_frame_count(),
+            channel,
+            scales: features.get_scales().to_vec(),
+            energy: features.get_energy().to_vec(),
+            amplitudes: (0..length)
+                .map(|i| features.get_amplitudes(i).to_vec())
+                .collect(),
+        }
+    }
+}
+
+impl Spectrum for FeatureFrame {
+    fn scales(&self) -> &[f64] {
+        &self.scales
+    }
+
+    fn energy(&self) -> &[f64] {
+        &self.energy
+    }
+
+    fn amplitudes(&self, i: usize) -> &[f64] {
+        let last = self.amplitudes.len().saturating_sub(1);
+        self.amplitudes.get(usize::min(i, last)).map_or(&[], |a| a)
+    }
+}
+
+/// Load line delimited JSON feature frames as streamed by `FeatureSink`,
+/// grouping the channels of each frame together.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Vec<FeatureFrame>>> {
+    let f = File::open(path)?;
+    let mut frames: Vec<Vec<FeatureFrame>> = Vec::new();
+    for line in BufReader::new(f).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let frame: FeatureFrame = serde_json::from_str(&line)?;
+        match frames.last_mut() {
+            Some(group) if group[0].frame == frame.frame && frame.channel > 0 => group.push(frame),
+            _ => frames.push(vec![frame]),
+        }
+    }
+    Ok(frames)
+}
+
+/// Destination for line delimited JSON feature frames.
+pub enum FeatureSink {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+impl FeatureSink {
+    /// Connect to an address like `tcp://host:port` or `udp://host:port`.
+    pub fn connect(url: &str) -> Result<Self> {
+        if let Some(addr) = url.strip_prefix("tcp://") {
+            Ok(FeatureSink::Tcp(TcpStream::connect(addr)?))
+        } else if let Some(addr) = url.strip_prefix("udp://") {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(addr)?;
+            Ok(FeatureSink::Udp(socket))
+        } else {
+            Err(anyhow!("expected tcp:// or udp:// address, got {}", url))
+        }
+    }
+
+    pub fn send(&mut self, frame: &FeatureFrame) -> Result<()> {
+        let mut line = serde_json::to_string(frame)?;
+        line.push('\n');
+        match self {
+            FeatureSink::Tcp(stream) => stream.write_all(line.as_bytes())?,
+            FeatureSink::Udp(socket) => {
+                socket.send(line.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feature_frame(frame: usize, channel: usize) -> FeatureFrame {
+        FeatureFrame {
+            frame,
+            channel,
+            scales: vec![1., 0.5],
+            energy: vec![0.25, 0.75],
+            amplitudes: vec![vec![0.1, 0.2], vec![0.3, 0.4]],
+        }
+    }
+
+    #[test]
+    fn feature_frame_json_round_trip() {
+        let frame = feature_frame(7, 1);
+        let line = serde_json::to_string(&frame).unwrap();
+        assert!(!line.contains('\n'));
+        assert_eq!(serde_json::from_str::<FeatureFrame>(&line).unwrap(), frame);
+    }
+
+    #[test]
+    fn loaded_json_groups_channels_by_frame() {
+        let path = std::env::temp_dir().join(format!("features-{}.jsonl", std::process::id()));
+        let frames = [
+            feature_frame(1, 0),
+            feature_frame(1, 1),
+            feature_frame(2, 0),
+        ];
+        let lines: Vec<String> = frames
+            .iter()
+            .map(|f| serde_json::to_string(f).unwrap())
+            .collect();
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        let loaded = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, vec![frames[..2].to_vec(), frames[2..].to_vec()]);
+    }
+
+    #[test]
+    fn amplitudes_past_the_history_repeat_the_oldest() {
+        let frame = feature_frame(0, 0);
+        assert_eq!(frame.amplitudes(1), &[0.3, 0.4]);
+        assert_eq!(frame.amplitudes(5), &[0.3, 0.4]);
+    }
+}
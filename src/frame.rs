@@ -0,0 +1,142 @@
+use log::{debug, warn};
+
+use crate::apa102::ARGB8;
+use crate::display::Frame;
+
+/// Frame of `pixels` numbered `seq`, with a checksum if `check` is set, so
+/// the output can tell when frames were dropped or corrupted on the way.
+pub fn numbered(seq: u64, pixels: Vec<ARGB8>, check: bool) -> Frame<ARGB8> {
+    let mut frame = Frame::new(pixels);
+    frame.seq = Some(seq);
+    if check {
+        frame.checksum = Some(checksum(&frame.pixels));
+    }
+    frame
+}
+
+/// Whether the pixels still match the checksum, if there is one.
+pub fn is_intact(frame: &Frame<ARGB8>) -> bool {
+    frame
+        .checksum
+        .map_or(true, |c| c == checksum(&frame.pixels))
+}
+
+/// FNV-1a hash of the pixel bytes.
+pub fn checksum(pixels: &[ARGB8]) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for p in pixels {
+        for &b in &[p.a, p.r, p.g, p.b] {
+            hash ^= b as u32;
+            hash = hash.wrapping_mul(0x0100_0193);
+        }
+    }
+    hash
+}
+
+/// GapDetector counts the sequence numbers skipped between frames.
+#[derive(Default)]
+pub struct GapDetector {
+    next: Option<u64>,
+}
+
+impl GapDetector {
+    /// Number of frames missing before the one numbered `seq`.
+    pub fn check(&mut self, seq: u64) -> u64 {
+        let missing = match self.next {
+            Some(next) => seq.saturating_sub(next),
+            None => 0,
+        };
+        self.next = Some(seq + 1);
+        missing
+    }
+}
+
+/// FrameCheck checks the frames reaching the output, so frames dropped by
+/// any stage between the renderer and the output are caught.
+#[derive(Default)]
+pub struct FrameCheck {
+    gaps: GapDetector,
+}
+
+impl FrameCheck {
+    /// Number of frames dropped before this one, logging it if it was
+    /// corrupted. Frames which weren't numbered aren't checked.
+    pub fn check(&mut self, frame: &Frame<ARGB8>) -> u64 {
+        let seq = match frame.seq {
+            Some(seq) => seq,
+            None => return 0,
+        };
+        let missing = self.gaps.check(seq);
+        if missing > 0 {
+            debug!("dropped {} frames before frame {}", missing, seq);
+        }
+        if !is_intact(frame) {
+            warn!("frame {} failed its checksum", seq);
+        }
+        missing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::sync_channel;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::display;
+
+    #[test]
+    fn gaps_count_the_skipped_frames() {
+        let mut gaps = GapDetector::default();
+        let missing: Vec<u64> = [3, 4, 5, 8, 9, 20].iter().map(|&s| gaps.check(s)).collect();
+        assert_eq!(missing, vec![0, 0, 0, 2, 0, 10]);
+        // a restarted sequence isn't counted as missing frames
+        assert_eq!(gaps.check(0), 0);
+        assert_eq!(gaps.check(2), 1);
+    }
+
+    #[test]
+    fn checksums_catch_corrupted_pixels() {
+        let mut frame = numbered(0, vec![ARGB8::new(31, 1, 2, 3); 4], true);
+        assert!(is_intact(&frame));
+        frame.pixels[2].g = 0;
+        assert!(!is_intact(&frame));
+        assert!(is_intact(&numbered(0, Vec::new(), false)));
+
+        // stages which rewrite the pixels drop the checksum along with them
+        frame.set_pixels(vec![ARGB8::new(31, 0, 0, 0); 4]);
+        assert_eq!(frame.checksum, None);
+        assert!(is_intact(&frame));
+    }
+
+    #[test]
+    fn frames_dropped_before_the_output_are_counted() {
+        let (tx, rx) = sync_channel(16);
+        let rx = display::spawn_latest(rx);
+        let frame = |seq| numbered(seq, vec![ARGB8::new(31, 1, 2, 3)], true);
+        let mut check = FrameCheck::default();
+        tx.send(frame(0)).unwrap();
+        assert_eq!(check.check(&rx.recv().unwrap()), 0);
+
+        for seq in 1..16 {
+            tx.send(frame(seq)).unwrap();
+        }
+        drop(tx);
+        thread::sleep(Duration::from_millis(20));
+
+        let (mut received, mut missing) = (1, 0);
+        while let Ok(frame) = rx.recv() {
+            // a slow output, so the latest frame display skips some
+            thread::sleep(Duration::from_millis(5));
+            missing += check.check(&frame);
+            received += 1;
+        }
+        assert!(received < 16);
+        // the newest frame always gets through
+        assert_eq!(received + missing, 16);
+        // frames which weren't numbered aren't counted
+        assert_eq!(check.check(&Frame::new(Vec::new())), 0);
+    }
+}
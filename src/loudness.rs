@@ -0,0 +1,112 @@
+use crate::features::Spectrum;
+
+/// Normalizer tracks the long-term average loudness of the input and gives the
+/// factor which brings it to a target level, so the visualizer fills its range
+/// in a quiet room as well as a loud one.
+pub struct Normalizer {
+    target: f64,
+    // seconds for the average to follow a change in level by 1 - 1/e
+    adapt: f64,
+    // lowest average which is brought up to the target
+    floor: f64,
+    average: Option<f64>,
+}
+
+impl Normalizer {
+    pub fn new(target: f64, adapt: f64, floor: f64) -> Self {
+        Self {
+            target,
+            adapt,
+            floor,
+            average: None,
+        }
+    }
+
+    /// Update the average with the `level` of a frame lasting `dt` seconds,
+    /// returning the factor to scale the frame by.
+    pub fn update(&mut self, level: f64, dt: f64) -> f64 {
+        let level = level.abs();
+        self.average = Some(match self.average {
+            Some(average) if self.adapt > 0. => {
+                let w = f64::exp(-dt / self.adapt);
+                w * average + (1. - w) * level
+            }
+            _ => level,
+        });
+        self.factor()
+    }
+
+    /// Factor bringing the average level to the target.
+    pub fn factor(&self) -> f64 {
+        match self.average {
+            Some(average) => self.target / f64::max(average, self.floor),
+            None => 1.,
+        }
+    }
+}
+
+/// Spectrum with its scales and energy multiplied by a normalization factor.
+pub struct Normalized<'a, F> {
+    features: &'a F,
+    scales: Vec<f64>,
+    energy: Vec<f64>,
+}
+
+impl<'a, F: Spectrum> Normalized<'a, F> {
+    pub fn new(features: &'a F, factor: f64) -> Self {
+        Self {
+            features,
+            scales: features.scales().iter().map(|s| factor * s).collect(),
+            energy: features.energy().iter().map(|e| factor * e).collect(),
+        }
+    }
+}
+
+impl<'a, F: Spectrum> Spectrum for Normalized<'a, F> {
+    fn scales(&self) -> &[f64] {
+        &self.scales
+    }
+
+    fn energy(&self) -> &[f64] {
+        &self.energy
+    }
+
+    fn amplitudes(&self, i: usize) -> &[f64] {
+        self.features.amplitudes(i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_in_level_converges_to_the_target() {
+        let (target, dt) = (0.5, 0.01);
+        let mut normalizer = Normalizer::new(target, 1., 0.01);
+        let output = |n: &mut Normalizer, level: f64| level * n.update(level, dt);
+        for _ in 0..1000 {
+            output(&mut normalizer, 0.1);
+        }
+        assert!((output(&mut normalizer, 0.1) - target).abs() < 1e-3);
+
+        // the room gets four times louder, which is brought back down
+        let first = output(&mut normalizer, 0.4);
+        assert!(first > 1.5 * target);
+        let mut last = first;
+        for _ in 0..1000 {
+            let out = output(&mut normalizer, 0.4);
+            assert!(out <= last + 1e-12);
+            last = out;
+        }
+        assert!((last - target).abs() < 1e-3, "{}", last);
+    }
+
+    #[test]
+    fn quiet_input_is_only_raised_to_the_floor() {
+        let mut normalizer = Normalizer::new(0.5, 0., 0.1);
+        assert_eq!(normalizer.factor(), 1.);
+        assert_eq!(normalizer.update(0.001, 0.01), 5.);
+        assert_eq!(normalizer.update(0.25, 0.01), 2.);
+    }
+}
@@ -1,23 +1,61 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
 #[macro_use]
 extern crate lazy_static;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::Clap;
-use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
-use serde::{Deserialize, Serialize};
+use log::{debug, error, LevelFilter};
 use serde_yaml;
 
 use audio::frequency_sensor::FrequencySensorParams;
 
 mod apa102;
-use apa102::{Apa102, ARGB8};
+use apa102::{Apa102, ChipType, ColorOrder, Strip, ARGB8};
+mod beat;
+mod blend;
+mod bucket;
+mod calibrate;
+use bucket::BucketScale;
+mod config;
+mod daemon;
+mod device;
+mod envelope;
+use config::{Config, Shared};
 mod display;
-use display::Display;
+use display::{ChainTransform, Display, Done, FanOut, Frame, FrameLimiter};
+mod fade;
+mod features;
+mod frame;
+use frame::FrameCheck;
+mod loudness;
+mod metrics;
+use metrics::Metrics;
+mod osc;
+mod pattern;
+use pattern::Pattern;
+mod preview;
+mod record;
+mod schedule;
+mod silence;
+mod sk6812;
+mod sparkle;
+mod spi;
+mod strobe;
+mod synth;
+use sk6812::Sk6812Rgbw;
 mod transform;
-use transform::Transform;
+use transform::{Axis, GammaTransform, MirrorTransform, PixelCorrection, Rotation, Transform};
 mod visualizer;
+mod wav;
+mod web;
+mod ws2812;
+use ws2812::Ws2812;
+mod zone;
 
 /// LED Strip Visualizer
 #[derive(Clap)]
@@ -27,27 +65,216 @@ struct Opts {
     #[clap(short, long, parse(from_occurrences))]
     verbose: i32,
 
-    /// Don't actually load SPI or output anything
+    /// Don't actually load SPI or output anything, always set when built
+    /// without the hardware feature
     #[clap(short = 'n', long)]
     dry_run: bool,
+    /// Print a few pixels of every nth frame when doing a dry run
+    #[clap(long)]
+    sample: Option<usize>,
     /// Number of LEDs in strips
     length: u16,
     /// SPI clock speed in hz
     #[clap(default_value = "4000000")]
     spi_clock: u32,
+    /// Split the output across consecutive SPI buses with these numbers of
+    /// LEDs on each, eg. 300,300
+    #[clap(long, use_delimiter = true)]
+    spi_buses: Vec<u16>,
+    /// SPI bus to write to, from 0 to 6, or the first when splitting output
+    #[clap(long, default_value = "0", parse(try_from_str = spi::parse_bus))]
+    spi_bus: u8,
+    /// SPI chip select, from 0 to 2
+    #[clap(long, default_value = "0", parse(try_from_str = spi::parse_cs))]
+    spi_cs: u8,
+    /// SPI mode, from 0 to 3
+    #[clap(long, default_value = "0")]
+    spi_mode: spi::Mode,
+    /// LED strip type: apa102, sk9822, ws2812 or sk6812-rgbw
+    #[clap(long, default_value = "apa102")]
+    strip: StripType,
+    /// Color byte order of apa102 type strips, eg. bgr or grb
+    #[clap(long, default_value = "bgr")]
+    color_order: ColorOrder,
+    /// Master brightness of apa102 type strips, from 0.0 to 1.0
+    #[clap(long, default_value = "1.0")]
+    brightness: f32,
+    /// Gamma correction applied by the apa102 driver, either one gamma or
+    /// separate red, green and blue gammas, eg. 2.2,2.4,2.8
+    #[clap(long, default_value = "1.0", use_delimiter = true)]
+    strip_gamma: Vec<f64>,
+    /// Dither apa102 output over time to keep low levels smooth after gamma
+    #[clap(long)]
+    dither: bool,
+    /// Scale apa102 output down to keep the estimated current under this
+    /// many milliamps
+    #[clap(long)]
+    power_limit: Option<u32>,
+    /// Estimated milliamps drawn by one color channel at full brightness
+    #[clap(long, default_value = "20")]
+    channel_milliamps: f32,
+    /// Strip layout mapping file
+    #[clap(long)]
+    layout: Option<String>,
+    /// Lay the LEDs out as this many rows of a single strip snaking back and
+    /// forth, instead of the default 4 strips
+    #[clap(long)]
+    serpentine: Option<u8>,
+    /// Coordinate mapping file giving the position of each LED, for layouts
+    /// which aren't made of strips
+    #[clap(long)]
+    coord_map: Option<String>,
+    /// Rotate the image clockwise by 0, 90, 180 or 270 degrees
+    #[clap(long, default_value = "0")]
+    rotate: Rotation,
+    /// Mirror half of the image onto the other: horizontal or vertical
+    #[clap(long)]
+    mirror: Option<Axis>,
+    /// Separate red, green and blue gamma correction to white balance the
+    /// output, eg. 1.0,1.1,1.3
+    #[clap(long, use_delimiter = true)]
+    channel_gamma: Vec<f64>,
+    /// Per-LED correction file listing dead LEDs and color gains
+    #[clap(long)]
+    correction: Option<String>,
+    /// Maximum rate to output frames at
+    #[clap(long)]
+    max_fps: Option<f64>,
+    /// Number of frames which can be queued for output
+    #[clap(long, default_value = "0")]
+    frame_buffer: usize,
+    /// Skip to the newest frame when the output falls behind, rather than
+    /// holding up rendering
+    #[clap(long)]
+    latest_frame: bool,
+    /// Preview output instead of writing to the strip: terminal
+    #[clap(long)]
+    preview: Option<Preview>,
+    /// Preview output in the terminal as well as writing it to the strip
+    #[clap(long)]
+    tee_preview: bool,
+    /// Serve the config and status over http on this port
+    #[clap(long)]
+    web: Option<u16>,
+    /// Fade in on start and out on exit over this many milliseconds
+    #[clap(long)]
+    fade_time: Option<u64>,
+    /// Smooth out flashing faster than this many full brightness flashes a
+    /// second
+    #[clap(long)]
+    max_flash_hz: Option<f64>,
+    /// Seconds between reports of frame rate, drops and latency
+    #[clap(long, default_value = "5")]
+    metrics_interval: f64,
+    /// Print the metrics reports as JSON lines
+    #[clap(long)]
+    metrics_json: bool,
 
     #[clap(subcommand)]
     cmd: Command,
 }
 
+#[derive(Copy, Clone)]
+enum StripType {
+    Apa102,
+    Sk9822,
+    Ws2812,
+    Sk6812Rgbw,
+}
+
+impl std::str::FromStr for StripType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "apa102" => Ok(StripType::Apa102),
+            "sk9822" => Ok(StripType::Sk9822),
+            "ws2812" => Ok(StripType::Ws2812),
+            "sk6812-rgbw" => Ok(StripType::Sk6812Rgbw),
+            _ => Err(anyhow::anyhow!("unknown strip type: {}", s)),
+        }
+    }
+}
+
+/// Settings of the strip driver, most of which only apply to apa102 type
+/// strips.
+#[derive(Copy, Clone)]
+struct DriverOpts {
+    order: ColorOrder,
+    brightness: f32,
+    gamma: [f64; 3],
+    dither: bool,
+    power_limit: Option<u32>,
+    channel_milliamps: f32,
+}
+
+impl DriverOpts {
+    fn apa102(self, length: u16, chip: ChipType) -> Apa102 {
+        let mut leds = Apa102::with_gamma(length, chip, self.order, self.gamma);
+        leds.set_brightness(self.brightness);
+        leds.enable_dithering(self.dither);
+        leds.set_power_limit(self.power_limit);
+        leds.set_channel_milliamps(self.channel_milliamps);
+        leds
+    }
+}
+
+impl StripType {
+    fn build(self, length: u16, driver: DriverOpts) -> Box<dyn Strip> {
+        match self {
+            StripType::Apa102 => Box::new(driver.apa102(length, ChipType::Apa102)),
+            StripType::Sk9822 => Box::new(driver.apa102(length, ChipType::Sk9822)),
+            StripType::Ws2812 => Box::new(Ws2812::new(length)),
+            StripType::Sk6812Rgbw => Box::new(Sk6812Rgbw::new(length)),
+        }
+    }
+
+    /// Clockless strips encode their timing in the data, so need a fixed clock.
+    fn spi_clock(self, spi_clock: u32) -> u32 {
+        match self {
+            StripType::Ws2812 | StripType::Sk6812Rgbw => ws2812::SPI_CLOCK,
+            _ => spi_clock,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Preview {
+    Terminal,
+}
+
+impl std::str::FromStr for Preview {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "terminal" => Ok(Preview::Terminal),
+            _ => Err(anyhow::anyhow!("unknown preview: {}", s)),
+        }
+    }
+}
+
 #[derive(Clap)]
 enum Command {
     Init,
+    Config(ConfigOpts),
     Set(SetOpts),
+    Pattern(PatternOpts),
+    Breathe(BreatheOpts),
+    Calibrate(CalibrateOpts),
+    Zones(ZonesOpts),
+    Daemon(DaemonOpts),
     Test(TestOpts),
     Visualizer(visualizer::Opts),
 }
 
+/// Print the effective config
+#[derive(Clap)]
+struct ConfigOpts {
+    #[clap(flatten)]
+    overrides: visualizer::ParamOverrides,
+}
+
 /// Set all LEDs a single color
 #[derive(Clap)]
 struct SetOpts {
@@ -60,6 +287,79 @@ struct SetOpts {
     /// Alpha
     #[clap(default_value = "31")]
     alpha: u8,
+    /// Fade in from black over this many milliseconds, since whatever was
+    /// shown before isn't known
+    #[clap(long)]
+    fade: Option<u64>,
+}
+
+/// Animate a pattern without audio input
+#[derive(Clap)]
+struct PatternOpts {
+    /// Pattern to show: rainbow, plasma or fire
+    pattern: Pattern,
+    /// Frames per second to render
+    #[clap(long, default_value = "60")]
+    fps: f64,
+    /// Animation speed multiplier
+    #[clap(long, default_value = "1.0")]
+    speed: f64,
+    /// Alpha
+    #[clap(long, default_value = "31")]
+    alpha: u8,
+}
+
+/// Pulse all LEDs a single color
+#[derive(Clap)]
+struct BreatheOpts {
+    /// Red
+    red: u8,
+    /// Green
+    green: u8,
+    /// Blue
+    blue: u8,
+    /// Seconds per breath
+    #[clap(long, default_value = "4.0")]
+    period: f64,
+    /// Frames per second to render
+    #[clap(long, default_value = "60")]
+    fps: f64,
+}
+
+/// Cycle through red, green, blue and white to tune the white balance
+#[derive(Clap)]
+struct CalibrateOpts {
+    /// Seconds to show each color for
+    #[clap(long, default_value = "2")]
+    step: f64,
+}
+
+/// Drive named zones of the strip independently
+#[derive(Clap)]
+struct ZonesOpts {
+    /// YAML file listing the zones
+    #[clap(default_value = "zones.yaml")]
+    file: String,
+    /// Frames per second to render
+    #[clap(long, default_value = "60")]
+    fps: f64,
+}
+
+/// Run as a service taking commands like `set 255 0 0 fade 500`,
+/// `pattern plasma`, `breathe 0 0 255`, `visualizer` or `off` on a unix socket
+#[derive(Clap)]
+struct DaemonOpts {
+    /// Path of the control socket
+    #[clap(long, default_value = "/tmp/led-strip.sock")]
+    socket: String,
+    /// Frames per second to render animations at
+    #[clap(long, default_value = "60")]
+    fps: f64,
+    /// YAML file of commands to run by time of day
+    #[clap(long)]
+    schedule: Option<String>,
+    #[clap(flatten)]
+    visualizer: visualizer::Opts,
 }
 
 /// Run tests
@@ -76,7 +376,13 @@ struct TestOpts {
 enum TestCommand {
     Fps,
     Transform,
+    /// Print the strip layout as a mapping file for --layout
+    Layout,
     Audio(TestAudioOpts),
+    Pipeline(TestPipelineOpts),
+    Record(TestRecordOpts),
+    Replay(TestReplayOpts),
+    Ramp(TestRampOpts),
 }
 
 #[derive(Clap)]
@@ -85,36 +391,86 @@ struct TestAudioOpts {
     show_configs: bool,
     // #[clap(default_value = "default")]
     device: Option<String>,
+    /// Lowest frequency of the printed buckets in Hz
+    #[clap(long, default_value = "32")]
+    freq_min: f64,
+    /// Highest frequency of the printed buckets in Hz
+    #[clap(long, default_value = "16000")]
+    freq_max: f64,
+    /// Spacing of the buckets: log, linear or mel
+    #[clap(long, default_value = "log")]
+    bucket_scale: BucketScale,
+}
+
+/// Benchmark the audio analysis pipeline with a synthetic signal
+#[derive(Clap)]
+struct TestPipelineOpts {
+    #[clap(long, default_value = "1024")]
+    fft_size: usize,
+    #[clap(long, default_value = "256", parse(try_from_str = synth::parse_block_size))]
+    block_size: usize,
+    #[clap(long, default_value = "16")]
+    bins: usize,
+    #[clap(long, default_value = "144")]
+    length: usize,
+}
+
+/// Record the visualizer to a PNG sequence
+#[derive(Clap)]
+struct TestRecordOpts {
+    /// Directory to write frames into
+    dir: String,
+    /// Number of frames to record
+    #[clap(long, default_value = "100")]
+    frames: usize,
+    #[clap(flatten)]
+    visualizer: visualizer::Opts,
+}
+
+/// Render features recorded with `visualizer --features-out`
+#[derive(Clap)]
+struct TestReplayOpts {
+    /// File of JSON lines feature frames
+    file: String,
+    /// Frames per second to render
+    #[clap(long, default_value = "60")]
+    fps: f64,
+    #[clap(flatten)]
+    visualizer: visualizer::Opts,
+}
+
+/// Show a brightness ramp along the strip for tuning gamma
+#[derive(Clap)]
+struct TestRampOpts {
+    /// Apply the configured gamma to the ramp
+    #[clap(long)]
+    gamma: bool,
 }
 
 struct App {
     display: Display<ARGB8>,
+    /// Size of the logical frame the layout takes, as (width, height).
+    size: (usize, usize),
+    metrics: Arc<Metrics>,
     config: Config,
+    shared: Shared,
+    output: thread::JoinHandle<()>,
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
-struct Config {
-    audio: FrequencySensorParams,
-    visualizer: visualizer::Params,
-}
-
-impl Config {
-    const CONFIG_FILE: &'static str = ".ledconfig.yaml";
+impl App {
+    /// Number of pixels in a logical frame.
+    fn frame_len(&self) -> usize {
+        self.size.0 * self.size.1
+    }
 
-    fn default() -> Self {
-        Self {
-            audio: FrequencySensorParams::defaults(),
-            visualizer: visualizer::Params::defaults(),
-        }
+    /// Size of the logical frame as the visualizer renders it, as (length of
+    /// each strip, number of strips).
+    fn strips(&self) -> (usize, usize) {
+        (self.size.1, self.size.0)
     }
 }
 
-fn setup(opts: &Opts) -> Result<App> {
-    let length = opts.length;
-    let spi_clock = opts.spi_clock;
-    let dry_run = opts.dry_run;
-    let verbose = opts.verbose;
-
+fn load_config(opts: &Opts) -> Result<Config> {
     let config = match std::fs::File::open(Config::CONFIG_FILE) {
         Ok(f) => serde_yaml::from_reader(f)?,
         Err(_) => {
@@ -126,80 +482,466 @@ fn setup(opts: &Opts) -> Result<App> {
             config
         }
     };
+    Ok(config)
+}
 
-    let (display, frame_rx) = Display::new();
+/// Print the effective config as YAML, with warnings for out of range values.
+fn dump_config(opts: &Opts, overrides: &visualizer::ParamOverrides) -> Result<()> {
+    let mut config = load_config(opts)?;
+    overrides.apply(&mut config.visualizer)?;
+    for warning in config.visualizer.warnings() {
+        eprintln!("warning: {}", warning);
+    }
+    print!("{}", serde_yaml::to_string(&config)?);
+    Ok(())
+}
+
+fn dump_layout(opts: &Opts) -> Result<()> {
+    let layout = build_transform(opts)?
+        .layout()
+        .ok_or_else(|| anyhow!("a coordinate mapped layout has no strips"))?;
+    print!("{}", serde_yaml::to_string(&layout)?);
+    Ok(())
+}
 
+/// Fade output in, and install a handler for SIGINT and SIGTERM which fades it
+/// out and leaves the strip black before exiting.
+fn blackout_on_exit(
+    display: &Display<ARGB8>,
+    frame_rx: Receiver<Frame<ARGB8>>,
+    frame_len: usize,
+    duration: Duration,
+) -> Result<Receiver<Frame<ARGB8>>> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let (done_tx, done_rx) = channel();
+    let frame_rx = fade::spawn(frame_rx, duration, shutdown.clone(), done_tx);
+    let sink = display.sink();
+    ctrlc::set_handler(move || {
+        shutdown.store(true, Ordering::SeqCst);
+        // wakes the fade thread, and is what gets blacked out if nothing was shown
+        let _ = sink.send(Frame::new(fade::blackout(frame_len)));
+        // disconnected once the black frame has been written out, giving up
+        // if the output thread has stalled
+        let _ = done_rx.recv_timeout(duration + Duration::from_secs(1));
+        std::process::exit(0);
+    })?;
+    Ok(frame_rx)
+}
+
+/// Write each frame sent to SPI bus number `bus` from its own thread, so
+/// several buses are written in parallel.
+fn spawn_bus(
+    spi_opts: spi::Options,
+    strip: StripType,
+    length: u16,
+    driver: DriverOpts,
+    metrics: Arc<Metrics>,
+) -> SyncSender<(Vec<ARGB8>, Option<Done>)> {
+    let (tx, rx) = sync_channel::<(Vec<ARGB8>, Option<Done>)>(1);
+    let bus = spi_opts.bus;
     thread::spawn(move || {
-        let mut fps = 0;
-        let mut then = std::time::SystemTime::now();
-        let mut print_fps = || {
-            fps += 1;
-            if verbose > 0 && fps % 256 == 0 {
-                let now = std::time::SystemTime::now();
-                if let Ok(e) = now.duration_since(then) {
-                    then = now;
-                    println!("FPS: {}", fps as f64 / e.as_secs_f64());
-                }
-                fps = 0;
+        let mut spi = spi::AdaptiveSpi::open(spi_opts).expect("failed to open spi bus");
+        let mut leds = strip.build(length, driver);
+        // the frame's done handle is held until it's been written
+        while let Ok((frame, _done)) = rx.recv() {
+            if let Err(e) = leds.update(&frame) {
+                error!("failed to update leds on spi bus {}: {}", bus, e);
+                continue;
+            }
+            let buffer = leds.get_buffer();
+            if let Err(e) = spi.write(buffer) {
+                metrics.write_failed();
+                error!("failed to write to spi bus {}: {:}", bus, e);
+            }
+        }
+    });
+    tx
+}
+
+fn setup(opts: &Opts) -> Result<App> {
+    let length = opts.length;
+    let spi_clock = opts.strip.spi_clock(opts.spi_clock);
+    let dry_run = opts.dry_run || !cfg!(feature = "hardware");
+    let strip = opts.strip;
+    let driver = DriverOpts {
+        order: opts.color_order,
+        brightness: opts.brightness,
+        gamma: match opts.strip_gamma[..] {
+            [gamma] => [gamma; 3],
+            [r, g, b] => [r, g, b],
+            _ => {
+                return Err(anyhow!(
+                    "--strip-gamma needs one gamma, or red, green and blue"
+                ))
             }
+        },
+        dither: opts.dither,
+        power_limit: opts.power_limit,
+        channel_milliamps: opts.channel_milliamps,
+    };
+    let mut limiter = opts.max_fps.map(FrameLimiter::new);
+    let preview = opts.preview;
+    let sample = opts.sample.map(|n| usize::max(n, 1));
+    let spi_buses = opts.spi_buses.clone();
+    let spi_opts = spi::Options {
+        bus: opts.spi_bus,
+        cs: opts.spi_cs,
+        mode: opts.spi_mode,
+        clock: spi_clock,
+    };
+    let record = match &opts.cmd {
+        Command::Test(TestOpts {
+            cmd: TestCommand::Record(r),
+            ..
+        }) => Some((r.dir.clone(), r.frames)),
+        _ => None,
+    };
+
+    let config = load_config(opts)?;
+    config.visualizer.validate().map_err(|e| {
+        anyhow!(
+            "invalid visualizer params in {}: {}",
+            Config::CONFIG_FILE,
+            e
+        )
+    })?;
+
+    let transform = build_transform(opts)?;
+
+    let correction = match &opts.correction {
+        Some(path) => PixelCorrection::from_file(path)?,
+        None => PixelCorrection::default(),
+    };
+
+    let shared = Shared::new(config.clone());
+    let fps_status = Arc::new(RwLock::new(0.));
+    if let Some(port) = opts.web {
+        web::serve(port, shared.clone(), fps_status.clone())?;
+    }
+    let metrics = Arc::new(Metrics::default());
+    metrics::spawn_reporter(
+        metrics.clone(),
+        Duration::from_secs_f64(opts.metrics_interval),
+        opts.metrics_json,
+        fps_status,
+    );
+    let output_metrics = metrics.clone();
+
+    let (display, frame_rx) = Display::with_capacity(opts.frame_buffer);
+    let (width, height) = transform.input_size();
+    let fade_time = Duration::from_millis(opts.fade_time.unwrap_or(0));
+    let frame_rx = match opts.max_flash_hz {
+        Some(max_hz) => strobe::spawn(frame_rx, max_hz),
+        None => frame_rx,
+    };
+    // after the limiter, so it can't hold back the blackout on exit
+    let frame_rx = blackout_on_exit(&display, frame_rx, width * height, fade_time)?;
+    // effects on the logical frame, before it's mapped onto the strips
+    let mut effects = ChainTransform::new(Vec::new());
+    if let Some(axis) = opts.mirror {
+        effects.push(Box::new(MirrorTransform::new(width, height, axis)));
+    }
+    match opts.channel_gamma[..] {
+        [] => (),
+        [r, g, b] => effects.push(Box::new(GammaTransform::new(r, g, b))),
+        _ => return Err(anyhow!("--channel-gamma needs red, green and blue gammas")),
+    }
+    let frame_rx = match effects.is_empty() {
+        true => frame_rx,
+        false => display::spawn_transform(frame_rx, effects),
+    };
+    let frame_rx = match opts.tee_preview {
+        true => {
+            let (preview_tx, preview_rx) = sync_channel(1);
+            thread::spawn(move || preview::run_terminal(preview_rx, width, height));
+            display::spawn_fan_out(frame_rx, FanOut::new(vec![preview_tx]))
+        }
+        false => frame_rx,
+    };
+    let frame_rx = match opts.latest_frame {
+        true => display::spawn_latest(frame_rx),
+        false => frame_rx,
+    };
+
+    let output = thread::spawn(move || {
+        let metrics = output_metrics;
+        if let Some((dir, frames)) = record {
+            let (width, height) = transform.input_size();
+            if let Err(e) = record::run(frame_rx, &dir, frames, width, height) {
+                error!("failed to record frames: {}", e);
+            }
+            return;
+        }
+
+        // frames are checked here, after every stage which might drop them
+        let mut check = FrameCheck::default();
+        let mut written = |frame: &Frame<ARGB8>| {
+            metrics.missed(check.check(frame));
+            metrics.written(frame.latency(Instant::now()));
         };
 
+        if let Some(Preview::Terminal) = preview {
+            let (width, height) = transform.input_size();
+            preview::run_terminal(frame_rx, width, height);
+            return;
+        }
+
         if dry_run {
-            loop {
-                if let Err(e) = frame_rx.recv() {
-                    println!("error receiving frame: {}", e);
-                    break;
+            for n in 0.. {
+                let frame = match frame_rx.recv() {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        error!("error receiving frame: {}", e);
+                        break;
+                    }
+                };
+                if let Some(every) = sample {
+                    if n % every == 0 {
+                        println!("frame {}: {}", n, preview::sample(&frame.pixels));
+                    }
+                }
+                if let Some(limiter) = &mut limiter {
+                    limiter.wait();
                 }
-                print_fps();
+                written(&frame);
             }
             return;
         }
 
-        let mut spi = Spi::new(Bus::Spi0, SlaveSelect::Ss0, spi_clock, Mode::Mode0)
-            .expect("failed to open spi bus");
-        let mut leds = Apa102::new(length);
-        let transform = Transform::new(4, 144, vec![false, true, false, true], vec![0, 2, 1, 3]);
+        if spi_buses.len() > 1 {
+            let lengths: Vec<usize> = spi_buses.iter().map(|&l| l as usize).collect();
+            let buses: Vec<_> = spi_buses
+                .iter()
+                .enumerate()
+                .map(|(i, &length)| {
+                    let spi_opts = spi::Options {
+                        bus: spi_opts.bus + i as u8,
+                        ..spi_opts
+                    };
+                    spawn_bus(spi_opts, strip, length, driver, metrics.clone())
+                })
+                .collect();
+            while let Ok(frame) = frame_rx.recv() {
+                if let Some(limiter) = &mut limiter {
+                    limiter.wait();
+                }
+                let pixels: Vec<ARGB8> = correction
+                    .apply_iter(transform.apply_iter(&frame.pixels))
+                    .collect();
+                for (bus, part) in buses.iter().zip(spi::split(&pixels, &lengths)) {
+                    if bus.send((part.to_vec(), frame.done())).is_err() {
+                        error!("spi bus output stopped");
+                        return;
+                    }
+                }
+                written(&frame);
+            }
+            error!("uh-oh, dead");
+            return;
+        }
 
+        let mut spi = spi::AdaptiveSpi::open(spi_opts).expect("failed to open spi bus");
+        let mut leds = strip.build(length, driver);
+
+        // each frame is dropped once written, which wakes anything waiting on it
         while let Ok(frame) = frame_rx.recv() {
-            let frame = transform.apply(&frame);
-            leds.update(&frame);
-            if let Err(e) = spi.write(leds.get_buffer()) {
-                println!("failed to write to spi bus: {:}", e);
+            if let Some(limiter) = &mut limiter {
+                limiter.wait();
+            }
+            let mut pixels = correction.apply_iter(transform.apply_iter(&frame.pixels));
+            if let Err(e) = leds.update_iter(&mut pixels) {
+                error!("failed to update leds: {}", e);
+                continue;
             }
-            print_fps();
+            if leds.power_scale() < 1. {
+                debug!("power limited to {:.0}%", 100. * leds.power_scale());
+            }
+            let buffer = leds.get_buffer();
+            if let Err(e) = spi.write(buffer) {
+                metrics.write_failed();
+                error!("failed to write to spi bus: {:}", e);
+            }
+            written(&frame);
         }
-        println!("uh-oh, dead");
+        error!("uh-oh, dead");
     });
 
-    Ok(App { display, config })
+    Ok(App {
+        display,
+        size: (width, height),
+        metrics,
+        config,
+        shared,
+        output,
+    })
+}
+
+/// Strip layout from the layout options.
+fn build_transform(opts: &Opts) -> Result<Transform> {
+    let transform = if let Some(path) = &opts.coord_map {
+        Transform::from_coord_file(path)?
+    } else if let Some(path) = &opts.layout {
+        Transform::from_file(path)?
+    } else if let Some(rows) = opts.serpentine {
+        if rows == 0 {
+            return Err(anyhow!("--serpentine needs at least one row"));
+        }
+        Transform::serpentine(rows, opts.length / rows as u16)
+    } else {
+        Transform::new(
+            vec![144; 4],
+            vec![false, true, false, true],
+            vec![0, 2, 1, 3],
+        )
+    };
+    Ok(transform.with_rotation(opts.rotate))
+}
+
+/// Default log level for the number of times `--verbose` was given, which
+/// `RUST_LOG` overrides.
+fn log_level(verbose: i32) -> LevelFilter {
+    match verbose {
+        i32::MIN..=0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
 }
 
 fn main() {
     let opts = Opts::parse();
+    env_logger::Builder::new()
+        .filter_level(log_level(opts.verbose))
+        .parse_default_env()
+        .init();
+
+    if let Command::Config(ConfigOpts { overrides }) = &opts.cmd {
+        dump_config(&opts, overrides).unwrap();
+        return;
+    }
+    if let Command::Test(TestOpts {
+        cmd: TestCommand::Layout,
+        ..
+    }) = &opts.cmd
+    {
+        dump_layout(&opts).unwrap();
+        return;
+    }
 
     let app = setup(&opts).unwrap();
 
     match opts.cmd {
-        Command::Init => (),
+        Command::Init | Command::Config(_) => (),
         Command::Set(SetOpts {
             red,
             green,
             blue,
             alpha,
+            fade,
         }) => {
             let alpha = if alpha > 31 { 31 } else { alpha };
-            let frame = (0..opts.length)
+            let frame: Vec<ARGB8> = (0..app.frame_len())
                 .map(|_| ARGB8::new(alpha, red, green, blue))
                 .collect();
-            for _ in 0..2 {
-                // write twice to block until the first frame has finished transferring
+            if let Some(ms) = fade {
+                let black = fade::blackout(frame.len());
+                fade::transition(&app.display, &black, &frame, Duration::from_millis(ms), 60.)
+                    .expect("failed to write frame");
+            }
+            if let Some(ms) = opts.fade_time {
+                // fade in, then finish below on the frame at full brightness
                 app.display.write(&frame).expect("failed to write frame");
+                thread::sleep(Duration::from_millis(ms));
             }
+            app.display
+                .write_and_wait(&frame)
+                .expect("failed to write frame");
+        }
+        Command::Pattern(PatternOpts {
+            pattern,
+            fps,
+            speed,
+            alpha,
+        }) => {
+            let params = app.shared.visualizer.read().unwrap().clone();
+            let palette = pattern
+                .palette()
+                .unwrap_or_else(|| params.palette().clone());
+            let clut = visualizer::Clut::cached(&palette, params.gamma(), params.color_space());
+            let alpha = u8::min(alpha, 31);
+            pattern::run(&app.display, pattern, &clut, app.size, fps, speed, alpha)
+                .expect("failed to write frame");
+        }
+        Command::Breathe(BreatheOpts {
+            red,
+            green,
+            blue,
+            period,
+            fps,
+        }) => {
+            let color = ARGB8::new(31, red, green, blue);
+            pattern::breathe(&app.display, color, app.frame_len(), period, fps)
+                .expect("failed to write frame");
+        }
+        Command::Calibrate(CalibrateOpts { step }) => {
+            let params = app.shared.visualizer.clone();
+            let step = Duration::from_secs_f64(step);
+            calibrate::run(&app.display, params, app.frame_len(), step)
+                .expect("failed to calibrate");
+        }
+        Command::Zones(ZonesOpts { file, fps }) => {
+            let length = app.frame_len();
+            let zones = zone::load(&file).expect("failed to load zones");
+            zone::validate(&zones, length).expect("invalid zones");
+            let params = app.shared.visualizer.read().unwrap().clone();
+            zone::run(&app.display, &zones, &params, length, fps).expect("failed to write frame");
+        }
+        Command::Daemon(DaemonOpts {
+            socket,
+            fps,
+            schedule,
+            visualizer: vopts,
+        }) => {
+            let params = app.shared.visualizer.clone();
+            let (request_tx, request_rx) = channel();
+            if let Some(path) = schedule {
+                let schedule = schedule::Schedule::load(&path).expect("failed to load schedule");
+                schedule::spawn(schedule, request_tx.clone());
+            }
+            daemon::listen(&socket, request_tx).expect("failed to listen on control socket");
+
+            let (sink, visualizer_rx) = sync_channel(1);
+            let start_visualizer = {
+                let (shared, metrics) = (app.shared.clone(), app.metrics.clone());
+                let (audio, strips) = (app.config.audio, app.strips());
+                move || {
+                    thread::spawn(move || {
+                        let (audio_tx, audio_rx) = channel();
+                        if let Err(e) = config::watch(shared.clone(), audio_tx) {
+                            error!("failed to watch {}: {}", Config::CONFIG_FILE, e);
+                        }
+                        let vis = visualizer::Visualizer::new(vopts, shared.visualizer, metrics);
+                        vis.run(strips, audio, audio_rx, sink);
+                    });
+                }
+            };
+            daemon::run(
+                &app.display,
+                request_rx,
+                params,
+                app.size,
+                fps,
+                visualizer_rx,
+                start_visualizer,
+            )
+            .expect("failed to write frame");
         }
         Command::Test(TestOpts { duration, cmd }) => match cmd {
             TestCommand::Fps => {
                 // spam frames to check for flickering
-                let frame = (0..opts.length).map(|_| ARGB8::new(1, 1, 1, 1)).collect();
+                let frame = (0..app.frame_len())
+                    .map(|_| ARGB8::new(1, 1, 1, 1))
+                    .collect();
 
                 let mut fps = 0;
                 use std::time::SystemTime;
@@ -215,7 +957,7 @@ fn main() {
             }
             TestCommand::Transform => {
                 let mut fps = 0;
-                let l = opts.length;
+                let l = app.frame_len();
                 use std::time::SystemTime;
                 let then = SystemTime::now();
                 while {
@@ -234,49 +976,175 @@ fn main() {
                         })
                         .collect();
 
-                    app.display.write(&frame).expect("failed to write frame");
+                    app.display
+                        .write_owned(frame)
+                        .expect("failed to write frame");
                 }
                 println!("Fps: {:?}", fps as u32 / duration);
             }
-            TestCommand::Audio(TestAudioOpts {
-                show_configs,
-                device,
-            }) => {
-                test_audio(duration as u64, show_configs, device.as_deref());
+            TestCommand::Layout => (),
+            TestCommand::Audio(opts) => {
+                test_audio(duration as u64, &opts);
+            }
+            TestCommand::Pipeline(opts) => {
+                test_pipeline(duration as u64, &opts);
+            }
+            TestCommand::Record(TestRecordOpts { visualizer, .. }) => {
+                let sink = app.display.sink();
+                let strips = app.strips();
+                let audio = app.config.audio;
+                let metrics = app.metrics.clone();
+                let params = app.shared.visualizer.clone();
+                visualizer
+                    .overrides
+                    .apply(&mut params.write().unwrap())
+                    .expect("failed to apply param overrides");
+                thread::spawn(move || {
+                    let (_, audio_rx) = channel();
+                    let vis = visualizer::Visualizer::new(visualizer, params, metrics);
+                    vis.run(strips, audio, audio_rx, sink);
+                });
+                app.output.join().expect("output thread panicked");
+            }
+            TestCommand::Ramp(TestRampOpts { gamma }) => {
+                let gamma = match gamma {
+                    true => app.shared.visualizer.read().unwrap().gamma(),
+                    false => 1.,
+                };
+                let frame = pattern::ramp(app.frame_len(), gamma);
+                app.display
+                    .write_owned(frame)
+                    .expect("failed to write frame");
+                thread::sleep(Duration::from_secs(duration as u64));
+            }
+            TestCommand::Replay(replay) => {
+                let params = app.shared.visualizer.clone();
+                replay
+                    .visualizer
+                    .overrides
+                    .apply(&mut params.write().unwrap())
+                    .expect("failed to apply param overrides");
+                let vis =
+                    visualizer::Visualizer::new(replay.visualizer, params, app.metrics.clone());
+                vis.replay(app.strips(), &replay.file, replay.fps, &app.display)
+                    .expect("failed to replay features");
             }
         },
         Command::Visualizer(vopts) => {
-            let vis = visualizer::Visualizer::new(vopts, app.config.visualizer, opts.verbose);
-            vis.run((144, 4), app.config.audio, app.display.sink());
+            let (audio_tx, audio_rx) = channel();
+            if let Err(e) = config::watch(app.shared.clone(), audio_tx) {
+                error!("failed to watch {}: {}", Config::CONFIG_FILE, e);
+            }
+            let params = app.shared.visualizer.clone();
+            vopts
+                .overrides
+                .apply(&mut params.write().unwrap())
+                .expect("failed to apply param overrides");
+            let vis = visualizer::Visualizer::new(vopts, params, app.metrics.clone());
+            vis.run(app.strips(), app.config.audio, audio_rx, app.display.sink());
         }
     };
 }
 
 use std::sync::mpsc::channel;
 
-fn test_audio(timeout: u64, show_configs: bool, device: Option<&str>) {
-    audio::Source::print_devices(show_configs).expect("failed to print devices");
+fn test_pipeline(duration: u64, opts: &TestPipelineOpts) {
+    use std::time::Instant;
 
-    let (audio_data_tx, audio_data_rx) = channel();
+    let mut synth = synth::Synth::new(44100., vec![110., 440., 3520.]);
+    let mut sfft = audio::sfft::SlidingFFT::new(opts.fft_size);
+    let mut bucketer = audio::bucketer::Bucketer::new(sfft.output_size(), opts.bins, 32.0, 16000.0);
+    let mut fs = audio::frequency_sensor::FrequencySensor::new(
+        opts.bins,
+        opts.length,
+        FrequencySensorParams::defaults(),
+    );
+
+    let (mut t_sfft, mut t_bucket, mut t_fs) = (
+        Duration::default(),
+        Duration::default(),
+        Duration::default(),
+    );
+    let mut frames = 0u32;
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(duration) {
+        let block = synth.block(opts.block_size);
+        let t0 = Instant::now();
+        sfft.push_input(&block);
+        let frame = sfft.process();
+        let t1 = Instant::now();
+        let bins = bucketer.bucket(frame);
+        let t2 = Instant::now();
+        fs.process(bins);
+        let t3 = Instant::now();
+        t_sfft += t1 - t0;
+        t_bucket += t2 - t1;
+        t_fs += t3 - t2;
+        frames += 1;
+    }
+
+    let frames = u32::max(frames, 1);
+    println!(
+        "Pipeline: {:.1} frames/sec",
+        frames as f64 / start.elapsed().as_secs_f64()
+    );
+    println!("  sfft:             {:?}/frame", t_sfft / frames);
+    println!("  bucketer:         {:?}/frame", t_bucket / frames);
+    println!("  frequency sensor: {:?}/frame", t_fs / frames);
+}
+
+fn test_audio(timeout: u64, opts: &TestAudioOpts) {
+    audio::Source::print_devices(opts.show_configs).expect("failed to print devices");
+
+    let device = opts
+        .device
+        .as_deref()
+        .map(|d| device::resolve(d).expect("failed to find device"));
+    let sample_rate = device::negotiate_rate(device.as_deref(), 1, 44100)
+        .expect("failed to find a supported sample rate");
+    println!("Sample Rate: {}", sample_rate);
+
+    let (audio_data_tx, audio_data_rx) = channel::<(std::time::SystemTime, Vec<f64>)>();
 
     let mut sfft = audio::sfft::SlidingFFT::new(1024);
-    let mut bucketer = audio::bucketer::Bucketer::new(512, 16, 32.0, 16000.0);
-    let mut fs =
-        audio::frequency_sensor::FrequencySensor::new(16, 128, FrequencySensorParams::defaults());
+    let mut bucketer = bucket::bucketer(
+        sfft.output_size(),
+        16,
+        opts.freq_min,
+        opts.freq_max,
+        opts.bucket_scale,
+        sample_rate as f64,
+    );
     println!("Bucket Indices: {:?}", bucketer.indices);
 
     thread::spawn(move || {
         let boost_params = audio::gain_control::Params::defaults();
         let fs_params = FrequencySensorParams::defaults();
         let mut analyzer = audio::Analyzer::new(1024, 256, 4, 128, boost_params, fs_params);
+        let mut beats = beat::BeatDetector::defaults();
+        // the analyzer produces a frame every hop of 256 samples
+        let mut tempo = beat::TempoEstimator::new(sample_rate as f64 / 256., 16);
         loop {
-            if let Ok((t, mut data)) = audio_data_rx.recv() {
+            if let Ok((_, mut data)) = audio_data_rx.recv() {
+                sfft.push_input(&data);
+                let levels = bucketer.bucket(sfft.process());
+                println!("buckets: {:.2?}", levels);
                 if let Some(features) = analyzer.process(&mut data) {
+                    let beat = beats.process(features.get_energy());
+                    let bpm = tempo.update(beat.beat);
                     let mut out = String::new();
                     analyzer
                         .write_debug(&mut out)
                         .expect("failed to write fs debug");
                     println!("{}", out);
+                    if beat.beat {
+                        match bpm {
+                            Some(bpm) => {
+                                println!("beat: {:.2}, tempo: {:.1} bpm", beat.strength, bpm)
+                            }
+                            None => println!("beat: {:.2}", beat.strength),
+                        }
+                    }
                 }
             } else {
                 break;
@@ -284,13 +1152,13 @@ fn test_audio(timeout: u64, show_configs: bool, device: Option<&str>) {
         }
     });
 
-    let s = audio::Source::new(device).expect("failed to get device");
+    let s = audio::Source::new(device.as_deref()).expect("failed to get device");
 
     let handle_stream = move |data: &[f32]| {
         let now = std::time::SystemTime::now();
         let data = data.iter().map(|&x| x as f64).collect();
         if let Err(e) = audio_data_tx.send((now, data)) {
-            println!("failed to send audio data: {}", e);
+            error!("failed to send audio data: {}", e);
         }
     };
     // random rust thing:
@@ -298,9 +1166,22 @@ fn test_audio(timeout: u64, show_configs: bool, device: Option<&str>) {
     let handle_stream = Box::new(handle_stream) as Box<dyn Fn(&[f32]) -> () + Send>;
 
     let stream = s
-        .get_stream(1, 44100, 512, handle_stream)
+        .get_stream(1, sample_rate, 512, handle_stream)
         .expect("failed to get stream");
 
     std::thread::sleep(std::time::Duration::from_secs(timeout));
     drop(stream);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbosity_sets_the_log_level() {
+        use LevelFilter::*;
+        let levels: Vec<LevelFilter> = (0..=4).map(log_level).collect();
+        assert_eq!(levels, vec![Warn, Info, Debug, Trace, Trace]);
+        assert_eq!(log_level(-1), Warn);
+    }
+}
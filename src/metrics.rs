@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+use serde::Serialize;
+
+/// Metrics counts what happens to frames, updated from the render and output
+/// threads.
+#[derive(Default)]
+pub struct Metrics {
+    rendered: AtomicU64,
+    dropped: AtomicU64,
+    written: AtomicU64,
+    write_failures: AtomicU64,
+    // total latency of written frames, in microseconds
+    latency: AtomicU64,
+    // bits of the last loudness normalization factor, 0 when not normalizing
+    loudness_factor: AtomicU64,
+}
+
+impl Metrics {
+    pub fn rendered(&self) {
+        self.rendered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count `n` frames which went missing on the way to the output.
+    pub fn missed(&self, n: u64) {
+        self.dropped.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Count a frame written to the output, which spent `latency` in the
+    /// pipeline.
+    pub fn written(&self, latency: Duration) {
+        self.written.fetch_add(1, Ordering::Relaxed);
+        self.latency
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn write_failed(&self) {
+        self.write_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_loudness_factor(&self, factor: f64) {
+        self.loudness_factor
+            .store(factor.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Last loudness normalization factor, if the input is being normalized.
+    pub fn loudness_factor(&self) -> Option<f64> {
+        match self.loudness_factor.load(Ordering::Relaxed) {
+            0 => None,
+            bits => Some(f64::from_bits(bits)),
+        }
+    }
+
+    fn counts(&self) -> [u64; 5] {
+        [
+            self.rendered.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            self.written.load(Ordering::Relaxed),
+            self.write_failures.load(Ordering::Relaxed),
+            self.latency.load(Ordering::Relaxed),
+        ]
+    }
+}
+
+/// RollingFps is the rate a count increased at over a trailing window.
+pub struct RollingFps {
+    window: Duration,
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RollingFps {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Add the `count` at `now`, returning the rate since the oldest sample
+    /// still within the window.
+    pub fn update(&mut self, now: Instant, count: u64) -> f64 {
+        self.samples.push_back((now, count));
+        while let Some(&(t, _)) = self.samples.front() {
+            if now.saturating_duration_since(t) <= self.window || self.samples.len() <= 2 {
+                break;
+            }
+            self.samples.pop_front();
+        }
+        let (t0, c0) = self.samples[0];
+        let elapsed = now.saturating_duration_since(t0).as_secs_f64();
+        if elapsed == 0. {
+            return 0.;
+        }
+        count.saturating_sub(c0) as f64 / elapsed
+    }
+}
+
+/// One line summary of the metrics over an interval.
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Report {
+    pub fps: f64,
+    pub rendered: u64,
+    pub dropped: u64,
+    pub write_failures: u64,
+    /// Mean latency of the written frames in milliseconds.
+    pub latency_ms: f64,
+    /// Factor the features are scaled by to normalize loudness.
+    pub loudness_factor: Option<f64>,
+}
+
+/// Report the metrics every `interval`, logged as a line or printed as JSON,
+/// and keep `fps_status` up to date.
+pub fn spawn_reporter(
+    metrics: Arc<Metrics>,
+    interval: Duration,
+    json: bool,
+    fps_status: Arc<RwLock<f64>>,
+) {
+    thread::spawn(move || {
+        let mut fps = RollingFps::new(interval * 4);
+        let mut last = metrics.counts();
+        loop {
+            thread::sleep(interval);
+            let counts = metrics.counts();
+            let delta: Vec<u64> = counts.iter().zip(&last).map(|(c, l)| c - l).collect();
+            last = counts;
+
+            let report = Report {
+                fps: fps.update(Instant::now(), counts[2]),
+                rendered: delta[0],
+                dropped: delta[1],
+                write_failures: delta[3],
+                latency_ms: if delta[2] > 0 {
+                    delta[4] as f64 / delta[2] as f64 / 1000.
+                } else {
+                    0.
+                },
+                loudness_factor: metrics.loudness_factor(),
+            };
+            *fps_status.write().unwrap() = report.fps;
+            if json {
+                match serde_json::to_string(&report) {
+                    Ok(line) => println!("{}", line),
+                    Err(e) => error!("failed to encode metrics: {}", e),
+                }
+            } else {
+                let loudness = report
+                    .loudness_factor
+                    .map(|f| format!(", loudness factor: {:.2}", f))
+                    .unwrap_or_default();
+                info!(
+                    "fps: {:.1}, rendered: {}, dropped: {}, write failures: {}, latency: {:.1}ms{}",
+                    report.fps,
+                    report.rendered,
+                    report.dropped,
+                    report.write_failures,
+                    report.latency_ms,
+                    loudness
+                );
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolling_fps_over_the_window() {
+        let t0 = Instant::now();
+        let at = |ms| t0 + Duration::from_millis(ms);
+        let mut fps = RollingFps::new(Duration::from_secs(1));
+        assert_eq!(fps.update(at(0), 0), 0.);
+        assert_eq!(fps.update(at(500), 30), 60.);
+        assert_eq!(fps.update(at(1000), 60), 60.);
+        // the rate slows once the faster samples leave the window
+        assert_eq!(fps.update(at(1500), 75), 45.);
+        assert_eq!(fps.update(at(2000), 90), 30.);
+    }
+}
@@ -0,0 +1,126 @@
+use std::net::UdpSocket;
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use anyhow::{anyhow, Result};
+use log::{debug, error, info, warn};
+use rosc::{OscMessage, OscPacket, OscType};
+
+use crate::config::{self, Config};
+use crate::visualizer::Params;
+
+const PREFIX: &str = "/vis/";
+const SAVE: &str = "/save";
+
+/// Listen for OSC messages like `/vis/cycle 0.01` and apply them to the params.
+/// A `/save` message writes the current params back to the config file.
+pub fn listen(addr: &str, params: Arc<RwLock<Params>>) -> Result<()> {
+    let socket = UdpSocket::bind(addr)?;
+    thread::spawn(move || {
+        let mut buf = [0u8; rosc::decoder::MTU];
+        loop {
+            let size = match socket.recv_from(&mut buf) {
+                Ok((size, _)) => size,
+                Err(e) => {
+                    error!("failed to receive osc packet: {}", e);
+                    break;
+                }
+            };
+            match rosc::decoder::decode(&buf[..size]) {
+                Ok(packet) => handle_packet(packet, &params),
+                Err(e) => warn!("failed to decode osc packet: {:?}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_packet(packet: OscPacket, params: &RwLock<Params>) {
+    match packet {
+        OscPacket::Message(msg) if msg.addr == SAVE => match config::save_visualizer(params) {
+            Ok(()) => info!("osc: saved {}", Config::CONFIG_FILE),
+            Err(e) => error!("failed to save {}: {}", Config::CONFIG_FILE, e),
+        },
+        OscPacket::Message(msg) => match apply(&msg, params) {
+            Ok(()) => debug!("osc: {} {:?}", msg.addr, msg.args),
+            Err(e) => warn!("failed to apply osc message {}: {}", msg.addr, e),
+        },
+        OscPacket::Bundle(bundle) => {
+            for packet in bundle.content {
+                handle_packet(packet, params);
+            }
+        }
+    }
+}
+
+/// Apply a message addressed to a `Params` field, rejecting it if the new
+/// value is out of range.
+pub fn apply(msg: &OscMessage, params: &RwLock<Params>) -> Result<()> {
+    let field = msg
+        .addr
+        .strip_prefix(PREFIX)
+        .ok_or_else(|| anyhow!("unknown address"))?;
+    let values = msg
+        .args
+        .iter()
+        .map(|arg| match arg {
+            OscType::Float(x) => Ok(*x as f64),
+            OscType::Double(x) => Ok(*x),
+            OscType::Int(x) => Ok(*x as f64),
+            arg => Err(anyhow!("unsupported argument {:?}", arg)),
+        })
+        .collect::<Result<Vec<f64>>>()?;
+    // set a copy, so an out of range value never reaches the visualizer
+    let mut params = params.write().unwrap();
+    let mut updated = params.clone();
+    updated.set(field, &values)?;
+    updated.validate().map_err(|e| anyhow!("{}", e))?;
+    *params = updated;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(addr: &str, args: Vec<OscType>) -> OscMessage {
+        OscMessage {
+            addr: addr.to_string(),
+            args,
+        }
+    }
+
+    #[test]
+    fn message_updates_its_param() {
+        let params = RwLock::new(Params::defaults());
+        apply(&message("/vis/gamma", vec![OscType::Float(1.5)]), &params).unwrap();
+        assert_eq!(params.read().unwrap().gamma(), 1.5);
+        apply(&message("/vis/gamma", vec![OscType::Int(3)]), &params).unwrap();
+        assert_eq!(params.read().unwrap().gamma(), 3.);
+        let wb = vec![OscType::Double(1.), OscType::Float(0.5), OscType::Int(0)];
+        apply(&message("/vis/white_balance", wb), &params).unwrap();
+        assert_eq!(params.read().unwrap().white_balance(), (1., 0.5, 0.));
+    }
+
+    #[test]
+    fn bad_messages_are_errors() {
+        let params = RwLock::new(Params::defaults());
+        let one = || vec![OscType::Float(1.)];
+        assert!(apply(&message("/other/gamma", one()), &params).is_err());
+        assert!(apply(&message("/vis/nonsense", one()), &params).is_err());
+        let text = vec![OscType::String("high".to_string())];
+        assert!(apply(&message("/vis/gamma", text), &params).is_err());
+        assert_eq!(params.read().unwrap().gamma(), 2.);
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected() {
+        let params = RwLock::new(Params::defaults());
+        let err = apply(
+            &message("/vis/max_alpha", vec![OscType::Float(5.)]),
+            &params,
+        );
+        assert!(err.unwrap_err().to_string().contains("max_alpha"));
+        assert!(params.read().unwrap().validate().is_ok());
+    }
+}
@@ -0,0 +1,210 @@
+use std::f64::consts::PI;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::apa102::ARGB8;
+use crate::display::{Display, FrameLimiter};
+use crate::visualizer::{Clut, Palette};
+
+/// Animated patterns which don't need any audio input.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Pattern {
+    Rainbow,
+    Plasma,
+    Fire,
+}
+
+impl std::str::FromStr for Pattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rainbow" => Ok(Pattern::Rainbow),
+            "plasma" => Ok(Pattern::Plasma),
+            "fire" => Ok(Pattern::Fire),
+            _ => Err(anyhow!("unknown pattern: {}", s)),
+        }
+    }
+}
+
+impl Pattern {
+    /// Palette the pattern is designed for, or None to use the configured one.
+    pub fn palette(self) -> Option<Palette> {
+        match self {
+            Pattern::Fire => Some(Palette::Gradient(vec![
+                (0.0, (0, 0, 0)),
+                (0.35, (160, 0, 0)),
+                (0.6, (255, 80, 0)),
+                (0.85, (255, 200, 0)),
+                (1.0, (255, 255, 160)),
+            ])),
+            _ => None,
+        }
+    }
+
+    /// Render a `width x height` frame, one strip per column, at time `t` seconds.
+    pub fn render(self, clut: &Clut, width: usize, height: usize, t: f64, alpha: u8) -> Vec<ARGB8> {
+        let mut frame = Vec::with_capacity(width * height);
+        for x in 0..width {
+            for y in 0..height {
+                let u = x as f64 / width as f64;
+                let v = y as f64 / height as f64;
+                let (hue, value) = self.pixel(u, v, t);
+                let color = clut.lookup(hue, value);
+                frame.push(ARGB8::new(
+                    alpha,
+                    (255.5 * color.0) as u8,
+                    (255.5 * color.1) as u8,
+                    (255.5 * color.2) as u8,
+                ));
+            }
+        }
+        frame
+    }
+
+    /// Hue cycle position and value of the pixel at `u` across the strips and
+    /// `v` along them, both from 0.0 to 1.0.
+    fn pixel(self, u: f64, v: f64, t: f64) -> (f64, f64) {
+        match self {
+            Pattern::Rainbow => ((v + 0.1 * t).rem_euclid(1.0), 0.6),
+            Pattern::Plasma => {
+                let p = f64::sin(2. * PI * (2. * v + 0.3 * t))
+                    + f64::sin(2. * PI * (u + 0.21 * t))
+                    + f64::sin(2. * PI * (1.5 * (u + v) - 0.17 * t));
+                ((p / 6. + 0.5 + 0.05 * t).rem_euclid(1.0), 0.6)
+            }
+            Pattern::Fire => {
+                // heat falls off along the strip, with flicker rising from the base
+                let flicker = f64::sin(2. * PI * (3. * v - 1.3 * t) + 7. * u)
+                    * f64::sin(2. * PI * (5. * v - 2.1 * t) + 3. * u);
+                let heat = (1. - v) * (0.75 + 0.25 * flicker);
+                (f64::min(f64::max(heat, 0.), 0.999), 0.99)
+            }
+        }
+    }
+}
+
+/// Write frames of the pattern to the display until it's closed.
+pub fn run(
+    display: &Display<ARGB8>,
+    pattern: Pattern,
+    clut: &Clut,
+    size: (usize, usize),
+    fps: f64,
+    speed: f64,
+    alpha: u8,
+) -> Result<()> {
+    let (width, height) = size;
+    let mut limiter = FrameLimiter::new(fps);
+    let start = Instant::now();
+    loop {
+        let t = speed * start.elapsed().as_secs_f64();
+        display.write_owned(pattern.render(clut, width, height, t, alpha))?;
+        limiter.wait();
+    }
+}
+
+/// Brightness from 0.0 to 1.0 of a breath `t` seconds in, starting and ending
+/// dark and peaking halfway through each period.
+pub fn breathe_level(t: f64, period: f64) -> f64 {
+    0.5 - 0.5 * f64::cos(2. * PI * t / period)
+}
+
+/// A white ramp from black to full brightness along `length` pixels, with
+/// each level raised to `gamma`, so 1.0 leaves it uncorrected.
+pub fn ramp(length: usize, gamma: f64) -> Vec<ARGB8> {
+    let last = usize::max(length, 2) - 1;
+    (0..length)
+        .map(|i| {
+            let level = (i as f64 / last as f64).powf(gamma);
+            let v = (255. * level + 0.5) as u8;
+            ARGB8::new(31, v, v, v)
+        })
+        .collect()
+}
+
+/// A `length` pixel frame of `color` scaled to the given brightness.
+pub fn breathe_frame(color: ARGB8, length: usize, level: f64) -> Vec<ARGB8> {
+    let scale = |c: u8| (c as f64 * level + 0.5) as u8;
+    let pixel = ARGB8::new(color.a, scale(color.r), scale(color.g), scale(color.b));
+    vec![pixel; length]
+}
+
+/// Pulse a single color on the display until it's closed.
+pub fn breathe(
+    display: &Display<ARGB8>,
+    color: ARGB8,
+    length: usize,
+    period: f64,
+    fps: f64,
+) -> Result<()> {
+    let mut limiter = FrameLimiter::new(fps);
+    let start = Instant::now();
+    loop {
+        let level = breathe_level(start.elapsed().as_secs_f64(), period);
+        display.write_owned(breathe_frame(color, length, level))?;
+        limiter.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::visualizer::ColorSpace;
+
+    #[test]
+    fn rainbow_sweeps_every_hue_at_start() {
+        let hues: Vec<f64> = (0..36)
+            .map(|y| Pattern::Rainbow.pixel(0., y as f64 / 36., 0.).0)
+            .collect();
+        assert_eq!(hues[0], 0.);
+        assert!(hues
+            .windows(2)
+            .all(|w| (w[1] - w[0] - 1. / 36.).abs() < 1e-9));
+
+        let clut = Clut::new(&Palette::Rainbow, 1., ColorSpace::Hsv);
+        let frame = Pattern::Rainbow.render(&clut, 1, 36, 0., 31);
+        let dominant = |p: &ARGB8| {
+            let c = [p.r, p.g, p.b];
+            (0..3).max_by_key(|&i| c[i]).unwrap()
+        };
+        assert_eq!(dominant(&frame[0]), 0);
+        assert_eq!(dominant(&frame[12]), 1);
+        assert_eq!(dominant(&frame[24]), 2);
+    }
+
+    #[test]
+    fn breathing_is_sinusoidal_and_peaks_at_the_color() {
+        let period = 4.;
+        for i in 0..=40 {
+            let t = period * i as f64 / 40.;
+            let expected = (1. - f64::cos(2. * PI * t / period)) / 2.;
+            assert!((breathe_level(t, period) - expected).abs() < 1e-9);
+        }
+        assert!(breathe_level(0., period).abs() < 1e-9);
+        assert!((breathe_level(period / 2., period) - 1.).abs() < 1e-9);
+
+        let color = ARGB8::new(31, 200, 100, 50);
+        let peak = breathe_frame(color, 3, breathe_level(period / 2., period));
+        assert_eq!(peak, vec![color; 3]);
+        let half = breathe_frame(color, 1, 0.5);
+        assert_eq!(half[0], ARGB8::new(31, 100, 50, 25));
+    }
+
+    #[test]
+    fn ramp_is_monotonic() {
+        for &gamma in &[1., 2.2] {
+            let ramp = ramp(144, gamma);
+            let levels: Vec<u8> = ramp.iter().map(|p| p.r).collect();
+            assert!(levels.windows(2).all(|w| w[0] <= w[1]));
+            assert_eq!((levels[0], levels[143]), (0, 255));
+            assert!(ramp.iter().all(|p| p.r == p.g && p.g == p.b));
+        }
+        // gamma darkens the middle of the ramp
+        assert!(ramp(144, 2.2)[72].r < ramp(144, 1.)[72].r);
+        assert_eq!(ramp(1, 1.)[0].r, 0);
+    }
+}
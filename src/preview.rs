@@ -0,0 +1,72 @@
+use std::io::Write;
+use std::sync::mpsc::Receiver;
+
+use log::warn;
+
+use crate::apa102::ARGB8;
+use crate::display::Frame;
+
+/// Render a `width x height` frame as 24-bit color blocks, one row per strip.
+pub fn render(frame: &[ARGB8], width: usize, height: usize) -> String {
+    let mut out = String::new();
+    for x in 0..width {
+        for y in 0..height {
+            let (r, g, b) = frame[x * height + y].scaled_rgb();
+            out.push_str(&format!("\x1b[48;2;{};{};{}m ", r, g, b));
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    out
+}
+
+/// Describe the first, middle and last pixels of a frame.
+pub fn sample(frame: &[ARGB8]) -> String {
+    if frame.is_empty() {
+        return "empty frame".to_string();
+    }
+    let pixel = |i: usize| {
+        let p = frame[i];
+        format!("[{}] argb({}, {}, {}, {})", i, p.a, p.r, p.g, p.b)
+    };
+    let n = frame.len();
+    format!("{} {} {}", pixel(0), pixel(n / 2), pixel(n - 1))
+}
+
+/// Draw each received frame to stdout, redrawing in place.
+pub fn run_terminal(frame_rx: Receiver<Frame<ARGB8>>, width: usize, height: usize) {
+    let stdout = std::io::stdout();
+    let mut first = true;
+    while let Ok(Frame { pixels: frame, .. }) = frame_rx.recv() {
+        if frame.len() < width * height {
+            warn!("frame too short for preview: {}", frame.len());
+            continue;
+        }
+        let mut out = stdout.lock();
+        if !first {
+            // move back up over the previous frame
+            let _ = write!(out, "\x1b[{}A", width);
+        }
+        first = false;
+        let _ = write!(out, "{}", render(&frame, width, height));
+        let _ = out.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_the_first_middle_and_last_pixels() {
+        let frame: Vec<ARGB8> = (0..5).map(|i| ARGB8::new(31, i * 10, 0, 255)).collect();
+        assert_eq!(
+            sample(&frame),
+            "[0] argb(31, 0, 0, 255) [2] argb(31, 20, 0, 255) [4] argb(31, 40, 0, 255)"
+        );
+        assert_eq!(
+            sample(&frame[..1]),
+            "[0] argb(31, 0, 0, 255) [0] argb(31, 0, 0, 255) [0] argb(31, 0, 0, 255)"
+        );
+        assert_eq!(sample(&[]), "empty frame");
+    }
+}
@@ -0,0 +1,90 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+
+use anyhow::{anyhow, Result};
+
+use crate::apa102::ARGB8;
+use crate::display::Frame;
+
+/// Convert a `width x height` frame to an RGB image with one row per strip.
+pub fn to_rgb(frame: &[ARGB8], width: usize, height: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(3 * width * height);
+    for x in 0..width {
+        for y in 0..height {
+            let (r, g, b) = frame[x * height + y].scaled_rgb();
+            data.extend_from_slice(&[r, g, b]);
+        }
+    }
+    data
+}
+
+pub fn write_png<P: AsRef<Path>>(
+    path: P,
+    frame: &[ARGB8],
+    width: usize,
+    height: usize,
+) -> Result<()> {
+    let w = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(w, height as u32, width as u32);
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&to_rgb(frame, width, height))?;
+    Ok(())
+}
+
+/// Write the next `frames` received frames into `dir` as a PNG sequence.
+pub fn run<P: AsRef<Path>>(
+    frame_rx: Receiver<Frame<ARGB8>>,
+    dir: P,
+    frames: usize,
+    width: usize,
+    height: usize,
+) -> Result<()> {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir)?;
+    for i in 0..frames {
+        let frame = frame_rx.recv()?.pixels;
+        if frame.len() < width * height {
+            return Err(anyhow!("frame too short to record: {}", frame.len()));
+        }
+        write_png(
+            dir.join(format!("frame_{:05}.png", i)),
+            &frame,
+            width,
+            height,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::sync_channel;
+
+    #[test]
+    fn solid_frames_become_solid_images() {
+        let red = vec![ARGB8::new(31, 255, 0, 0); 6];
+        assert_eq!(to_rgb(&red, 2, 3), [255, 0, 0].repeat(6));
+        let dim_blue = vec![ARGB8::new(15, 0, 0, 62); 6];
+        assert_eq!(to_rgb(&dim_blue, 2, 3), [0, 0, 30].repeat(6));
+    }
+
+    #[test]
+    fn records_each_frame() {
+        let dir = std::env::temp_dir().join(format!("led-record-{}", std::process::id()));
+        let (tx, rx) = sync_channel(2);
+        tx.send(Frame::new(vec![ARGB8::new(31, 255, 0, 0); 6]))
+            .unwrap();
+        tx.send(Frame::new(vec![ARGB8::new(31, 0, 255, 0); 6]))
+            .unwrap();
+        run(rx, &dir, 2, 2, 3).unwrap();
+        assert!(dir.join("frame_00000.png").is_file());
+        assert!(dir.join("frame_00001.png").is_file());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::{Local, Timelike};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::daemon::Request;
+
+/// How often the clock is checked against the schedule.
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Rule as written in the schedule file, running a daemon command like
+/// `set 255 120 40 4` from a local time like `22:30` onwards.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Rule {
+    pub at: String,
+    pub action: String,
+}
+
+/// Schedule of daemon requests by time of day.
+pub struct Schedule {
+    // minute of the day each request starts at, in order
+    rules: Vec<(u32, Request)>,
+}
+
+impl Schedule {
+    pub fn new(rules: &[Rule]) -> Result<Self> {
+        let mut parsed = Vec::with_capacity(rules.len());
+        for rule in rules {
+            let request = rule
+                .action
+                .parse()
+                .map_err(|e| anyhow!("bad action for {}: {}", rule.at, e))?;
+            parsed.push((parse_time(&rule.at)?, request));
+        }
+        parsed.sort_by_key(|(minute, _)| *minute);
+        Ok(Self { rules: parsed })
+    }
+
+    /// Load the rules from a YAML list.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let f = std::fs::File::open(path)?;
+        let rules: Vec<Rule> = serde_yaml::from_reader(f)?;
+        Self::new(&rules)
+    }
+
+    /// Index and request of the rule in effect at `minute` of the day, which
+    /// before the first rule is the last one from the day before.
+    pub fn lookup(&self, minute: u32) -> Option<(usize, &Request)> {
+        let i = match self.rules.iter().rposition(|(m, _)| *m <= minute) {
+            Some(i) => i,
+            None => self.rules.len().checked_sub(1)?,
+        };
+        Some((i, &self.rules[i].1))
+    }
+}
+
+/// Minute of the day from a 24 hour `HH:MM` time.
+fn parse_time(s: &str) -> Result<u32> {
+    let bad = || anyhow!("bad time {:?}, expected HH:MM", s);
+    let mut parts = s.trim().splitn(2, ':');
+    let hour: u32 = parts.next().ok_or_else(bad)?.parse().map_err(|_| bad())?;
+    let minute: u32 = parts.next().ok_or_else(bad)?.parse().map_err(|_| bad())?;
+    if hour > 23 || minute > 59 {
+        return Err(bad());
+    }
+    Ok(hour * 60 + minute)
+}
+
+/// Send the scheduled request on `tx` whenever the rule in effect changes by
+/// the system clock, starting with the one in effect now.
+pub fn spawn(schedule: Schedule, tx: Sender<Request>) {
+    thread::spawn(move || {
+        let mut current = None;
+        loop {
+            let now = Local::now();
+            let minute = now.hour() * 60 + now.minute();
+            if let Some((i, request)) = schedule.lookup(minute) {
+                if current != Some(i) {
+                    info!("scheduled: {:?}", request);
+                    if tx.send(request.clone()).is_err() {
+                        break;
+                    }
+                    current = Some(i);
+                }
+            }
+            thread::sleep(CHECK_INTERVAL);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(at: &str, action: &str) -> Rule {
+        Rule {
+            at: at.to_string(),
+            action: action.to_string(),
+        }
+    }
+
+    #[test]
+    fn lookup_finds_the_rule_in_effect() {
+        let schedule = Schedule::new(&[
+            rule("22:30", "off"),
+            rule("07:00", "set 255 120 40"),
+            rule("18:00", "visualizer"),
+        ])
+        .unwrap();
+        let index = |minute| schedule.lookup(minute).map(|(i, _)| i);
+        // sorted by time: 07:00, 18:00, 22:30
+        assert_eq!(index(7 * 60), Some(0));
+        assert_eq!(index(12 * 60), Some(0));
+        assert_eq!(index(18 * 60), Some(1));
+        assert_eq!(index(23 * 60), Some(2));
+        // before the first rule, the last one from the day before holds
+        assert_eq!(index(3 * 60), Some(2));
+        assert!(matches!(
+            schedule.lookup(3 * 60),
+            Some((_, Request::Off(_)))
+        ));
+        assert!(Schedule::new(&[]).unwrap().lookup(0).is_none());
+    }
+
+    #[test]
+    fn bad_times_and_actions_are_rejected() {
+        assert_eq!(parse_time("07:05").unwrap(), 425);
+        assert!(parse_time("24:00").is_err());
+        assert!(parse_time("7").is_err());
+        assert!(Schedule::new(&[rule("07:00", "dance")]).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+
+/// SilenceDetector goes idle once the energy has stayed below a threshold for
+/// a timeout, and becomes active again as soon as it rises above it.
+pub struct SilenceDetector {
+    threshold: f64,
+    timeout: Duration,
+    quiet_since: Option<Instant>,
+    idle: bool,
+}
+
+impl SilenceDetector {
+    pub fn new(threshold: f64, timeout: Duration) -> Self {
+        Self {
+            threshold,
+            timeout,
+            quiet_since: None,
+            idle: false,
+        }
+    }
+
+    /// Update with the mean energy of a frame, returning whether the input
+    /// is idle.
+    pub fn update(&mut self, energy: f64, now: Instant) -> bool {
+        if energy.abs() >= self.threshold {
+            self.quiet_since = None;
+            self.idle = false;
+            return false;
+        }
+        let since = *self.quiet_since.get_or_insert(now);
+        if now.saturating_duration_since(since) >= self.timeout {
+            self.idle = true;
+        }
+        self.idle
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle
+    }
+}
+
+/// Mean of the band energies.
+pub fn mean_energy(energy: &[f64]) -> f64 {
+    if energy.is_empty() {
+        return 0.;
+    }
+    energy.iter().sum::<f64>() / energy.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn goes_idle_then_active_then_idle_again() {
+        let t0 = Instant::now();
+        let at = |ms| t0 + Duration::from_millis(ms);
+        let mut detector = SilenceDetector::new(0.1, Duration::from_millis(500));
+        assert!(!detector.update(0.05, at(0)));
+        assert!(!detector.update(0.05, at(499)));
+        assert!(detector.update(0.05, at(500)));
+        assert!(detector.is_idle());
+
+        // sound wakes it straight away
+        assert!(!detector.update(0.2, at(600)));
+        assert!(!detector.is_idle());
+
+        // and the timeout starts over once it's quiet again
+        assert!(!detector.update(-0.05, at(700)));
+        assert!(!detector.update(0.05, at(1100)));
+        assert!(detector.update(0.05, at(1200)));
+    }
+
+    #[test]
+    fn mean_energy_of_no_bands_is_zero() {
+        assert_eq!(mean_energy(&[]), 0.);
+        assert_eq!(mean_energy(&[0.5, 1.5]), 1.);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::apa102::{Apa102Error, Strip, ARGB8};
+use crate::ws2812;
+
+#[derive(Copy, Clone, Debug)]
+pub struct RGBW8 {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub w: u8,
+}
+
+impl RGBW8 {
+    pub fn new(r: u8, g: u8, b: u8, w: u8) -> Self {
+        Self { r, g, b, w }
+    }
+
+    /// Convert from ARGB, scaling by the global brightness and moving the
+    /// common part of the color channels onto the white channel.
+    pub fn from_argb(c: ARGB8) -> Self {
+        let (r, g, b) = c.scaled_rgb();
+        let w = u8::min(r, u8::min(g, b));
+        Self::new(r - w, g - w, b - w, w)
+    }
+}
+
+/// Sk6812 RGBW LED strip buffer, which uses the same wire encoding as the Ws2812.
+pub struct Sk6812Rgbw {
+    length: usize,
+    buffer: Vec<u8>,
+}
+
+impl Sk6812Rgbw {
+    pub fn new(length: u16) -> Self {
+        let length = length as usize;
+        Self {
+            length,
+            buffer: ws2812::encoded_buffer(4 * length),
+        }
+    }
+
+    pub fn update_rgbw(&mut self, frame: &[RGBW8]) -> Result<(), Apa102Error> {
+        self.update_from_iter(frame.iter().copied())
+    }
+
+    /// Update from a stream of pixels, so a frame needn't be collected first.
+    pub fn update_from_iter<I: Iterator<Item = RGBW8>>(
+        &mut self,
+        iter: I,
+    ) -> Result<(), Apa102Error> {
+        let buf = &mut self.buffer;
+        let mut got = 0;
+        for (i, e) in iter.take(self.length).enumerate() {
+            let idx = 32 * i;
+            ws2812::encode_byte(e.g, &mut buf[idx..]);
+            ws2812::encode_byte(e.r, &mut buf[idx + 8..]);
+            ws2812::encode_byte(e.b, &mut buf[idx + 16..]);
+            ws2812::encode_byte(e.w, &mut buf[idx + 24..]);
+            got = i + 1;
+        }
+        if got < self.length {
+            return Err(Apa102Error::LengthMismatch {
+                expected: self.length,
+                got,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn get_buffer(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl Strip for Sk6812Rgbw {
+    fn get_buffer(&self) -> &[u8] {
+        Sk6812Rgbw::get_buffer(self)
+    }
+
+    fn update_iter(&mut self, iter: &mut dyn Iterator<Item = ARGB8>) -> Result<(), Apa102Error> {
+        self.update_from_iter(iter.map(RGBW8::from_argb))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode the color bytes from the SPI encoding.
+    fn decode(buffer: &[u8], bytes: usize) -> Vec<u8> {
+        buffer[..8 * bytes]
+            .chunks(8)
+            .map(|bits| {
+                bits.iter()
+                    .fold(0, |b, &bit| b << 1 | (bit == 0b1111_1000) as u8)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn two_led_byte_layout() {
+        let mut leds = Sk6812Rgbw::new(2);
+        let frame = [RGBW8::new(1, 2, 3, 4), RGBW8::new(0, 0, 0, 200)];
+        leds.update_rgbw(&frame).unwrap();
+        assert_eq!(decode(leds.get_buffer(), 8), vec![2, 1, 3, 4, 0, 0, 0, 200]);
+        assert!(leds.get_buffer()[64..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn white_is_independent_of_rgb() {
+        let mut leds = Sk6812Rgbw::new(1);
+        leds.update_rgbw(&[RGBW8::new(10, 20, 30, 0)]).unwrap();
+        assert_eq!(decode(leds.get_buffer(), 4), vec![20, 10, 30, 0]);
+        leds.update_rgbw(&[RGBW8::new(10, 20, 30, 255)]).unwrap();
+        assert_eq!(decode(leds.get_buffer(), 4), vec![20, 10, 30, 255]);
+    }
+
+    #[test]
+    fn argb_moves_common_part_to_white() {
+        let c = RGBW8::from_argb(ARGB8::new(31, 100, 150, 200));
+        assert_eq!((c.r, c.g, c.b, c.w), (0, 50, 100, 100));
+    }
+}
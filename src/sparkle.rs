@@ -0,0 +1,91 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::apa102::ARGB8;
+
+/// Sparkle flashes random pixels white on a trigger, fading them out over the
+/// following frames.
+pub struct Sparkle {
+    levels: Vec<f64>,
+    rng: u64,
+}
+
+impl Sparkle {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            levels: Vec::new(),
+            // xorshift gets stuck at zero
+            rng: seed | 1,
+        }
+    }
+
+    /// Seeded from the clock.
+    pub fn defaults() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Self::new(seed)
+    }
+
+    /// Light `count` distinct random pixels out of `length` at full
+    /// brightness.
+    pub fn trigger(&mut self, count: usize, length: usize) {
+        self.levels.resize(length, 0.);
+        let mut indices: Vec<usize> = (0..length).collect();
+        for n in 0..usize::min(count, length) {
+            let k = n + (self.next() % (length - n) as u64) as usize;
+            indices.swap(n, k);
+            self.levels[indices[n]] = 1.;
+        }
+    }
+
+    /// Fade every sparkle by `decay` out of 1.0 and return them as a layer of
+    /// `length` white pixels, with their level as the alpha.
+    pub fn step(&mut self, length: usize, decay: f64) -> Vec<ARGB8> {
+        self.levels.resize(length, 0.);
+        self.levels
+            .iter_mut()
+            .map(|level| {
+                let a = (31. * *level + 0.5) as u8;
+                *level = f64::max(*level - decay, 0.);
+                ARGB8::new(a, 255, 255, 255)
+            })
+            .collect()
+    }
+
+    fn next(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lit(layer: &[ARGB8]) -> usize {
+        layer.iter().filter(|p| p.a > 0).count()
+    }
+
+    #[test]
+    fn trigger_lights_count_pixels_which_decay_to_zero() {
+        let mut sparkle = Sparkle::new(7);
+        sparkle.trigger(5, 20);
+        let first = sparkle.step(20, 0.25);
+        assert_eq!(lit(&first), 5);
+        assert!(first.iter().all(|p| p.a == 0 || p.a == 31));
+        for _ in 0..3 {
+            assert_eq!(lit(&sparkle.step(20, 0.25)), 5);
+        }
+        assert_eq!(lit(&sparkle.step(20, 0.25)), 0);
+    }
+
+    #[test]
+    fn trigger_is_capped_at_length() {
+        let mut sparkle = Sparkle::new(0);
+        sparkle.trigger(10, 4);
+        assert_eq!(lit(&sparkle.step(4, 1.)), 4);
+    }
+}
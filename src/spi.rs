@@ -0,0 +1,435 @@
+//! The SPI bus, which is only available when built with the `hardware` feature.
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+
+use crate::apa102::ARGB8;
+
+#[cfg(feature = "hardware")]
+pub use rppal::spi::Spi;
+
+/// Clock polarity and phase, as numbered by the SPI spec.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mode {
+    Mode0,
+    Mode1,
+    Mode2,
+    Mode3,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().trim_start_matches("mode") {
+            "0" => Ok(Mode::Mode0),
+            "1" => Ok(Mode::Mode1),
+            "2" => Ok(Mode::Mode2),
+            "3" => Ok(Mode::Mode3),
+            _ => Err(anyhow!("unknown spi mode: {}, expected 0 to 3", s)),
+        }
+    }
+}
+
+/// Parse a bus number, which the pi numbers from 0 to 6.
+pub fn parse_bus(s: &str) -> Result<u8> {
+    match s.parse()? {
+        bus @ 0..=6 => Ok(bus),
+        bus => Err(anyhow!("no such spi bus: {}, expected 0 to 6", bus)),
+    }
+}
+
+/// Parse a chip select number, which the pi numbers from 0 to 2.
+pub fn parse_cs(s: &str) -> Result<u8> {
+    match s.parse()? {
+        cs @ 0..=2 => Ok(cs),
+        cs => Err(anyhow!("no such chip select: {}, expected 0 to 2", cs)),
+    }
+}
+
+/// Open SPI bus number `bus` which a strip is connected to, selecting chip
+/// `cs`.
+#[cfg(feature = "hardware")]
+pub fn open(bus: u8, cs: u8, mode: Mode, clock: u32) -> Result<Spi> {
+    use rppal::spi::{self, Bus, SlaveSelect};
+    let bus = match bus {
+        0 => Bus::Spi0,
+        1 => Bus::Spi1,
+        2 => Bus::Spi2,
+        3 => Bus::Spi3,
+        4 => Bus::Spi4,
+        5 => Bus::Spi5,
+        6 => Bus::Spi6,
+        _ => return Err(anyhow!("no such spi bus: {}", bus)),
+    };
+    let cs = match cs {
+        0 => SlaveSelect::Ss0,
+        1 => SlaveSelect::Ss1,
+        2 => SlaveSelect::Ss2,
+        _ => return Err(anyhow!("no such chip select: {}", cs)),
+    };
+    let mode = match mode {
+        Mode::Mode0 => spi::Mode::Mode0,
+        Mode::Mode1 => spi::Mode::Mode1,
+        Mode::Mode2 => spi::Mode::Mode2,
+        Mode::Mode3 => spi::Mode::Mode3,
+    };
+    Ok(Spi::new(bus, cs, clock, mode)?)
+}
+
+/// Stand in for the SPI bus when built without hardware support, which can
+/// never be opened.
+#[cfg(not(feature = "hardware"))]
+pub enum Spi {}
+
+#[cfg(not(feature = "hardware"))]
+impl Spi {
+    pub fn write(&mut self, _buffer: &[u8]) -> Result<usize> {
+        match *self {}
+    }
+}
+
+/// Open SPI bus number `bus` which a strip is connected to, selecting chip
+/// `cs`.
+#[cfg(not(feature = "hardware"))]
+pub fn open(_bus: u8, _cs: u8, _mode: Mode, _clock: u32) -> Result<Spi> {
+    Err(anyhow!(
+        "built without the hardware feature, only --dry-run is available"
+    ))
+}
+
+/// Lowest clock the backoff steps down to.
+const MIN_CLOCK: u32 = 500_000;
+
+/// ClockBackoff steps the clock down by a quarter each time `threshold`
+/// frames in a row fail to be written, until it reaches `MIN_CLOCK`.
+pub struct ClockBackoff {
+    clock: u32,
+    threshold: usize,
+    failures: usize,
+}
+
+impl ClockBackoff {
+    pub fn new(clock: u32, threshold: usize) -> Self {
+        Self {
+            clock,
+            threshold,
+            failures: 0,
+        }
+    }
+
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Count a frame which failed to be written, returning the lower clock to
+    /// switch to if there have been too many in a row.
+    pub fn failed(&mut self) -> Option<u32> {
+        self.failures += 1;
+        if self.failures < self.threshold || self.clock <= MIN_CLOCK {
+            return None;
+        }
+        self.failures = 0;
+        self.clock = u32::max(self.clock / 4 * 3, MIN_CLOCK);
+        Some(self.clock)
+    }
+}
+
+/// Which bus to open, and how to talk to it.
+#[derive(Copy, Clone, Debug)]
+pub struct Options {
+    pub bus: u8,
+    pub cs: u8,
+    pub mode: Mode,
+    pub clock: u32,
+}
+
+/// AdaptiveSpi retries failed writes, and reopens its bus at a lower clock
+/// when frames keep failing.
+pub struct AdaptiveSpi {
+    spi: Spi,
+    opts: Options,
+    backoff: ClockBackoff,
+}
+
+impl AdaptiveSpi {
+    /// Consecutive failed frames before the clock is lowered.
+    const FAILURES: usize = 3;
+
+    pub fn open(opts: Options) -> Result<Self> {
+        Ok(Self {
+            spi: open(opts.bus, opts.cs, opts.mode, opts.clock)?,
+            opts,
+            backoff: ClockBackoff::new(opts.clock, Self::FAILURES),
+        })
+    }
+
+    /// Write a frame, retrying up to `WRITE_ATTEMPTS` times. Only a frame
+    /// which fails every attempt counts towards lowering the clock.
+    pub fn write(&mut self, buffer: &[u8]) -> Result<usize> {
+        let spi = &mut self.spi;
+        match retry(WRITE_ATTEMPTS, RETRY_DELAY, || Ok(spi.write(buffer)?)) {
+            Ok(n) => {
+                self.backoff.succeeded();
+                Ok(n)
+            }
+            Err(e) => {
+                if let Some(clock) = self.backoff.failed() {
+                    let o = self.opts;
+                    warn!(
+                        "spi bus {} keeps failing, lowering clock to {} hz",
+                        o.bus, clock
+                    );
+                    self.spi = open(o.bus, o.cs, o.mode, clock)?;
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Attempts at writing each frame before it's dropped.
+pub const WRITE_ATTEMPTS: usize = 3;
+
+/// Wait before retrying a failed write, doubled for each further attempt.
+pub const RETRY_DELAY: Duration = Duration::from_millis(1);
+
+/// Call `f` up to `attempts` times until it succeeds, sleeping for `delay`
+/// after the first failure and twice as long after each one after that.
+/// Returns the last error if every attempt failed.
+pub fn retry<T, F: FnMut() -> Result<T>>(attempts: usize, delay: Duration, mut f: F) -> Result<T> {
+    let mut delay = delay;
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt >= attempts => return Err(e),
+            Err(e) => {
+                debug!(
+                    "attempt {} of {} failed, retrying: {}",
+                    attempt, attempts, e
+                );
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Split a frame into consecutive parts of the given lengths, one for each
+/// bus. Parts past the end of the frame are cut short.
+pub fn split<'a>(frame: &'a [ARGB8], lengths: &[usize]) -> Vec<&'a [ARGB8]> {
+    let mut start = 0;
+    lengths
+        .iter()
+        .map(|&length| {
+            let from = usize::min(start, frame.len());
+            start += length;
+            &frame[from..usize::min(start, frame.len())]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apa102::{Apa102, ChipType, ColorOrder};
+
+    /// FakeSpi stands in for the bus in tests, checking that each buffer written
+    /// is a well formed APA102 transfer and decoding the frame it carries.
+    struct FakeSpi {
+        length: usize,
+        order: [usize; 3],
+        frames: Vec<Vec<ARGB8>>,
+    }
+
+    impl FakeSpi {
+        fn new(length: usize, order: ColorOrder) -> Self {
+            Self {
+                length,
+                order: order.offsets(),
+                frames: Vec::new(),
+            }
+        }
+
+        fn write(&mut self, buffer: &[u8]) -> Result<usize> {
+            let frame = self.decode(buffer)?;
+            self.frames.push(frame);
+            Ok(buffer.len())
+        }
+
+        /// Frames decoded from every write so far.
+        fn frames(&self) -> &[Vec<ARGB8>] {
+            &self.frames
+        }
+
+        fn decode(&self, buffer: &[u8]) -> Result<Vec<ARGB8>> {
+            let led_end = 4 * (1 + self.length);
+            // at least half a clock per LED is needed to latch the last pixel
+            let min_end_frame = (self.length + 15) / 16;
+            if buffer.len() < led_end + min_end_frame {
+                return Err(anyhow!(
+                    "buffer of {} bytes too short for {} leds",
+                    buffer.len(),
+                    self.length
+                ));
+            }
+            if buffer[..4] != [0, 0, 0, 0] {
+                return Err(anyhow!("bad start frame: {:02x?}", &buffer[..4]));
+            }
+            if let Some(b) = buffer[led_end..].iter().find(|&&b| b != 0 && b != 0xff) {
+                return Err(anyhow!("bad end frame byte: {:02x}", b));
+            }
+            let [ro, go, bo] = self.order;
+            buffer[4..led_end]
+                .chunks(4)
+                .enumerate()
+                .map(|(i, led)| {
+                    if led[0] & 0xe0 != 0xe0 {
+                        return Err(anyhow!("led {} missing framing bits: {:02x}", i, led[0]));
+                    }
+                    Ok(ARGB8::new(led[0] & 0x1f, led[ro], led[go], led[bo]))
+                })
+                .collect()
+        }
+    }
+
+    #[cfg(not(feature = "hardware"))]
+    #[test]
+    fn without_hardware_the_bus_cannot_be_opened() {
+        let err = open(0, 0, Mode::Mode0, 8_000_000).err().unwrap();
+        assert!(err.to_string().contains("--dry-run"));
+        let opts = Options {
+            bus: 0,
+            cs: 0,
+            mode: Mode::Mode0,
+            clock: 8_000_000,
+        };
+        assert!(AdaptiveSpi::open(opts).is_err());
+    }
+
+    #[test]
+    fn apa102_transfers_decode_to_their_frame() {
+        let frame: Vec<ARGB8> = (0..20u8)
+            .map(|i| ARGB8::new(i + 10, i * 12, 255 - i, i ^ 0x5a))
+            .collect();
+        for &chip in &[ChipType::Apa102, ChipType::Sk9822] {
+            for &order in &[ColorOrder::Rgb, ColorOrder::Grb, ColorOrder::Bgr] {
+                let mut strip = Apa102::new(20, chip, order);
+                strip.update(&frame).unwrap();
+                let mut spi = FakeSpi::new(20, order);
+                spi.write(strip.get_buffer()).unwrap();
+                assert_eq!(spi.frames(), &[frame.clone()], "{:?} {:?}", chip, order);
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_transfers_are_rejected() {
+        let mut strip = Apa102::new(20, ChipType::Apa102, ColorOrder::Bgr);
+        strip.update(&vec![ARGB8::new(31, 1, 2, 3); 20]).unwrap();
+        let mut spi = FakeSpi::new(20, ColorOrder::Bgr);
+        let buffer = strip.get_buffer().to_vec();
+        assert!(spi.write(&buffer[..4 * 21]).is_err());
+        let mut unframed = buffer.clone();
+        unframed[8] = 0x1f;
+        assert!(spi.write(&unframed).is_err());
+        let mut started = buffer;
+        started[0] = 1;
+        assert!(spi.write(&started).is_err());
+        assert!(spi.frames().is_empty());
+    }
+
+    #[test]
+    fn splits_at_bus_boundaries() {
+        let frame: Vec<ARGB8> = (0..10).map(|i| ARGB8::new(31, i, 0, 0)).collect();
+        let parts = split(&frame, &[4, 6]);
+        assert_eq!(parts, vec![&frame[..4], &frame[4..]]);
+        // a short frame leaves the last buses with less, or nothing
+        let parts = split(&frame[..5], &[4, 4, 4]);
+        assert_eq!(
+            parts.iter().map(|p| p.len()).collect::<Vec<_>>(),
+            vec![4, 1, 0]
+        );
+        assert_eq!(parts[1], &frame[4..5]);
+    }
+
+    #[test]
+    fn parses_mode_bus_and_cs() {
+        assert_eq!("0".parse::<Mode>().unwrap(), Mode::Mode0);
+        assert_eq!("mode3".parse::<Mode>().unwrap(), Mode::Mode3);
+        assert_eq!("Mode1".parse::<Mode>().unwrap(), Mode::Mode1);
+        assert!("4".parse::<Mode>().is_err());
+        assert_eq!(parse_bus("6").unwrap(), 6);
+        assert!(parse_bus("7").is_err());
+        assert!(parse_bus("-1").is_err());
+        assert_eq!(parse_cs("2").unwrap(), 2);
+        let err = parse_cs("3").unwrap_err();
+        assert_eq!(err.to_string(), "no such chip select: 3, expected 0 to 2");
+    }
+
+    #[test]
+    fn backoff_lowers_the_clock_after_failed_frames() {
+        let mut backoff = ClockBackoff::new(2_000_000, 3);
+        let mut clocks = Vec::new();
+        for _ in 0..5 {
+            assert_eq!(backoff.failed(), None);
+            assert_eq!(backoff.failed(), None);
+            clocks.push(backoff.failed());
+        }
+        assert_eq!(
+            clocks,
+            vec![
+                Some(1_500_000),
+                Some(1_125_000),
+                Some(843_750),
+                Some(632_811),
+                Some(500_000)
+            ]
+        );
+        // it stays at the minimum
+        for _ in 0..6 {
+            assert_eq!(backoff.failed(), None);
+        }
+    }
+
+    #[test]
+    fn a_success_resets_the_failure_count() {
+        let mut backoff = ClockBackoff::new(2_000_000, 3);
+        backoff.failed();
+        backoff.failed();
+        backoff.succeeded();
+        assert_eq!(backoff.failed(), None);
+        assert_eq!(backoff.failed(), None);
+        assert_eq!(backoff.failed(), Some(1_500_000));
+    }
+
+    #[test]
+    fn retry_succeeds_on_the_second_attempt() {
+        let mut calls = 0;
+        let result = retry(3, Duration::from_millis(1), || {
+            calls += 1;
+            if calls < 2 {
+                Err(anyhow::anyhow!("busy"))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn retry_gives_up_after_its_attempts() {
+        let mut calls = 0;
+        let result: Result<()> = retry(3, Duration::from_millis(1), || {
+            calls += 1;
+            Err(anyhow::anyhow!("attempt {}", calls))
+        });
+        assert_eq!(result.unwrap_err().to_string(), "attempt 3");
+        assert_eq!(calls, 3);
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread;
+use std::time::Instant;
+
+use crate::apa102::ARGB8;
+use crate::display::Frame;
+
+/// StrobeLimiter caps how fast the overall brightness of the output can
+/// swing, so flashing faster than `max_hz` full-range flashes a second is
+/// smoothed out rather than shown.
+pub struct StrobeLimiter {
+    max_hz: f64,
+    // linear colors of the last frame output, from 0.0 to 255.0
+    last: Vec<(f64, f64, f64)>,
+    last_at: Option<Instant>,
+}
+
+impl StrobeLimiter {
+    pub fn new(max_hz: f64) -> Self {
+        Self {
+            max_hz,
+            last: Vec::new(),
+            last_at: None,
+        }
+    }
+
+    /// Limit `frame` shown at `now`. Frames are passed through unchanged
+    /// unless their brightness moved further from the last output than a full
+    /// flash at `max_hz` could, in which case they're mixed with the last
+    /// output to bring the change down to the limit.
+    pub fn limit(&mut self, frame: &[ARGB8], now: Instant) -> Vec<ARGB8> {
+        let current: Vec<(f64, f64, f64)> = frame
+            .iter()
+            .map(|p| {
+                let (r, g, b) = p.scaled_rgb();
+                (r as f64, g as f64, b as f64)
+            })
+            .collect();
+        let elapsed = match self.last_at.replace(now) {
+            Some(at) if self.last.len() == current.len() => {
+                now.saturating_duration_since(at).as_secs_f64()
+            }
+            _ => {
+                self.last = current;
+                return frame.to_vec();
+            }
+        };
+
+        // a full flash goes up and back down again within each period
+        let max_change = 2. * self.max_hz * elapsed;
+        let change = (brightness(&current) - brightness(&self.last)).abs();
+        if change <= max_change {
+            self.last = current;
+            return frame.to_vec();
+        }
+
+        let k = max_change / change;
+        let mix = |l: f64, c: f64| l + k * (c - l);
+        for (l, c) in self.last.iter_mut().zip(&current) {
+            *l = (mix(l.0, c.0), mix(l.1, c.1), mix(l.2, c.2));
+        }
+        self.last
+            .iter()
+            .map(|&(r, g, b)| ARGB8::new(31, (r + 0.5) as u8, (g + 0.5) as u8, (b + 0.5) as u8))
+            .collect()
+    }
+}
+
+/// Mean brightness of linear colors, from 0.0 to 1.0.
+fn brightness(frame: &[(f64, f64, f64)]) -> f64 {
+    if frame.is_empty() {
+        return 0.;
+    }
+    let total: f64 = frame.iter().map(|(r, g, b)| r + g + b).sum();
+    total / (3. * 255. * frame.len() as f64)
+}
+
+/// Limit the flashing of frames from `frame_rx` to `max_hz`, returning the
+/// receiver of the limited frames.
+pub fn spawn(frame_rx: Receiver<Frame<ARGB8>>, max_hz: f64) -> Receiver<Frame<ARGB8>> {
+    let (tx, rx) = sync_channel(0);
+    thread::spawn(move || {
+        let mut limiter = StrobeLimiter::new(max_hz);
+        while let Ok(mut frame) = frame_rx.recv() {
+            frame.set_pixels(limiter.limit(&frame.pixels, Instant::now()));
+            if tx.send(frame).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn level(frame: &[ARGB8]) -> f64 {
+        let linear: Vec<(f64, f64, f64)> = frame
+            .iter()
+            .map(|p| {
+                let (r, g, b) = p.scaled_rgb();
+                (r as f64, g as f64, b as f64)
+            })
+            .collect();
+        brightness(&linear)
+    }
+
+    #[test]
+    fn flashing_at_30hz_is_smoothed_below_a_3hz_cap() {
+        let mut limiter = StrobeLimiter::new(3.);
+        let start = Instant::now();
+        let period = Duration::from_secs_f64(1. / 30.);
+        let frames = [
+            vec![ARGB8::new(31, 0, 0, 0); 4],
+            vec![ARGB8::new(31, 255, 255, 255); 4],
+        ];
+        let mut last = level(&limiter.limit(&frames[0], start));
+        for n in 1..60 {
+            let out = limiter.limit(&frames[n % 2], start + period * n as u32);
+            let l = level(&out);
+            // a 3Hz flash may move 2 * 3 / 30 of full range per frame
+            assert!(
+                (l - last).abs() <= 0.2 + 1. / 255.,
+                "frame {}: {} -> {}",
+                n,
+                last,
+                l
+            );
+            last = l;
+        }
+    }
+
+    #[test]
+    fn slow_changes_pass_through() {
+        let mut limiter = StrobeLimiter::new(3.);
+        let start = Instant::now();
+        let dim = vec![ARGB8::new(31, 100, 100, 100); 2];
+        let bright = vec![ARGB8::new(31, 110, 110, 110); 2];
+        limiter.limit(&dim, start);
+        assert_eq!(
+            limiter.limit(&bright, start + Duration::from_millis(100)),
+            bright
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{anyhow, Result};
+
+use crate::display::FrameLimiter;
+use crate::wav::Playback;
+
+/// Synth generates a repeatable test signal of summed sine tones.
+pub struct Synth {
+    sample_rate: f64,
+    freqs: Vec<f64>,
+    t: usize,
+}
+
+impl Synth {
+    pub fn new(sample_rate: f64, freqs: Vec<f64>) -> Self {
+        Self {
+            sample_rate,
+            freqs,
+            t: 0,
+        }
+    }
+
+    /// The next `size` samples, normalized to the range -1.0 to 1.0.
+    pub fn block(&mut self, size: usize) -> Vec<f64> {
+        let gain = 1. / usize::max(self.freqs.len(), 1) as f64;
+        let block = (self.t..self.t + size)
+            .map(|i| {
+                let t = i as f64 / self.sample_rate;
+                gain * self
+                    .freqs
+                    .iter()
+                    .map(|f| f64::sin(2. * PI * f * t))
+                    .sum::<f64>()
+            })
+            .collect();
+        self.t += size;
+        block
+    }
+}
+
+/// Test signal generated in place of audio input.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Signal {
+    /// A sine sweeping up from 40 Hz to 8 kHz every ten seconds.
+    Sweep,
+    /// Pink noise.
+    Noise,
+    /// Bass, mid and high tones pulsing at 120 bpm.
+    Beat,
+}
+
+impl std::str::FromStr for Signal {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sweep" => Ok(Signal::Sweep),
+            "noise" => Ok(Signal::Noise),
+            "beat" => Ok(Signal::Beat),
+            _ => Err(anyhow!(
+                "unknown signal: {}, expected sweep, noise or beat",
+                s
+            )),
+        }
+    }
+}
+
+/// Generator produces blocks of a test signal.
+pub struct Generator {
+    signal: Signal,
+    sample_rate: f64,
+    t: usize,
+    phase: f64,
+    tones: Synth,
+    rng: u64,
+    // state of the pink noise filter
+    pink: [f64; 7],
+}
+
+impl Generator {
+    const SWEEP_PERIOD: f64 = 10.;
+    const SWEEP_RANGE: (f64, f64) = (40., 8000.);
+    const BEAT_PERIOD: f64 = 0.5;
+
+    pub fn new(signal: Signal, sample_rate: f64) -> Self {
+        Self {
+            signal,
+            sample_rate,
+            t: 0,
+            phase: 0.,
+            tones: Synth::new(sample_rate, vec![110., 440., 3520.]),
+            rng: 0x2545_f491_4f6c_dd1d,
+            pink: [0.; 7],
+        }
+    }
+
+    /// The next `size` samples, in the range -1.0 to 1.0.
+    pub fn block(&mut self, size: usize) -> Vec<f64> {
+        let block = match self.signal {
+            Signal::Sweep => (0..size).map(|i| self.sweep(self.t + i)).collect(),
+            Signal::Noise => (0..size).map(|_| self.pink()).collect(),
+            Signal::Beat => {
+                let tones = self.tones.block(size);
+                let t0 = self.t;
+                tones
+                    .iter()
+                    .enumerate()
+                    .map(|(i, x)| {
+                        // decaying pulse at the start of each beat
+                        let t = (t0 + i) as f64 / self.sample_rate;
+                        x * f64::exp(-8. * (t % Self::BEAT_PERIOD))
+                    })
+                    .collect()
+            }
+        };
+        self.t += size;
+        block
+    }
+
+    fn sweep(&mut self, i: usize) -> f64 {
+        let (lo, hi) = Self::SWEEP_RANGE;
+        let t = (i as f64 / self.sample_rate) % Self::SWEEP_PERIOD;
+        let freq = lo * (hi / lo).powf(t / Self::SWEEP_PERIOD);
+        self.phase = (self.phase + freq / self.sample_rate) % 1.;
+        f64::sin(2. * PI * self.phase)
+    }
+
+    /// Paul Kellet's filter of white noise.
+    fn pink(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let white = (self.rng >> 11) as f64 / (1u64 << 53) as f64 * 2. - 1.;
+        let b = &mut self.pink;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.1538520;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b.iter().sum::<f64>() + white * 0.5362;
+        b[6] = white * 0.115926;
+        f64::min(f64::max(0.11 * pink, -1.), 1.)
+    }
+}
+
+/// Parse a block size in samples, which must be at least one.
+pub fn parse_block_size(s: &str) -> Result<usize> {
+    match s.parse()? {
+        0 => Err(anyhow!("block size must be at least 1")),
+        size => Ok(size),
+    }
+}
+
+/// Pass interleaved blocks of `block_size` samples of `signal` per channel to
+/// `handler` in real time at `sample_rate`, like a live audio stream.
+pub fn play(
+    signal: Signal,
+    sample_rate: u32,
+    channels: usize,
+    block_size: usize,
+    handler: Box<dyn Fn(&[f32]) -> () + Send>,
+) -> Playback {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    thread::spawn(move || {
+        let mut generator = Generator::new(signal, sample_rate as f64);
+        let mut limiter = FrameLimiter::new(sample_rate as f64 / block_size as f64);
+        while !stopped.load(Ordering::Relaxed) {
+            let block: Vec<f32> = generator
+                .block(block_size)
+                .iter()
+                .flat_map(|&x| std::iter::repeat(x as f32).take(channels))
+                .collect();
+            handler(&block);
+            limiter.wait();
+        }
+    });
+    Playback::new(stop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn synth_blocks_have_the_configured_size() {
+        let freqs = vec![110., 440., 3520.];
+        let mut synth = Synth::new(44100., freqs.clone());
+        let blocks: Vec<Vec<f64>> = (0..3).map(|_| synth.block(256)).collect();
+        assert!(blocks.iter().all(|b| b.len() == 256));
+        // consecutive blocks continue the same signal
+        let whole = Synth::new(44100., freqs).block(768);
+        assert_eq!(blocks.concat(), whole);
+    }
+
+    #[test]
+    fn generated_signals_are_bounded_and_repeatable() {
+        for &signal in &[Signal::Sweep, Signal::Noise, Signal::Beat] {
+            let mut generator = Generator::new(signal, 44100.);
+            let blocks: Vec<Vec<f64>> = (0..20).map(|_| generator.block(1024)).collect();
+            let samples = blocks.concat();
+            assert!(
+                samples.iter().all(|x| (-1.0..=1.0).contains(x)),
+                "{:?} out of range",
+                signal
+            );
+            assert!(samples.iter().any(|&x| x != 0.), "{:?} is silent", signal);
+            let mut again = Generator::new(signal, 44100.);
+            assert_eq!(
+                again.block(20 * 1024),
+                samples,
+                "{:?} isn't repeatable",
+                signal
+            );
+        }
+    }
+
+    #[test]
+    fn zero_block_size_is_rejected() {
+        assert_eq!(parse_block_size("256").unwrap(), 256);
+        assert!(parse_block_size("0").is_err());
+        assert!(parse_block_size("-1").is_err());
+    }
+}
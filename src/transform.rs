@@ -1,44 +1,382 @@
-use crate::apa102::ARGB8;
+use std::convert::TryFrom;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::apa102::{gamma_table, ARGB8};
 use crate::display;
 
+/// Strip layout, as stored in a mapping file.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LayoutConfig {
+    pub strip_lengths: Vec<u16>,
+    pub reversed: Vec<bool>,
+    pub x_map: Vec<usize>,
+}
+
+/// Arbitrary layout, as stored in a coordinate mapping file, giving the
+/// logical (x, y) source pixel of each physical LED in a `width x height`
+/// frame.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CoordMapConfig {
+    pub width: usize,
+    pub height: usize,
+    pub coords: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TransformError {
+    NumStripsMismatch {
+        num_strips: usize,
+        reversed: usize,
+        x_map: usize,
+    },
+    OutOfBounds {
+        x: usize,
+        y: usize,
+    },
+    InvalidXMap {
+        missing: Vec<usize>,
+        duplicates: Vec<usize>,
+    },
+    TooManyStrips {
+        num_strips: usize,
+    },
+    StripTooLong {
+        length: usize,
+    },
+}
+
+impl std::fmt::Display for TransformError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TransformError::NumStripsMismatch {
+                num_strips,
+                reversed,
+                x_map,
+            } => write!(
+                f,
+                "invalid reverse or x_map. vectors must be exactly size of num_strips: \
+                 num_strips={}, reversed={}, x_map={}",
+                num_strips, reversed, x_map
+            ),
+            TransformError::OutOfBounds { x, y } => {
+                write!(f, "pixel {{x:{:},y:{:}}} is out of bounds", x, y)
+            }
+            TransformError::InvalidXMap {
+                missing,
+                duplicates,
+            } => write!(
+                f,
+                "x_map must be a permutation of strip indices: missing {:?}, duplicates {:?}",
+                missing, duplicates
+            ),
+            TransformError::TooManyStrips { num_strips } => write!(
+                f,
+                "too many strips: {}, at most {} are supported",
+                num_strips,
+                u8::MAX
+            ),
+            TransformError::StripTooLong { length } => write!(
+                f,
+                "strip too long: {} LEDs, at most {} are supported",
+                length,
+                u16::MAX
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TransformError {}
+
+/// Clockwise rotation of the logical frame before it's mapped onto the strips.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Rotation {
+    R0,
+    R90,
+    R180,
+    R270,
+}
+
+impl std::str::FromStr for Rotation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "0" => Ok(Rotation::R0),
+            "90" => Ok(Rotation::R90),
+            "180" => Ok(Rotation::R180),
+            "270" => Ok(Rotation::R270),
+            _ => Err(anyhow::anyhow!("rotation must be 0, 90, 180 or 270: {}", s)),
+        }
+    }
+}
+
 pub struct Transform {
     num_strips: u8,
+    /// Length of the longest strip, which is the height of the logical frame.
     strip_length: u16,
+    strip_lengths: Vec<u16>,
+    /// Output index of the start of each strip.
+    offsets: Vec<usize>,
     reversed: Vec<bool>,
     x_map: Vec<usize>,
+    coord_map: Option<Vec<Option<usize>>>,
+    rotation: Rotation,
 }
 
 impl Transform {
-    pub fn new(num_strips: u8, strip_length: u16, reversed: Vec<bool>, x_map: Vec<usize>) -> Self {
-        let size = num_strips as usize;
+    pub fn new(strip_lengths: Vec<u16>, reversed: Vec<bool>, x_map: Vec<usize>) -> Self {
+        match Self::try_new(strip_lengths, reversed, x_map) {
+            Ok(t) => t,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    pub fn try_new(
+        strip_lengths: Vec<u16>,
+        reversed: Vec<bool>,
+        x_map: Vec<usize>,
+    ) -> Result<Self, TransformError> {
+        let size = strip_lengths.len();
         if reversed.len() != size || x_map.len() != size {
-            panic!("invalid reverse or x_map. vectors must be exactly size of num_strips");
+            return Err(TransformError::NumStripsMismatch {
+                num_strips: size,
+                reversed: reversed.len(),
+                x_map: x_map.len(),
+            });
         }
-        Self {
-            num_strips,
-            strip_length,
+        Self::check_x_map(&x_map)?;
+        Ok(Self {
+            num_strips: Self::num_strips(size)?,
+            strip_length: strip_lengths.iter().copied().max().unwrap_or(0),
+            offsets: Self::offsets(&strip_lengths),
+            strip_lengths,
             reversed,
             x_map,
+            coord_map: None,
+            rotation: Rotation::R0,
+        })
+    }
+
+    /// Arbitrary layout where `coords` gives the logical (x, y) source pixel
+    /// of each physical LED. LEDs mapped outside of the frame are left black.
+    pub fn from_coord_map(
+        width: usize,
+        height: usize,
+        coords: Vec<(usize, usize)>,
+    ) -> Result<Self, TransformError> {
+        let num_strips = Self::num_strips(width)?;
+        let strip_length =
+            u16::try_from(height).map_err(|_| TransformError::StripTooLong { length: height })?;
+        let coord_map = coords
+            .iter()
+            .map(|&(x, y)| {
+                if x < width && y < height {
+                    Some(x * height + y)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let strip_lengths = vec![strip_length; width];
+        Ok(Self {
+            num_strips,
+            strip_length,
+            offsets: Self::offsets(&strip_lengths),
+            strip_lengths,
+            reversed: vec![false; width],
+            x_map: (0..width).collect(),
+            coord_map: Some(coord_map),
+            rotation: Rotation::R0,
+        })
+    }
+
+    /// Load an arbitrary layout from a YAML or JSON coordinate mapping file.
+    pub fn from_coord_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let f = std::fs::File::open(path)?;
+        let map: CoordMapConfig = serde_yaml::from_reader(f)?;
+        Ok(Self::from_coord_map(map.width, map.height, map.coords)?)
+    }
+
+    /// Rotate the logical frame, which swaps its dimensions for 90 and 270.
+    pub fn with_rotation(mut self, rotation: Rotation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// Size of the logical frame expected by `apply`, as (width, height).
+    pub fn input_size(&self) -> (usize, usize) {
+        let (w, h) = (self.num_strips as usize, self.strip_length as usize);
+        match self.rotation {
+            Rotation::R0 | Rotation::R180 => (w, h),
+            Rotation::R90 | Rotation::R270 => (h, w),
+        }
+    }
+
+    /// Map layout coordinates to coordinates in the rotated logical frame.
+    fn rotate(&self, x: usize, y: usize) -> (usize, usize) {
+        let (w, h) = (self.num_strips as usize, self.strip_length as usize);
+        match self.rotation {
+            Rotation::R0 => (x, y),
+            Rotation::R90 => (y, w - 1 - x),
+            Rotation::R180 => (w - 1 - x, h - 1 - y),
+            Rotation::R270 => (h - 1 - y, x),
+        }
+    }
+
+    /// Inverse of `rotate`.
+    fn unrotate(&self, x: usize, y: usize) -> (usize, usize) {
+        let (w, h) = (self.num_strips as usize, self.strip_length as usize);
+        match self.rotation {
+            Rotation::R0 => (x, y),
+            Rotation::R90 => (w - 1 - y, x),
+            Rotation::R180 => (w - 1 - x, h - 1 - y),
+            Rotation::R270 => (y, h - 1 - x),
+        }
+    }
+
+    fn num_strips(num_strips: usize) -> Result<u8, TransformError> {
+        u8::try_from(num_strips).map_err(|_| TransformError::TooManyStrips { num_strips })
+    }
+
+    fn offsets(strip_lengths: &[u16]) -> Vec<usize> {
+        let mut offsets = vec![0];
+        for &l in strip_lengths {
+            offsets.push(offsets[offsets.len() - 1] + l as usize);
+        }
+        offsets
+    }
+
+    fn check_x_map(x_map: &[usize]) -> Result<(), TransformError> {
+        let mut counts = vec![0; x_map.len()];
+        let mut duplicates = vec![];
+        for &x in x_map {
+            if x < counts.len() {
+                counts[x] += 1;
+                if counts[x] == 2 {
+                    duplicates.push(x);
+                }
+            }
+        }
+        let missing: Vec<usize> = (0..counts.len()).filter(|&x| counts[x] == 0).collect();
+        if !missing.is_empty() || !duplicates.is_empty() {
+            return Err(TransformError::InvalidXMap {
+                missing,
+                duplicates,
+            });
+        }
+        Ok(())
+    }
+
+    /// Load a layout from a YAML or JSON mapping file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let f = std::fs::File::open(path)?;
+        let layout: LayoutConfig = serde_yaml::from_reader(f)?;
+        Self::from_layout(layout)
+    }
+
+    pub fn from_layout(layout: LayoutConfig) -> Result<Self> {
+        Ok(Self::try_new(
+            layout.strip_lengths,
+            layout.reversed,
+            layout.x_map,
+        )?)
+    }
+
+    /// Strip layout, which a coordinate mapped layout doesn't have.
+    pub fn layout(&self) -> Option<LayoutConfig> {
+        if self.coord_map.is_some() {
+            return None;
         }
+        Some(LayoutConfig {
+            strip_lengths: self.strip_lengths.clone(),
+            reversed: self.reversed.clone(),
+            x_map: self.x_map.clone(),
+        })
+    }
+
+    /// Layout for a single strip snaking back and forth, where every other
+    /// row runs in the opposite direction.
+    pub fn serpentine(num_strips: u8, strip_length: u16) -> Self {
+        let size = num_strips as usize;
+        let reversed = (0..size).map(|x| x % 2 == 1).collect();
+        let x_map = (0..size).collect();
+        Self::new(vec![strip_length; size], reversed, x_map)
     }
 
     pub fn apply(&self, frame: &Vec<ARGB8>) -> Vec<ARGB8> {
+        self.apply_iter(frame).collect()
+    }
+
+    /// Lazily produce the transformed frame in output order.
+    pub fn apply_iter<'a>(&'a self, frame: &'a [ARGB8]) -> impl Iterator<Item = ARGB8> + 'a {
+        (0..self.output_len()).map(move |i| match self.source(i) {
+            Some(idx) => frame[idx],
+            None => ARGB8::new(0, 0, 0, 0),
+        })
+    }
+
+    fn output_len(&self) -> usize {
+        match &self.coord_map {
+            Some(map) => map.len(),
+            None => self.offsets[self.offsets.len() - 1],
+        }
+    }
+
+    /// Index in the logical frame which output pixel `i` is taken from.
+    fn source(&self, i: usize) -> Option<usize> {
         let l = self.strip_length as usize;
-        let n = self.num_strips as usize;
-        (0..n)
-            .map(|x| (self.x_map[x], self.reversed[x]))
-            .map(|x| (x, frame[l * x.0..l * (x.0 + 1)].iter().copied()))
-            .map(
-                |(x, s)| -> Vec<ARGB8> {
-                    if x.1 {
-                        s.rev().collect()
-                    } else {
-                        s.collect()
-                    }
-                },
-            )
-            .flatten()
-            .collect()
+        let idx = if let Some(map) = &self.coord_map {
+            map[i]?
+        } else {
+            let x = self.offsets.iter().rposition(|&o| o <= i)?;
+            let (y, len) = (i - self.offsets[x], self.strip_lengths[x] as usize);
+            let (x, rev) = (self.x_map[x], self.reversed[x]);
+            let y = if rev { len - 1 - y } else { y };
+            l * x + y
+        };
+        if self.rotation == Rotation::R0 {
+            return Some(idx);
+        }
+        let (x, y) = self.rotate(idx / l, idx % l);
+        Some(x * self.input_size().1 + y)
+    }
+
+    /// Write a logical pixel to its location in the output frame.
+    pub fn try_write_pixel(
+        &self,
+        frame: &mut Vec<ARGB8>,
+        x: usize,
+        y: usize,
+        color: ARGB8,
+    ) -> Result<(), TransformError> {
+        let (w, h) = self.input_size();
+        if x >= w || y >= h {
+            return Err(TransformError::OutOfBounds { x, y });
+        }
+        let (x, y) = self.unrotate(x, y);
+        let l = self.strip_length as usize;
+        if let Some(map) = &self.coord_map {
+            let src = Some(x * l + y);
+            for (i, _) in map.iter().enumerate().filter(|(_, &m)| m == src) {
+                frame[i] = color;
+            }
+            return Ok(());
+        }
+        // the output strip which `source` maps back to logical strip x
+        let s = match self.x_map.iter().position(|&m| m == x) {
+            Some(s) => s,
+            None => return Err(TransformError::OutOfBounds { x, y }),
+        };
+        let l = self.strip_lengths[s] as usize;
+        if y >= l {
+            return Err(TransformError::OutOfBounds { x, y });
+        }
+        let idx = self.offsets[s] + if self.reversed[s] { l - 1 - y } else { y };
+        frame[idx] = color;
+        Ok(())
     }
 }
 
@@ -48,15 +386,445 @@ impl display::Transform<ARGB8> for Transform {
     }
 
     fn write_pixel(&self, frame: &mut Vec<ARGB8>, x: usize, y: usize, color: ARGB8) {
-        let l = self.strip_length as usize;
-        if x > (self.num_strips - 1) as usize || y > l - 1 {
+        if let Err(e) = self.try_write_pixel(frame, x, y, color) {
+            let (w, h) = self.input_size();
+            panic!("invalid {{x:{:},y:{:}}} for {{{:},{:}}}: {}", x, y, w, h, e);
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Axis {
+    /// Reflect the left half of the frame onto the right.
+    Horizontal,
+    /// Reflect the top half of the frame onto the bottom.
+    Vertical,
+}
+
+impl std::str::FromStr for Axis {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "horizontal" => Ok(Axis::Horizontal),
+            "vertical" => Ok(Axis::Vertical),
+            _ => Err(anyhow::anyhow!("unknown mirror axis: {}", s)),
+        }
+    }
+}
+
+/// Mirrors one half of a `width x height` logical frame onto the other.
+pub struct MirrorTransform {
+    width: usize,
+    height: usize,
+    axis: Axis,
+}
+
+impl MirrorTransform {
+    pub fn new(width: usize, height: usize, axis: Axis) -> Self {
+        Self {
+            width,
+            height,
+            axis,
+        }
+    }
+
+    fn reflect(&self, x: usize, y: usize) -> (usize, usize) {
+        match self.axis {
+            Axis::Horizontal => (self.width - 1 - x, y),
+            Axis::Vertical => (x, self.height - 1 - y),
+        }
+    }
+}
+
+impl display::Transform<ARGB8> for MirrorTransform {
+    fn transform(&self, frame: &Vec<ARGB8>) -> Vec<ARGB8> {
+        let h = self.height;
+        (0..self.width * h)
+            .map(|i| {
+                let (x, y) = (i / h, i % h);
+                let (mx, my) = self.reflect(x, y);
+                let (x, y) = (usize::min(x, mx), usize::min(y, my));
+                frame[x * h + y]
+            })
+            .collect()
+    }
+
+    fn write_pixel(&self, frame: &mut Vec<ARGB8>, x: usize, y: usize, color: ARGB8) {
+        if x >= self.width || y >= self.height {
             panic!(
                 "invalid {{x:{:},y:{:}}} for {{{:},{:}}}",
-                x, y, self.num_strips, self.strip_length
+                x, y, self.width, self.height
             );
         }
-        let (x, rev) = (self.x_map[x], self.reversed[x]);
-        let idx = l * x + if rev { l - y } else { y };
-        frame[idx] = color;
+        let (mx, my) = self.reflect(x, y);
+        frame[x * self.height + y] = color;
+        frame[mx * self.height + my] = color;
+    }
+}
+
+/// Per-channel gamma correction, which can be used for white balance.
+pub struct GammaTransform {
+    r: [u8; 256],
+    g: [u8; 256],
+    b: [u8; 256],
+}
+
+impl GammaTransform {
+    pub fn new(r: f64, g: f64, b: f64) -> Self {
+        Self {
+            r: gamma_table(r),
+            g: gamma_table(g),
+            b: gamma_table(b),
+        }
+    }
+
+    fn correct(&self, c: ARGB8) -> ARGB8 {
+        ARGB8::new(
+            c.a,
+            self.r[c.r as usize],
+            self.g[c.g as usize],
+            self.b[c.b as usize],
+        )
+    }
+}
+
+impl display::Transform<ARGB8> for GammaTransform {
+    fn transform(&self, frame: &Vec<ARGB8>) -> Vec<ARGB8> {
+        frame.iter().map(|&c| self.correct(c)).collect()
+    }
+
+    /// Color correction has no geometry of its own, so like `Identity` this
+    /// doesn't write anything.
+    fn write_pixel(&self, _: &mut Vec<ARGB8>, _: usize, _: usize, _: ARGB8) {}
+}
+
+/// Per-LED fixes for a particular strip, as stored in a correction file.
+/// Indexes are of physical LEDs in output order.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct CorrectionConfig {
+    /// LEDs which are always left black.
+    #[serde(default)]
+    pub dead: Vec<usize>,
+    /// RGB gains from 0.0 to 1.0 for LEDs with shifted colors.
+    #[serde(default)]
+    pub gains: Vec<(usize, (f64, f64, f64))>,
+}
+
+/// Corrects individual LEDs of the transformed output, blacking out dead ones
+/// and scaling the channels of color-shifted ones.
+#[derive(Default)]
+pub struct PixelCorrection {
+    fixes: Vec<Option<Fix>>,
+}
+
+#[derive(Copy, Clone)]
+enum Fix {
+    Dead,
+    Gain(f64, f64, f64),
+}
+
+impl PixelCorrection {
+    pub fn new(config: &CorrectionConfig) -> Self {
+        let len = config
+            .dead
+            .iter()
+            .chain(config.gains.iter().map(|(i, _)| i))
+            .map(|i| i + 1)
+            .max()
+            .unwrap_or(0);
+        let mut fixes = vec![None; len];
+        for &(i, (r, g, b)) in &config.gains {
+            fixes[i] = Some(Fix::Gain(r, g, b));
+        }
+        // dead takes precedence over any gain
+        for &i in &config.dead {
+            fixes[i] = Some(Fix::Dead);
+        }
+        Self { fixes }
+    }
+
+    /// Load the correction from a YAML or JSON file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let f = std::fs::File::open(path)?;
+        let config: CorrectionConfig = serde_yaml::from_reader(f)?;
+        Ok(Self::new(&config))
+    }
+
+    /// Corrected color of output LED `i`.
+    pub fn correct(&self, i: usize, c: ARGB8) -> ARGB8 {
+        match self.fixes.get(i).copied().flatten() {
+            Some(Fix::Dead) => ARGB8::new(0, 0, 0, 0),
+            Some(Fix::Gain(r, g, b)) => {
+                let s =
+                    |x: u8, gain: f64| (x as f64 * f64::min(f64::max(gain, 0.), 1.) + 0.5) as u8;
+                ARGB8::new(c.a, s(c.r, r), s(c.g, g), s(c.b, b))
+            }
+            None => c,
+        }
+    }
+
+    /// Correct a frame produced in output order.
+    pub fn apply_iter<'a, I>(&'a self, frame: I) -> impl Iterator<Item = ARGB8> + 'a
+    where
+        I: Iterator<Item = ARGB8> + 'a,
+    {
+        frame.enumerate().map(move |(i, c)| self.correct(i, c))
+    }
+}
+
+impl display::Transform<ARGB8> for PixelCorrection {
+    fn transform(&self, frame: &Vec<ARGB8>) -> Vec<ARGB8> {
+        self.apply_iter(frame.iter().copied()).collect()
+    }
+
+    /// Like `GammaTransform` this only changes colors, so doesn't write
+    /// anything.
+    fn write_pixel(&self, _: &mut Vec<ARGB8>, _: usize, _: usize, _: ARGB8) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(len: usize) -> Vec<ARGB8> {
+        (0..len).map(|i| ARGB8::new(31, i as u8, 0, 0)).collect()
+    }
+
+    fn reds(frame: &[ARGB8]) -> Vec<u8> {
+        frame.iter().map(|p| p.r).collect()
+    }
+
+    #[test]
+    fn serpentine_matches_hand_built_layout() {
+        let snake = Transform::serpentine(4, 8);
+        let by_hand = Transform::new(vec![8; 4], vec![false, true, false, true], vec![0, 1, 2, 3]);
+        let f = frame(32);
+        assert_eq!(snake.apply(&f), by_hand.apply(&f));
+        let out = reds(&snake.apply(&f));
+        assert_eq!(&out[..10], &[0, 1, 2, 3, 4, 5, 6, 7, 15, 14]);
+    }
+
+    #[test]
+    fn layout_round_trip() {
+        let t = Transform::new(vec![8, 4, 8], vec![false, true, false], vec![2, 0, 1]);
+        let yaml = serde_yaml::to_string(&t.layout().unwrap()).unwrap();
+        let layout: LayoutConfig = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(Some(layout.clone()), t.layout());
+        let read_back = Transform::from_layout(layout).unwrap();
+        let f = frame(24);
+        assert_eq!(read_back.apply(&f), t.apply(&f));
+    }
+
+    #[test]
+    fn coord_map_gathers_scattered_pixels() {
+        // a 3x2 frame, with LED 2 mapped outside of it
+        let t =
+            Transform::from_coord_map(3, 2, vec![(2, 1), (0, 0), (5, 0), (1, 1), (2, 1)]).unwrap();
+        let out = t.apply(&frame(6));
+        assert_eq!(reds(&out), vec![5, 0, 0, 3, 5]);
+        assert_eq!(out[2].a, 0);
+        assert_eq!(t.layout(), None);
+
+        let mut written = vec![ARGB8::new(0, 0, 0, 0); 5];
+        t.try_write_pixel(&mut written, 2, 1, ARGB8::new(31, 9, 0, 0))
+            .unwrap();
+        assert_eq!(reds(&written), vec![9, 0, 0, 0, 9]);
+    }
+
+    #[test]
+    fn mismatched_vectors_are_an_error() {
+        let err = Transform::try_new(vec![8; 3], vec![false; 2], vec![0, 1, 2]).err();
+        assert_eq!(
+            err,
+            Some(TransformError::NumStripsMismatch {
+                num_strips: 3,
+                reversed: 2,
+                x_map: 3,
+            })
+        );
+        let err = Transform::try_new(vec![8; 2], vec![false; 2], vec![0]).err();
+        assert_eq!(
+            err,
+            Some(TransformError::NumStripsMismatch {
+                num_strips: 2,
+                reversed: 2,
+                x_map: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn writes_outside_the_frame_are_an_error() {
+        let t = Transform::new(vec![8, 4], vec![false, true], vec![0, 1]);
+        let mut out = vec![ARGB8::new(0, 0, 0, 0); 12];
+        let c = ARGB8::new(31, 1, 1, 1);
+        assert_eq!(
+            t.try_write_pixel(&mut out, 2, 0, c),
+            Err(TransformError::OutOfBounds { x: 2, y: 0 })
+        );
+        assert_eq!(
+            t.try_write_pixel(&mut out, 0, 8, c),
+            Err(TransformError::OutOfBounds { x: 0, y: 8 })
+        );
+        // within the frame, but past the end of the shorter strip
+        assert_eq!(
+            t.try_write_pixel(&mut out, 1, 5, c),
+            Err(TransformError::OutOfBounds { x: 1, y: 5 })
+        );
+        assert_eq!(t.try_write_pixel(&mut out, 1, 3, c), Ok(()));
+        assert_eq!(out[8], c);
+    }
+
+    #[test]
+    fn x_map_must_be_a_permutation() {
+        let err = Transform::try_new(vec![8; 4], vec![false; 4], vec![0, 2, 2, 3]).err();
+        assert_eq!(
+            err,
+            Some(TransformError::InvalidXMap {
+                missing: vec![1],
+                duplicates: vec![2],
+            })
+        );
+        let err = Transform::try_new(vec![8; 2], vec![false; 2], vec![0, 7]).err();
+        assert_eq!(
+            err,
+            Some(TransformError::InvalidXMap {
+                missing: vec![1],
+                duplicates: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn rotates_a_2x3_frame() {
+        let cases = [
+            (Rotation::R0, (2, 3), [0, 1, 2, 3, 4, 5]),
+            (Rotation::R90, (3, 2), [1, 3, 5, 0, 2, 4]),
+            (Rotation::R180, (2, 3), [5, 4, 3, 2, 1, 0]),
+            (Rotation::R270, (3, 2), [4, 2, 0, 5, 3, 1]),
+        ];
+        for &(rotation, size, expected) in cases.iter() {
+            let t = Transform::new(vec![3; 2], vec![false; 2], vec![0, 1]).with_rotation(rotation);
+            assert_eq!(t.input_size(), size, "{:?}", rotation);
+            let f = frame(6);
+            assert_eq!(reds(&t.apply(&f)), expected.to_vec(), "{:?}", rotation);
+
+            // writing each pixel puts it where apply does
+            let (w, h) = size;
+            let mut written = vec![ARGB8::new(0, 0, 0, 0); 6];
+            for x in 0..w {
+                for y in 0..h {
+                    t.try_write_pixel(&mut written, x, y, f[x * h + y]).unwrap();
+                }
+            }
+            assert_eq!(written, t.apply(&f), "{:?}", rotation);
+        }
+        assert_eq!("270".parse::<Rotation>().unwrap(), Rotation::R270);
+        assert!("45".parse::<Rotation>().is_err());
+    }
+
+    #[test]
+    fn mirrors_horizontally() {
+        let m = MirrorTransform::new(4, 2, Axis::Horizontal);
+        let out = display::Transform::transform(&m, &frame(8));
+        assert_eq!(reds(&out), vec![0, 1, 2, 3, 2, 3, 0, 1]);
+    }
+
+    #[test]
+    fn mirrors_vertically() {
+        let m = MirrorTransform::new(2, 4, Axis::Vertical);
+        let out = display::Transform::transform(&m, &frame(8));
+        assert_eq!(reds(&out), vec![0, 1, 1, 0, 4, 5, 5, 4]);
+    }
+
+    #[test]
+    fn mirrored_write_hits_both_sides() {
+        let c = ARGB8::new(31, 9, 0, 0);
+        let m = MirrorTransform::new(4, 2, Axis::Horizontal);
+        let mut out = vec![ARGB8::new(0, 0, 0, 0); 8];
+        display::Transform::write_pixel(&m, &mut out, 0, 1, c);
+        assert_eq!(reds(&out), vec![0, 9, 0, 0, 0, 0, 0, 9]);
+
+        let m = MirrorTransform::new(2, 4, Axis::Vertical);
+        let mut out = vec![ARGB8::new(0, 0, 0, 0); 8];
+        display::Transform::write_pixel(&m, &mut out, 1, 0, c);
+        assert_eq!(reds(&out), vec![0, 0, 0, 0, 9, 0, 0, 9]);
+    }
+
+    #[test]
+    fn flattens_strips_of_different_lengths() {
+        // the logical frame is 3 strips of the longest length, 8
+        let t = Transform::new(vec![8, 4, 8], vec![false, true, false], vec![0, 1, 2]);
+        let out = reds(&t.apply(&frame(24)));
+        let mut expected: Vec<u8> = (0..8).collect();
+        expected.extend(&[11, 10, 9, 8]);
+        expected.extend(16..24);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn red_gamma_leaves_green_and_blue() {
+        let g = GammaTransform::new(2.2, 1.0, 1.0);
+        let f: Vec<ARGB8> = (0..=255).map(|c| ARGB8::new(31, c, c, c)).collect();
+        let out = display::Transform::transform(&g, &f);
+        for (p, c) in out.iter().zip(0..=255) {
+            assert_eq!((p.a, p.g, p.b), (31, c, c));
+        }
+        assert_eq!(out[128].r, gamma_table(2.2)[128]);
+        assert!(out[128].r < 128);
+    }
+
+    #[test]
+    fn write_pixel_on_reversed_strips_matches_apply() {
+        let transform = Transform::new(vec![4, 4, 4], vec![false, true, true], vec![2, 0, 1]);
+        let logical = frame(12);
+        let mut written = vec![ARGB8::new(0, 0, 0, 0); 12];
+        for x in 0..3 {
+            for y in 0..4 {
+                transform
+                    .try_write_pixel(&mut written, x, y, logical[x * 4 + y])
+                    .unwrap();
+            }
+        }
+        assert_eq!(reds(&written), reds(&transform.apply(&logical)));
+        assert_eq!(reds(&written)[4..8], [3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn correction_blacks_out_dead_pixels_and_scales_gains() {
+        let correction = PixelCorrection::new(&CorrectionConfig {
+            dead: vec![5],
+            gains: vec![(2, (0.5, 1., 0.)), (5, (1., 1., 1.))],
+        });
+        let white = vec![ARGB8::new(31, 200, 200, 200); 8];
+        let out = display::Transform::transform(&correction, &white);
+        assert_eq!(out.len(), 8);
+        assert_eq!(out[5], ARGB8::new(0, 0, 0, 0));
+        assert_eq!(out[2], ARGB8::new(31, 100, 200, 0));
+        for i in [0, 1, 3, 4, 6, 7].iter() {
+            assert_eq!(out[*i], white[*i]);
+        }
+    }
+
+    #[test]
+    fn layouts_past_the_strip_limits_are_errors() {
+        let t = Transform::try_new(vec![1; 255], vec![false; 255], (0..255).collect()).unwrap();
+        assert_eq!(t.input_size(), (255, 1));
+        assert_eq!(
+            Transform::try_new(vec![1; 256], vec![false; 256], (0..256).collect()).err(),
+            Some(TransformError::TooManyStrips { num_strips: 256 })
+        );
+
+        assert_eq!(
+            Transform::from_coord_map(256, 2, vec![(0, 0)]).err(),
+            Some(TransformError::TooManyStrips { num_strips: 256 })
+        );
+        assert_eq!(
+            Transform::from_coord_map(2, 65536, vec![(0, 0)]).err(),
+            Some(TransformError::StripTooLong { length: 65536 })
+        );
+        let t = Transform::from_coord_map(255, 65535, vec![(0, 0)]).unwrap();
+        assert_eq!(t.input_size(), (255, 65535));
     }
 }
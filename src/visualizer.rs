@@ -1,21 +1,47 @@
-use std::sync::mpsc::{channel, SyncSender, TrySendError};
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use anyhow::{anyhow, Result};
 use audio;
 use clap::Clap;
+use log::{debug, error, info, log_enabled, trace, warn, Level};
 use serde::{Deserialize, Serialize};
 
 use crate::apa102::ARGB8;
+use crate::beat::BeatDetector;
+use crate::blend;
+use crate::bucket::{BucketAnalyzer, BucketScale};
+use crate::device;
+use crate::display::{Display, Frame, FrameLimiter};
+use crate::envelope::{Envelope, Enveloped};
+use crate::fade::{self, Fader};
+use crate::features::{self, FeatureFrame, FeatureSink, Spectrum};
+use crate::frame;
+use crate::loudness::{Normalized, Normalizer};
+use crate::metrics::Metrics;
+use crate::osc;
+use crate::silence::{self, SilenceDetector};
+use crate::sparkle::Sparkle;
+use crate::synth::{self, Signal};
+use crate::wav::{self, WavRecorder, WavSource};
 
 #[derive(Clap)]
 pub struct Opts {
+    /// Input device, matched by any part of its name
     #[clap(long, short)]
     device: Option<String>,
 
     #[clap(long, short = 'r', default_value = "44100")]
     sample_rate: usize,
 
-    #[clap(long, short = 'b', default_value = "256")]
+    #[clap(long, short = 'b', default_value = "256", parse(try_from_str = synth::parse_block_size))]
     sample_block_size: usize,
 
     #[clap(long, short = 'f', default_value = "1024")]
@@ -26,26 +52,162 @@ pub struct Opts {
 
     #[clap(long, short = 'l', default_value = "144")]
     length: usize,
+
+    /// Lowest frequency of the buckets in Hz. Setting any of the bucket
+    /// options replaces the analyzer's own buckets, without its gain control
+    #[clap(long)]
+    freq_min: Option<f64>,
+
+    /// Highest frequency of the buckets in Hz
+    #[clap(long)]
+    freq_max: Option<f64>,
+
+    /// Spacing of the buckets: log, linear or mel
+    #[clap(long)]
+    bucket_scale: Option<BucketScale>,
+
+    /// Run the FFT this many times per window of samples, independent of the
+    /// input block size
+    #[clap(long)]
+    overlap: Option<usize>,
+
+    /// Number of audio channels, each driving an equal share of the strips
+    #[clap(long, default_value = "1")]
+    channels: usize,
+
+    /// Play audio from a WAV file instead of the input device
+    #[clap(long)]
+    wav: Option<String>,
+
+    /// Loop the WAV file instead of stopping at the end
+    #[clap(long = "loop")]
+    loop_wav: bool,
+
+    /// Generate a test signal instead of using an input device: sweep,
+    /// noise or beat
+    #[clap(long)]
+    synthetic: Option<Signal>,
+
+    /// Record the audio input to a WAV file
+    #[clap(long)]
+    record_audio: Option<String>,
+
+    /// Stream features as JSON lines to an address like tcp://host:port or udp://host:port
+    #[clap(long)]
+    features_out: Option<String>,
+
+    /// Only stream features, without rendering frames
+    #[clap(long)]
+    features_only: bool,
+
+    /// Fade to black after this many seconds of silence, until sound resumes
+    #[clap(long)]
+    idle_timeout: Option<f64>,
+
+    /// Mean band energy below which the input counts as silent
+    #[clap(long, default_value = "0.05")]
+    idle_threshold: f64,
+
+    /// Listen for OSC messages to tune params on this address, eg. 0.0.0.0:9000
+    #[clap(long)]
+    osc: Option<String>,
+
+    #[clap(flatten)]
+    pub overrides: ParamOverrides,
+}
+
+/// Flags overriding individual params loaded from the config.
+#[derive(Clap)]
+pub struct ParamOverrides {
+    /// Override the rate hue cycles with energy
+    #[clap(long)]
+    cycle: Option<f64>,
+
+    /// Override the maximum alpha, from 0.0 to 1.0
+    #[clap(long)]
+    max_alpha: Option<f64>,
+
+    /// Use a gradient palette from a CSV file of `position,r,g,b` stops
+    #[clap(long)]
+    palette: Option<String>,
+}
+
+impl ParamOverrides {
+    /// Replace the fields of `params` that were set on the command line.
+    pub fn apply(&self, params: &mut Params) -> Result<()> {
+        if let Some(cycle) = self.cycle {
+            params.cycle = cycle;
+        }
+        if let Some(max_alpha) = self.max_alpha {
+            params.max_alpha = max_alpha;
+        }
+        if let Some(path) = &self.palette {
+            params.palette = Palette::load_csv(path)?;
+        }
+        Ok(())
+    }
 }
 
 pub struct Visualizer {
     opts: Opts,
-    params: Params,
-    verbose: i32,
+    params: Arc<RwLock<Params>>,
+    // rebuilt whenever the palette or gamma params change
+    clut: RefCell<Clut>,
+    // table being built in another thread for changed params
+    pending_clut: RefCell<Option<Receiver<Clut>>>,
+    beat: RefCell<BeatDetector>,
+    // accumulated hue shift from beats, in radians
+    beat_phase: Cell<f64>,
+    // rotation of the ring layout from beats, as a fraction of a turn
+    ring_turn: Cell<f64>,
+    // past (value, energy) columns for the spectrogram, newest first
+    columns: RefCell<VecDeque<Vec<(f64, f64)>>>,
+    // per pixel values from the last frame, for smoothing
+    smoothed: RefCell<Vec<f64>>,
+    // held peak level of each strip, from 0.0 to 1.0
+    peaks: RefCell<Vec<f64>>,
+    sparkle: RefCell<Sparkle>,
+    // envelope of each channel's band amplitudes
+    envelopes: RefCell<Vec<Envelope>>,
+    // seconds between feature frames
+    frame_period: Cell<f64>,
+    // normalizer for the loudness params it was made with
+    normalizer: RefCell<Option<(LoudnessParams, Normalizer)>>,
+    metrics: Arc<Metrics>,
 }
 
+/// Time to fade out when going idle and back in when sound resumes.
+const IDLE_FADE: Duration = Duration::from_secs(2);
+
 lazy_static! {
     static ref SIGMOID: Sigmoid = Sigmoid::new();
-    static ref CLUT: Clut = Clut::new();
 }
 
 impl Visualizer {
-    pub fn new(opts: Opts, params: Params, verbose: i32) -> Self {
-        // let clut = Clut::new();
+    pub fn new(opts: Opts, params: Arc<RwLock<Params>>, metrics: Arc<Metrics>) -> Self {
+        let clut = {
+            let p = params.read().unwrap();
+            Clut::cached(&p.palette, p.gamma, p.color_space)
+        };
         Self {
-            opts,
             params,
-            verbose,
+            clut: RefCell::new(clut),
+            pending_clut: RefCell::new(None),
+            beat: RefCell::new(BeatDetector::defaults()),
+            beat_phase: Cell::new(0.),
+            ring_turn: Cell::new(0.),
+            columns: RefCell::new(VecDeque::new()),
+            smoothed: RefCell::new(Vec::new()),
+            peaks: RefCell::new(Vec::new()),
+            sparkle: RefCell::new(Sparkle::defaults()),
+            envelopes: RefCell::new(Vec::new()),
+            normalizer: RefCell::new(None),
+            frame_period: Cell::new(
+                hop_size(opts.fft_size, opts.overlap, opts.sample_block_size) as f64
+                    / opts.sample_rate as f64,
+            ),
+            opts,
+            metrics,
         }
     }
 
@@ -53,29 +215,87 @@ impl Visualizer {
         &self,
         output_size: (usize, usize),
         audio_params: audio::frequency_sensor::FrequencySensorParams,
-        frame_tx: SyncSender<Vec<ARGB8>>,
+        audio_updates: Receiver<audio::frequency_sensor::FrequencySensorParams>,
+        frame_tx: SyncSender<Frame<ARGB8>>,
     ) {
         let block_size = self.opts.sample_block_size;
         let fft_size = self.opts.fft_size;
         let bins = self.opts.bins;
+        let hop = hop_size(fft_size, self.opts.overlap, block_size);
         let length = self.opts.length;
-        let verbose = self.verbose;
+        let buckets = self.buckets();
 
-        let (audio_data_tx, audio_data_rx) = channel();
-        let (features_tx, features_rx) = channel();
+        let wav = self
+            .opts
+            .wav
+            .as_ref()
+            .map(|path| WavSource::from_file(path).expect("failed to read wav"));
+        let channels = match &wav {
+            Some(wav) => wav.channels() as usize,
+            None => usize::max(self.opts.channels, 1),
+        };
+        let synthetic = self.opts.synthetic.filter(|_| wav.is_none());
+        let device = match &wav {
+            Some(_) => None,
+            None if synthetic.is_some() => None,
+            None => self
+                .opts
+                .device
+                .as_deref()
+                .map(|d| device::resolve(d).expect("failed to find device")),
+        };
+        let requested_rate = self.opts.sample_rate as u32;
+        let sample_rate = match &wav {
+            Some(wav) => wav.sample_rate(),
+            None if synthetic.is_some() => requested_rate,
+            None => device::negotiate_rate(device.as_deref(), channels as u16, requested_rate)
+                .expect("failed to find a supported sample rate"),
+        };
+        if sample_rate != requested_rate {
+            info!(
+                "using sample rate {} instead of {}",
+                sample_rate, requested_rate
+            );
+        }
+        self.frame_period.set(hop as f64 / sample_rate as f64);
 
-        let now = std::time::SystemTime::now();
+        if let Some(addr) = &self.opts.osc {
+            if let Err(e) = osc::listen(addr, self.params.clone()) {
+                error!("failed to start osc server: {}", e);
+            }
+        }
+
+        let (audio_data_tx, audio_data_rx) = channel::<Vec<Vec<f64>>>();
+        let (features_tx, features_rx) = channel();
 
         thread::spawn(move || {
-            let boost_params = audio::gain_control::Params::defaults();
-            let mut analyzer = audio::Analyzer::new(
-                fft_size,
-                block_size,
-                bins,
-                length,
-                boost_params,
-                audio_params,
-            );
+            let new_analyzer = |audio_params| match buckets {
+                Some((range, scale)) => Analysis::Buckets(BucketAnalyzer::new(
+                    fft_size,
+                    bins,
+                    length,
+                    range,
+                    scale,
+                    sample_rate as f64,
+                    audio_params,
+                )),
+                None => {
+                    let boost_params = audio::gain_control::Params::defaults();
+                    Analysis::Analyzer(audio::Analyzer::new(
+                        fft_size,
+                        hop,
+                        bins,
+                        length,
+                        boost_params,
+                        audio_params,
+                    ))
+                }
+            };
+            let new_analyzers =
+                |audio_params| (0..channels).map(|_| new_analyzer(audio_params)).collect();
+            let mut analyzers: Vec<Analysis> = new_analyzers(audio_params);
+            let mut rechunkers: Vec<Rechunker> =
+                (0..channels).map(|_| Rechunker::new(hop)).collect();
 
             // let mut sfft = audio::sfft::SlidingFFT::new(fft_size);
             // let mut bucketer =
@@ -85,8 +305,14 @@ impl Visualizer {
             // let mut sample_count = 0;
             // let mut fps = 0;
 
-            let mut process = |mut data| {
-                if let Some(features) = analyzer.process(&mut data) {
+            let process = |analyzers: &mut Vec<Analysis>, data: Vec<Vec<f64>>| {
+                // channels are processed in lockstep, so all produce features together
+                let features: Vec<_> = analyzers
+                    .iter_mut()
+                    .zip(data)
+                    .filter_map(|(analyzer, mut data)| analyzer.process(&mut data))
+                    .collect();
+                if features.len() == analyzers.len() {
                     // sfft.push_input(&data);
                     // sample_count += data.len();
                     // if sample_count >= block_size {
@@ -97,23 +323,16 @@ impl Visualizer {
                     //     let features = fs.get_features();
 
                     // fps += 1;
-                    if verbose >= 2 && features.get_frame_count() % 32 == 0 {
+                    if log_enabled!(Level::Debug) && features[0].get_frame_count() % 32 == 0 {
                         let mut out = String::new();
-                        analyzer
+                        analyzers[0]
                             .write_debug(&mut out)
                             .expect("failed to write debug");
-                        println!("{}", out);
+                        debug!("{}", out);
                     }
 
-                    // FIXME: this clone is needlessly expensive on failure to send
-                    if let Err(e) = features_tx.send(features.clone()) {
-                        if verbose >= 3 {
-                            println!(
-                                "[{:08}]: failed to send features: {}",
-                                now.elapsed().unwrap().as_millis(),
-                                e
-                            );
-                        }
+                    if let Err(e) = features_tx.send(features) {
+                        trace!("failed to send features: {}", e);
                     }
                 }
             };
@@ -124,109 +343,478 @@ impl Visualizer {
                 //     Err(TryRecvError::Empty) => audio_data_rx.recv().map_err(|e| anyhow!(e)),
                 //     Err(e) => Err(anyhow!(e)),
                 // } {
+                if let Ok(audio_params) = audio_updates.try_recv() {
+                    analyzers = new_analyzers(audio_params);
+                }
                 match audio_data_rx.recv() {
                     Ok(data) => {
-                        process(data);
+                        let mut hops: Vec<_> = rechunkers
+                            .iter_mut()
+                            .zip(&data)
+                            .map(|(r, data)| r.push(data).into_iter())
+                            .collect();
+                        // channels get the same number of samples, so run out together
+                        while let Some(hop) = hops.iter_mut().map(|h| h.next()).collect() {
+                            process(&mut analyzers, hop);
+                        }
                     }
                     Err(e) => {
-                        println!("failed to recv audio: {}", e);
+                        error!("failed to recv audio: {}", e);
                         break;
                     }
                 };
-                if verbose >= 4 {
-                    println!("rx audio");
-                };
+                trace!("rx audio");
             }
         });
 
         let handle_stream = move |data: &[f32]| {
-            if verbose >= 4 {
-                println!("tx audio");
-            }
-            let data = data.iter().map(|&x| x as f64).collect();
+            trace!("tx audio");
+            let data = deinterleave(data, channels);
             if let Err(e) = audio_data_tx.send(data) {
-                if verbose >= 3 {
-                    println!(
-                        "[{:08}]: failed to send audio data: {}",
-                        now.elapsed().unwrap().as_millis(),
-                        e
-                    );
-                }
+                trace!("failed to send audio data: {}", e);
             }
         };
         // random rust thing:
         // https://stackoverflow.com/questions/25649423/sending-trait-objects-between-threads-in-rust
         let handle_stream = Box::new(handle_stream) as Box<dyn Fn(&[f32]) -> () + Send>;
+        let handle_stream = match &self.opts.record_audio {
+            Some(path) => {
+                let recorder = WavRecorder::create(path, channels as u16, sample_rate)
+                    .expect("failed to create audio recording");
+                wav::tee(recorder, handle_stream)
+            }
+            None => handle_stream,
+        };
 
-        let s = audio::Source::new(self.opts.device.as_deref()).expect("failed to get device");
-        let _stream = s
-            .get_stream(
-                1,
-                self.opts.sample_rate as u32,
-                block_size as u32,
-                handle_stream,
-            )
-            .expect("failed to get stream");
+        // only one of these is used, each stops its input when dropped
+        let _playback;
+        let _stream;
+        if let Some(wav) = wav {
+            _playback = wav.play(block_size, self.opts.loop_wav, handle_stream);
+        } else if let Some(signal) = synthetic {
+            _playback = synth::play(signal, sample_rate, channels, block_size, handle_stream);
+        } else {
+            let s = audio::Source::new(device.as_deref()).expect("failed to get device");
+            _stream = s
+                .get_stream(
+                    channels as u16,
+                    sample_rate,
+                    block_size as u32,
+                    handle_stream,
+                )
+                .expect("failed to get stream");
+        }
+
+        let mut feature_sink = self
+            .opts
+            .features_out
+            .as_ref()
+            .map(|url| FeatureSink::connect(url).expect("failed to connect features output"));
+
+        let mut silence = self.opts.idle_timeout.map(|secs| {
+            SilenceDetector::new(self.opts.idle_threshold, Duration::from_secs_f64(secs))
+        });
+        let mut idle_fader = Fader::fade_in(IDLE_FADE, Instant::now());
+        let mut seq = 0;
 
         while let Ok(features) = features_rx.recv() {
-            if self.verbose >= 4 {
-                println!("features update");
-            }
-            let frame = self.visualize(output_size, &features);
-            if let Err(e) = frame_tx.try_send(frame) {
-                match e {
-                    TrySendError::Full(_) => {
-                        if verbose >= 3 {
-                            println!("[{:08}]: dropped frame", now.elapsed().unwrap().as_millis());
-                        }
-                    }
-                    e => {
-                        println!("failed to send frame: {}", e);
+            trace!("features update");
+            if let Some(sink) = &mut feature_sink {
+                for (c, f) in features.iter().enumerate() {
+                    if let Err(e) = sink.send(&FeatureFrame::new(f, c, length)) {
+                        error!("failed to send features: {}", e);
                         break;
                     }
-                };
+                }
+            }
+            if self.opts.features_only {
+                continue;
+            }
+            let mut frame = self.visualize(output_size, &features);
+            if let Some(silence) = &mut silence {
+                let now = Instant::now();
+                let was_idle = silence.is_idle();
+                let idle = silence.update(silence::mean_energy(features[0].get_energy()), now);
+                if idle && !was_idle {
+                    info!("silence, going idle");
+                    idle_fader.fade_out(now);
+                } else if !idle && was_idle {
+                    info!("sound, resuming");
+                    idle_fader.fade_in_from(now);
+                }
+                frame = fade::scale(&frame, idle_fader.level(now));
+            }
+            self.metrics.rendered();
+            // frames dropped here are counted here, so only those sent are
+            // numbered and the output counts the ones lost after that
+            let frame = frame::numbered(seq, frame, log_enabled!(Level::Trace));
+            match frame_tx.try_send(frame) {
+                Ok(()) => seq += 1,
+                Err(TrySendError::Full(_)) => {
+                    self.metrics.dropped();
+                    trace!("dropped frame");
+                }
+                Err(e) => {
+                    error!("failed to send frame: {}", e);
+                    break;
+                }
             }
         }
-        println!("oops, dead");
+        error!("oops, dead");
     }
 
-    fn visualize(
+    /// Frequency range and spacing of the buckets, if they're set rather than
+    /// left to the analyzer.
+    fn buckets(&self) -> Option<((f64, f64), BucketScale)> {
+        let Opts {
+            freq_min,
+            freq_max,
+            bucket_scale,
+            ..
+        } = self.opts;
+        if freq_min.is_none() && freq_max.is_none() && bucket_scale.is_none() {
+            return None;
+        }
+        let range = (freq_min.unwrap_or(32.), freq_max.unwrap_or(16000.));
+        Some((range, bucket_scale.unwrap_or(BucketScale::Log)))
+    }
+
+    /// Render features recorded with `--features-out` from `path` at `fps`,
+    /// without any audio input.
+    pub fn replay<P: AsRef<Path>>(
         &self,
         output_size: (usize, usize),
-        features: &audio::frequency_sensor::Features,
-    ) -> Vec<ARGB8> {
+        path: P,
+        fps: f64,
+        display: &Display<ARGB8>,
+    ) -> Result<()> {
+        let frames = features::load(path)?;
+        info!("replaying {} frames", frames.len());
+        let mut limiter = FrameLimiter::new(fps);
+        self.frame_period.set(1. / fps);
+        for features in frames.iter() {
+            limiter.wait();
+            display.write_owned(self.visualize(output_size, features))?;
+            self.metrics.rendered();
+        }
+        Ok(())
+    }
+
+    /// Render a frame with any sparkles overlaid.
+    fn visualize<F: Spectrum>(&self, output_size: (usize, usize), features: &[F]) -> Vec<ARGB8> {
+        let envelope = self.params.read().unwrap().envelope.clone();
+        let frame = match envelope {
+            Some(envelope) => {
+                let mut envelopes = self.envelopes.borrow_mut();
+                envelopes.resize_with(features.len(), || Envelope::new(self.opts.length));
+                let dt = self.frame_period.get();
+                let enveloped: Vec<_> = features
+                    .iter()
+                    .zip(envelopes.iter_mut())
+                    .map(|(f, e)| {
+                        let time_constants = envelope.time_constants(f.scales().len());
+                        e.update(f.amplitudes(0), &time_constants, dt);
+                        Enveloped::new(f, e)
+                    })
+                    .collect();
+                self.normalize(output_size, &enveloped)
+            }
+            None => self.normalize(output_size, features),
+        };
+        match self.params.read().unwrap().sparkle {
+            Some(SparkleParams { decay, .. }) => {
+                let layer = self.sparkle.borrow_mut().step(frame.len(), decay);
+                blend::composite(&[&frame, &layer])
+            }
+            None => frame,
+        }
+    }
+
+    /// Render a frame, with the features scaled to bring the room's average
+    /// loudness to the target level if that's enabled.
+    fn normalize<F: Spectrum>(&self, output_size: (usize, usize), features: &[F]) -> Vec<ARGB8> {
+        let loudness = match self.params.read().unwrap().loudness.clone() {
+            Some(loudness) => loudness,
+            None => return self.render(output_size, features),
+        };
+        let mut normalizer = self.normalizer.borrow_mut();
+        if normalizer.as_ref().map_or(true, |(p, _)| *p != loudness) {
+            let n = Normalizer::new(loudness.target, loudness.adapt, loudness.floor);
+            *normalizer = Some((loudness, n));
+        }
+        let (_, normalizer) = normalizer.as_mut().unwrap();
+        let level = features
+            .iter()
+            .map(|f| silence::mean_energy(f.energy()))
+            .sum::<f64>()
+            / usize::max(features.len(), 1) as f64;
+        let factor = normalizer.update(level, self.frame_period.get());
+        self.metrics.set_loudness_factor(factor);
+        let normalized: Vec<_> = features
+            .iter()
+            .map(|f| Normalized::new(f, factor))
+            .collect();
+        self.render(output_size, &normalized)
+    }
+
+    /// Render a frame, with the strips split evenly between the features of
+    /// each channel.
+    fn render<F: Spectrum>(&self, output_size: (usize, usize), features: &[F]) -> Vec<ARGB8> {
         let (length, width) = output_size;
         let mut frame = vec![ARGB8::new(0, 0, 0, 0); length * width];
+        let params = self.params.read().unwrap().clone();
+        let mut clut = self.clut.borrow_mut();
+        self.update_clut(&mut clut, &params);
+
+        let beat = self.beat.borrow_mut().process(features[0].energy());
+        if beat.beat {
+            let phase = self.beat_phase.get() + params.beat_shift;
+            self.beat_phase.set(phase % (2. * std::f64::consts::PI));
+            self.ring_turn
+                .set((self.ring_turn.get() + Layout::RING_STEP) % 1.);
+            debug!("beat: {:.2}", beat.strength);
+            if let Some(sparkle) = &params.sparkle {
+                self.sparkle
+                    .borrow_mut()
+                    .trigger(sparkle.count, frame.len());
+            }
+        }
+        let mut smoothed = self.smoothed.borrow_mut();
+        if smoothed.len() != frame.len() {
+            *smoothed = vec![0.; frame.len()];
+        }
+
+        if params.mode == Mode::Matrix {
+            self.visualize_matrix(
+                &mut frame,
+                &mut smoothed,
+                output_size,
+                &features[0],
+                &params,
+                &clut,
+            );
+            return frame;
+        }
+        if params.mode == Mode::Spectrogram {
+            self.visualize_spectrogram(
+                &mut frame,
+                &mut smoothed,
+                output_size,
+                &features[0],
+                &params,
+                &clut,
+            );
+            return frame;
+        }
+        if !params.segments.is_empty() {
+            self.visualize_segments(
+                &mut frame,
+                &mut smoothed,
+                output_size,
+                features,
+                &params,
+                &clut,
+            );
+            return frame;
+        }
 
-        let scales = features.get_scales();
-        let energy = features.get_energy();
-        // let diff = features.get_diff();
         let ws = 2.0 * std::f64::consts::PI / (length as f64);
 
-        for i in 0..length {
-            let phi = ws * i as f64;
-            let amp = features.get_amplitudes(i);
-            for j in 0..width {
-                let val = scales[j] * (amp[j] - 1.0);
-                frame[j * length + i] = self.get_hsv(&self.params, val, energy[j], phi)
+        let mut levels = vec![0.; width];
+        let per_channel = (width + features.len() - 1) / features.len();
+        for (c, features) in features.iter().enumerate() {
+            let scales = features.scales();
+            let energy = features.energy();
+            // let diff = features.get_diff();
+            let strips = c * per_channel..usize::min((c + 1) * per_channel, width);
+            let bins = scales.len();
+            for i in 0..length {
+                let pos = params.layout.position(i, length, self.ring_turn.get());
+                let phi = ws * pos as f64 + self.beat_phase.get();
+                let amp = features.amplitudes(resample(pos, length, self.opts.length));
+                for (k, j) in strips.clone().enumerate() {
+                    let k = resample(k, strips.len(), bins);
+                    let val = scales[k] * (amp[k] - 1.0);
+                    let val = smooth(&mut smoothed[j * length + i], val, params.smoothing);
+                    let hue = params.hue(k, bins, energy[k], phi);
+                    frame[j * length + i] = self.get_hsv(&params, &clut, val, hue);
+                    if i == 0 {
+                        levels[j] = SIGMOID.f(params.value_scale.0 * val + params.value_scale.1);
+                    }
+                }
+            }
+        }
+
+        if let Some(peak) = &params.peak_hold {
+            let mut peaks = self.peaks.borrow_mut();
+            hold_peaks(&mut peaks, &levels, peak.decay);
+            let (r, g, b) = peak.color;
+            let color = ARGB8::new(alpha_byte(params.max_alpha), r, g, b);
+            for (j, p) in peaks.iter().enumerate() {
+                let i = usize::min((p * length as f64) as usize, length - 1);
+                frame[j * length + i] = color;
             }
         }
 
         frame
     }
 
-    fn get_hsv(&self, params: &Params, val: f64, e: f64, phi: f64) -> ARGB8 {
+    /// Swap in the table for changed palette, gamma or color space params once
+    /// it's ready. It's built in another thread, since that takes a while, so
+    /// rendering carries on with the old table in the meantime.
+    fn update_clut(&self, clut: &mut Clut, params: &Params) {
+        let mut pending = self.pending_clut.borrow_mut();
+        if let Some(rx) = pending.as_ref() {
+            match rx.try_recv() {
+                Ok(built) => *clut = built,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => error!("failed to build color table"),
+            }
+            *pending = None;
+        }
+        if clut.is_for(&params.palette, params.gamma, params.color_space) {
+            return;
+        }
+        let (tx, rx) = channel();
+        let (palette, gamma, color_space) =
+            (params.palette.clone(), params.gamma, params.color_space);
+        thread::spawn(move || {
+            let _ = tx.send(Clut::cached(&palette, gamma, color_space));
+        });
+        *pending = Some(rx);
+    }
+
+    /// Render each band into the pixels of its segments along every strip,
+    /// leaving pixels outside any segment dark.
+    fn visualize_segments<F: Spectrum>(
+        &self,
+        frame: &mut Vec<ARGB8>,
+        smoothed: &mut [f64],
+        output_size: (usize, usize),
+        features: &[F],
+        params: &Params,
+        clut: &Clut,
+    ) {
+        let (length, width) = output_size;
+        let ws = 2.0 * std::f64::consts::PI / (length as f64);
+
+        let per_channel = (width + features.len() - 1) / features.len();
+        for (c, features) in features.iter().enumerate() {
+            let scales = features.scales();
+            let energy = features.energy();
+            let bins = scales.len();
+            let strips = c * per_channel..usize::min((c + 1) * per_channel, width);
+            for seg in params.segments.iter().filter(|s| s.bin < bins) {
+                let k = seg.bin;
+                let (start, end) = (usize::min(seg.start, length), usize::min(seg.end, length));
+                for i in start..end {
+                    let phi = ws * i as f64 + self.beat_phase.get();
+                    let amp =
+                        features.amplitudes(resample(i - start, end - start, self.opts.length));
+                    let hue = params.hue(k, bins, energy[k], phi);
+                    for j in strips.clone() {
+                        let val = scales[k] * (amp[k] - 1.0);
+                        let val = smooth(&mut smoothed[j * length + i], val, params.smoothing);
+                        frame[j * length + i] = self.get_hsv(params, clut, val, hue);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Render a spectrogram over the whole grid, with frequency along each
+    /// strip and the amplitude history across the strips.
+    fn visualize_matrix<F: Spectrum>(
+        &self,
+        frame: &mut Vec<ARGB8>,
+        smoothed: &mut [f64],
+        output_size: (usize, usize),
+        features: &F,
+        params: &Params,
+        clut: &Clut,
+    ) {
+        let (length, width) = output_size;
+        let scales = features.scales();
+        let energy = features.energy();
+        let bins = scales.len();
+        let ws = 2.0 * std::f64::consts::PI / (length as f64);
+
+        for x in 0..width {
+            let amp = features.amplitudes(resample(x, width, self.opts.length));
+            for y in 0..length {
+                let k = resample(y, length, bins);
+                let phi = ws * y as f64 + self.beat_phase.get();
+                let val = scales[k] * (amp[k] - 1.0);
+                let val = smooth(&mut smoothed[x * length + y], val, params.smoothing);
+                let hue = params.hue(k, bins, energy[k], phi);
+                let color = self.get_hsv(params, clut, val, hue);
+                write_pixel(frame, length, x, y, color);
+            }
+        }
+    }
+
+    /// Render a waterfall where each frame adds a column of the current
+    /// amplitudes and older columns scroll across the strips.
+    fn visualize_spectrogram<F: Spectrum>(
+        &self,
+        frame: &mut Vec<ARGB8>,
+        smoothed: &mut [f64],
+        output_size: (usize, usize),
+        features: &F,
+        params: &Params,
+        clut: &Clut,
+    ) {
+        let (length, width) = output_size;
+        let scales = features.scales();
+        let energy = features.energy();
+        let amp = features.amplitudes(0);
+        let column = (0..scales.len())
+            .map(|k| (scales[k] * (amp[k] - 1.0), energy[k]))
+            .collect();
+
+        let mut columns = self.columns.borrow_mut();
+        push_column(&mut columns, column, width);
+
+        let ws = 2.0 * std::f64::consts::PI / (length as f64);
+        for (x, column) in columns.iter().enumerate() {
+            for y in 0..length {
+                let k = resample(y, length, column.len());
+                let (val, e) = column[k];
+                let val = smooth(&mut smoothed[x * length + y], val, params.smoothing);
+                let phi = ws * y as f64 + self.beat_phase.get();
+                let hue = params.hue(k, column.len(), e, phi);
+                let color = self.get_hsv(params, clut, val, hue);
+                write_pixel(frame, length, x, y, color);
+            }
+        }
+    }
+
+    /// Color of a pixel from its hue and value.
+    fn get_hsv(&self, params: &Params, clut: &Clut, val: f64, hue: f64) -> ARGB8 {
         let vs = params.value_scale;
         let ls = params.lightness_scale;
         let als = params.alpha_scale;
 
-        let hue = 180. * (params.cycle * e + phi) / std::f64::consts::PI;
-        let value = ls.0 * SIGMOID.f(vs.0 * val + vs.1) + ls.1;
-        let alpha = params.max_alpha * SIGMOID.f(als.0 * val + als.1);
+        // inverted, loud parts dip dark on an otherwise bright strip
+        let sigmoid = |x| match params.invert {
+            false => SIGMOID.f(x),
+            true => 1. - SIGMOID.f(x),
+        };
+        let value = ls.0 * sigmoid(vs.0 * val + vs.1) + ls.1;
+        let alpha = params.max_alpha * sigmoid(als.0 * val + als.1);
 
-        let color = CLUT.lookup(hue, value);
+        let color = clut.lookup(hue, value);
+        let wb = params.white_balance;
+        let color = (wb.0 * color.0, wb.1 * color.1, wb.2 * color.2);
+        let (alpha, color) = match params.brightness {
+            Brightness::Hybrid => (alpha, color),
+            Brightness::Rgb => {
+                let a = f64::min(f64::max(alpha, 0.), 1.);
+                (1., (a * color.0, a * color.1, a * color.2))
+            }
+        };
         ARGB8::new(
-            (31.5 * alpha) as u8,
+            alpha_byte(alpha),
             (255.5 * color.0) as u8,
             (255.5 * color.1) as u8,
             (255.5 * color.2) as u8,
@@ -234,13 +822,272 @@ impl Visualizer {
     }
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Params {
     value_scale: (f64, f64),
     lightness_scale: (f64, f64),
     alpha_scale: (f64, f64),
     max_alpha: f64,
     cycle: f64,
+    #[serde(default)]
+    palette: Palette,
+    #[serde(default = "Params::default_gamma")]
+    gamma: f64,
+    #[serde(default)]
+    beat_shift: f64,
+    #[serde(default)]
+    mode: Mode,
+    /// Weight of the previous frame in each pixel's moving average, 0 to
+    /// disable smoothing.
+    #[serde(default)]
+    smoothing: f64,
+    #[serde(default)]
+    color_space: ColorSpace,
+    /// Gain of each of the red, green and blue channels after gamma.
+    #[serde(default = "Params::default_white_balance")]
+    white_balance: (f64, f64, f64),
+    /// Lower brightness with amplitude instead of raising it.
+    #[serde(default)]
+    invert: bool,
+    /// Fixed hue in degrees for each band, spread evenly over the bins,
+    /// instead of cycling with energy and phase.
+    #[serde(default)]
+    band_hues: Vec<f64>,
+    #[serde(default)]
+    brightness: Brightness,
+    #[serde(default)]
+    layout: Layout,
+    /// Pixel ranges along each strip given over to particular bins, instead
+    /// of giving each strip its own bin.
+    #[serde(default)]
+    segments: Vec<Segment>,
+    /// Overlay each strip's peak level in ring mode.
+    #[serde(default)]
+    peak_hold: Option<PeakHold>,
+    /// Flash random pixels white on each beat.
+    #[serde(default)]
+    sparkle: Option<SparkleParams>,
+    /// Attack and decay of the band amplitudes, applied before rendering.
+    #[serde(default)]
+    envelope: Option<EnvelopeParams>,
+    /// Scale the features to adapt to the loudness of the room.
+    #[serde(default)]
+    loudness: Option<LoudnessParams>,
+}
+
+/// Where the alpha brightness of a pixel is encoded.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Brightness {
+    /// In the global brightness field, on top of the color.
+    Hybrid,
+    /// Scaled into the color, with the global brightness at full, for strips
+    /// which flicker when both are modulated.
+    Rgb,
+}
+
+impl Default for Brightness {
+    fn default() -> Self {
+        Brightness::Hybrid
+    }
+}
+
+/// How pixels along a strip map to positions in the rendered pattern.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Layout {
+    /// Position follows the pixel index.
+    Linear,
+    /// The strip is a closed ring, and the pattern rotates around it by
+    /// `RING_STEP` with each beat.
+    Ring,
+    /// The pattern spreads out from the center to both ends.
+    MirroredCenter,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Linear
+    }
+}
+
+impl Layout {
+    /// Fraction of a turn the ring rotates by on each beat.
+    const RING_STEP: f64 = 1. / 16.;
+
+    /// Position in the pattern of pixel `i` of a strip of `length`, given the
+    /// rotation of the ring as a fraction of a turn.
+    fn position(self, i: usize, length: usize, turn: f64) -> usize {
+        match self {
+            Layout::Linear => i,
+            Layout::Ring => {
+                let turn = turn.rem_euclid(1.);
+                let offset = (turn * length as f64) as usize;
+                (i + offset) % usize::max(length, 1)
+            }
+            Layout::MirroredCenter => {
+                // pixels i and length - 1 - i are the same distance from the center
+                let d = (2 * i as isize - length as isize + 1).abs() as usize / 2;
+                resample(d, (length + 1) / 2, length)
+            }
+        }
+    }
+}
+
+/// Pixels `start..end` along a strip which show bin `bin`.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct Segment {
+    bin: usize,
+    start: usize,
+    end: usize,
+}
+
+/// Marker drawn along each strip at its recent peak level.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PeakHold {
+    /// Level the peak falls by each frame, out of 1.0.
+    decay: f64,
+    color: (u8, u8, u8),
+}
+
+/// Sparkles lit on each beat.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct SparkleParams {
+    count: usize,
+    /// Level each sparkle fades by each frame, out of 1.0.
+    decay: f64,
+}
+
+/// Time constants in seconds of the band amplitudes rising and falling, for
+/// each band spread evenly over the bins.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EnvelopeParams {
+    attack: Vec<f64>,
+    decay: Vec<f64>,
+}
+
+impl EnvelopeParams {
+    /// (attack, decay) of each of `bins` bins.
+    fn time_constants(&self, bins: usize) -> Vec<(f64, f64)> {
+        let pick = |taus: &[f64], k| taus.get(resample(k, bins, taus.len())).copied();
+        (0..bins)
+            .map(|k| {
+                (
+                    pick(&self.attack, k).unwrap_or(0.),
+                    pick(&self.decay, k).unwrap_or(0.),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Normalization of the features to the long-term average band energy.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LoudnessParams {
+    /// Mean band energy the average is brought to.
+    target: f64,
+    /// Seconds for the average to follow a change in loudness by 1 - 1/e.
+    adapt: f64,
+    /// Lowest average that is brought up to the target, so silence isn't.
+    floor: f64,
+}
+
+/// Layout the visualizer renders for.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Mode {
+    /// Each strip is a ring, with phase around its length.
+    Ring,
+    /// The strips form a 2D matrix.
+    Matrix,
+    /// The strips form a 2D matrix showing a scrolling spectrogram.
+    Spectrogram,
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::Ring
+    }
+}
+
+/// Color space the table of hue and lightness is built in.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum ColorSpace {
+    /// Perceptually uniform, so equal steps look equally bright.
+    Hsluv,
+    /// Plain HSV, brighter and more saturated.
+    Hsv,
+}
+
+impl Default for ColorSpace {
+    fn default() -> Self {
+        ColorSpace::Hsluv
+    }
+}
+
+impl ColorSpace {
+    /// RGB from a hue in degrees, and saturation and lightness from 0.0 to 1.0.
+    fn to_rgb(self, h: f64, s: f64, l: f64) -> (f64, f64, f64) {
+        match self {
+            ColorSpace::Hsluv => hsluv::hsluv_to_rgb((h, 100. * s, 100. * l)),
+            ColorSpace::Hsv => hsv_to_rgb(h, s, l),
+        }
+    }
+}
+
+fn hsv_to_rgb(h: f64, s: f64, v: f64) -> (f64, f64, f64) {
+    let h = h.rem_euclid(360.) / 60.;
+    let c = v * s;
+    let x = c * (1. - (h % 2. - 1.).abs());
+    let (r, g, b) = match h as usize {
+        0 => (c, x, 0.),
+        1 => (x, c, 0.),
+        2 => (0., c, x),
+        3 => (0., x, c),
+        4 => (x, 0., c),
+        _ => (c, 0., x),
+    };
+    let m = v - c;
+    (r + m, g + m, b + m)
+}
+
+/// Colors the hue cycle is mapped onto.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Palette {
+    Rainbow,
+    Warm,
+    Cool,
+    Grayscale,
+    /// RGB stops positioned from 0.0 to 1.0 along the hue cycle.
+    Gradient(Vec<(f64, (u8, u8, u8))>),
+}
+
+impl Palette {
+    /// Load a gradient from lines of `position,r,g,b`, skipping blank lines
+    /// and `#` comments.
+    pub fn load_csv<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let mut stops = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let stop = match fields.as_slice() {
+                [p, r, g, b] => (p.parse()?, (r.parse()?, g.parse()?, b.parse()?)),
+                _ => return Err(anyhow!("line {}: expected position,r,g,b", n + 1)),
+            };
+            stops.push(stop);
+        }
+        if stops.is_empty() {
+            return Err(anyhow!("palette has no stops"));
+        }
+        Ok(Palette::Gradient(stops))
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::Rainbow
+    }
 }
 
 impl Params {
@@ -251,70 +1098,1205 @@ impl Params {
             alpha_scale: (1.0, -1.0),
             max_alpha: 0.125,
             cycle: 1. / 256.,
+            palette: Palette::default(),
+            gamma: Self::default_gamma(),
+            beat_shift: 0.,
+            mode: Mode::default(),
+            smoothing: 0.,
+            color_space: ColorSpace::default(),
+            white_balance: Self::default_white_balance(),
+            invert: false,
+            band_hues: Vec::new(),
+            brightness: Brightness::default(),
+            layout: Layout::default(),
+            segments: Vec::new(),
+            peak_hold: None,
+            sparkle: None,
+            envelope: None,
+            loudness: None,
         }
     }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    pub fn gamma(&self) -> f64 {
+        self.gamma
+    }
+
+    pub fn white_balance(&self) -> (f64, f64, f64) {
+        self.white_balance
+    }
+
+    fn default_white_balance() -> (f64, f64, f64) {
+        (1., 1., 1.)
+    }
+
+    pub fn color_space(&self) -> ColorSpace {
+        self.color_space
+    }
+
+    fn default_gamma() -> f64 {
+        2.0
+    }
+
+    /// Hue of bin `k` of `bins` to look up in the color table, from its band
+    /// hue if set, or else from the bin energy `e` and phase `phi`.
+    fn hue(&self, k: usize, bins: usize, e: f64, phi: f64) -> f64 {
+        if self.band_hues.is_empty() {
+            return 180. * (self.cycle * e + phi) / std::f64::consts::PI;
+        }
+        // the table takes hue as a fraction of the cycle
+        self.band_hues[resample(k, bins, self.band_hues.len())] / 360.
+    }
+
+    /// Check every value is within the range the visualizer expects.
+    pub fn validate(&self) -> Result<(), String> {
+        let warnings = self.warnings();
+        if warnings.is_empty() {
+            Ok(())
+        } else {
+            Err(warnings.join(", "))
+        }
+    }
+
+    /// Describe any values outside the range the visualizer expects.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let scales = [
+            ("value_scale", self.value_scale),
+            ("lightness_scale", self.lightness_scale),
+            ("alpha_scale", self.alpha_scale),
+        ];
+        for (name, (scale, offset)) in scales.iter() {
+            if !scale.is_finite() || !offset.is_finite() {
+                warnings.push(format!("{} = ({}, {}) is not finite", name, scale, offset));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.max_alpha) {
+            warnings.push(format!(
+                "max_alpha = {} is outside 0.0 to 1.0",
+                self.max_alpha
+            ));
+        }
+        let (scale, offset) = self.lightness_scale;
+        if !(0.0..=1.0).contains(&offset) || !(0.0..=1.0).contains(&(scale + offset)) {
+            warnings.push(format!(
+                "lightness_scale = ({}, {}) maps outside 0.0 to 1.0",
+                scale, offset
+            ));
+        }
+        let (r, g, b) = self.white_balance;
+        if [r, g, b].iter().any(|c| !(0.0..=1.0).contains(c)) {
+            warnings.push(format!(
+                "white_balance = ({}, {}, {}) is outside 0.0 to 1.0",
+                r, g, b
+            ));
+        }
+        if !(0.0..1.0).contains(&self.smoothing) {
+            warnings.push(format!(
+                "smoothing = {} is outside 0.0 to 1.0",
+                self.smoothing
+            ));
+        }
+        if let Some(envelope) = &self.envelope {
+            let mut taus = envelope.attack.iter().chain(&envelope.decay);
+            if envelope.attack.is_empty()
+                || envelope.decay.is_empty()
+                || taus.any(|t| !t.is_finite() || *t < 0.)
+            {
+                warnings.push(format!(
+                    "envelope = {:?} needs at least one finite, non-negative attack and decay",
+                    envelope
+                ));
+            }
+        }
+        if let Some(loudness) = &self.loudness {
+            let LoudnessParams {
+                target,
+                adapt,
+                floor,
+            } = loudness;
+            if !(target.is_finite() && *target > 0.)
+                || !(adapt.is_finite() && *adapt >= 0.)
+                || !(floor.is_finite() && *floor > 0.)
+            {
+                warnings.push(format!(
+                    "loudness = {:?} needs a positive target and floor and non-negative adapt",
+                    loudness
+                ));
+            }
+        }
+        warnings
+    }
+
+    /// Set a field by name, taking one value per element of the field.
+    pub fn set(&mut self, field: &str, values: &[f64]) -> Result<()> {
+        let pair = |values: &[f64]| match values {
+            [a, b] => Ok((*a, *b)),
+            _ => Err(anyhow!("{} takes 2 values, got {}", field, values.len())),
+        };
+        let single = |values: &[f64]| match values {
+            [a] => Ok(*a),
+            _ => Err(anyhow!("{} takes 1 value, got {}", field, values.len())),
+        };
+        let triple = |values: &[f64]| match values {
+            [a, b, c] => Ok((*a, *b, *c)),
+            _ => Err(anyhow!("{} takes 3 values, got {}", field, values.len())),
+        };
+        match field {
+            "value_scale" => self.value_scale = pair(values)?,
+            "lightness_scale" => self.lightness_scale = pair(values)?,
+            "alpha_scale" => self.alpha_scale = pair(values)?,
+            "max_alpha" => self.max_alpha = single(values)?,
+            "cycle" => self.cycle = single(values)?,
+            "gamma" => self.gamma = single(values)?,
+            "beat_shift" => self.beat_shift = single(values)?,
+            "smoothing" => self.smoothing = single(values)?,
+            "white_balance" => self.white_balance = triple(values)?,
+            "invert" => self.invert = single(values)? != 0.,
+            "band_hues" => self.band_hues = values.to_vec(),
+            "segments" => {
+                if values.len() % 3 != 0 {
+                    return Err(anyhow!("segments takes 3 values for each segment"));
+                }
+                self.segments = values
+                    .chunks(3)
+                    .map(|s| Segment {
+                        bin: s[0] as usize,
+                        start: s[1] as usize,
+                        end: s[2] as usize,
+                    })
+                    .collect();
+            }
+            "peak_hold" => {
+                self.peak_hold = match values {
+                    [] => None,
+                    [decay, r, g, b] => Some(PeakHold {
+                        decay: *decay,
+                        color: (*r as u8, *g as u8, *b as u8),
+                    }),
+                    _ => return Err(anyhow!("peak_hold takes 0 or 4 values")),
+                }
+            }
+            "sparkle" => {
+                self.sparkle = match values {
+                    [] => None,
+                    [count, decay] => Some(SparkleParams {
+                        count: *count as usize,
+                        decay: *decay,
+                    }),
+                    _ => return Err(anyhow!("sparkle takes 0 or 2 values")),
+                }
+            }
+            "loudness" => {
+                self.loudness = match values {
+                    [] => None,
+                    [target, adapt, floor] => Some(LoudnessParams {
+                        target: *target,
+                        adapt: *adapt,
+                        floor: *floor,
+                    }),
+                    _ => return Err(anyhow!("loudness takes 0 or 3 values")),
+                }
+            }
+            "envelope" => {
+                if values.len() % 2 != 0 {
+                    return Err(anyhow!("envelope takes an attack and decay for each band"));
+                }
+                self.envelope = match values {
+                    [] => None,
+                    _ => Some(EnvelopeParams {
+                        attack: values.iter().step_by(2).copied().collect(),
+                        decay: values.iter().skip(1).step_by(2).copied().collect(),
+                    }),
+                }
+            }
+            _ => return Err(anyhow!("unknown param: {}", field)),
+        };
+        Ok(())
+    }
 }
 
 struct Sigmoid {
-    lut: Vec<f64>, // [f64; Self::SIZE],
+    lut: Vec<f64>,
+    range: f64,
+    scale: f64,
 }
 
 impl Sigmoid {
     const SIZE: usize = 2048;
     const RANGE: f64 = 10.0;
-    const SCALE: f64 = Self::SIZE as f64 / (2. * Self::RANGE);
 
     fn new() -> Self {
-        let mut lut = vec![0.; Self::SIZE];
-        let hl = (Self::SIZE / 2) as f64;
-        for i in 0..Self::SIZE {
-            let x = (i as f64 - hl) / hl * Self::RANGE;
+        Self::with(Self::SIZE, Self::RANGE)
+    }
+
+    /// Table of `size` entries covering inputs from -range to +range.
+    fn with(size: usize, range: f64) -> Self {
+        let mut lut = vec![0.; size];
+        let hl = (size / 2) as f64;
+        for i in 0..size {
+            let x = (i as f64 - hl) / hl * range;
             lut[i] = 1. / (1. + f64::exp(-x));
         }
-        Self { lut }
+        Self {
+            lut,
+            range,
+            scale: size as f64 / (2. * range),
+        }
     }
 
     fn f(&self, x: f64) -> f64 {
-        if x >= Self::RANGE {
-            self.lut[Self::SIZE - 1]
-        } else if x <= -Self::RANGE {
+        let last = self.lut.len() - 1;
+        if x >= self.range {
+            self.lut[last]
+        } else if x <= -self.range {
             self.lut[0]
         } else {
-            let idx = (x * Self::SCALE) as usize + Self::SIZE / 2;
-            self.lut[idx]
+            // offset before truncating, negative x would otherwise saturate to 0
+            let pos = (x + self.range) * self.scale;
+            let idx = usize::min(pos as usize, last);
+            let next = usize::min(idx + 1, last);
+            lerp(self.lut[idx], self.lut[next], pos - idx as f64)
         }
     }
 }
 
-struct Clut {
+#[derive(Serialize, Deserialize)]
+pub struct Clut {
+    palette: Palette,
+    gamma: f64,
+    color_space: ColorSpace,
     lut: Vec<Vec<(f64, f64, f64)>>, //[[(f64, f64, f64); Self::VALUES]; Self::HUES],
 }
 
 impl Clut {
     const HUES: usize = 360;
     const VALUES: usize = 256;
+    const CACHE_DIR: &'static str = ".ledcache";
+    /// Number of tables kept in the cache, each of which is a couple of MB.
+    const CACHE_FILES: usize = 8;
 
-    fn new() -> Self {
-        use hsluv::hsluv_to_rgb;
+    /// Build the table for a palette in a color space, gamma correcting the
+    /// colors with the given exponent.
+    pub fn new(palette: &Palette, gamma: f64, color_space: ColorSpace) -> Self {
+        let cs = color_space;
+        let lut = match palette {
+            Palette::Rainbow => Self::build(|h, l| cs.to_rgb(h as f64, 1., l)),
+            Palette::Warm => Self::build(|h, l| cs.to_rgb(Self::sweep(h, 0., 60.), 1., l)),
+            Palette::Cool => Self::build(|h, l| cs.to_rgb(Self::sweep(h, 180., 270.), 1., l)),
+            Palette::Grayscale => Self::build(|_, l| {
+                let (x, _, _) = cs.to_rgb(0., 0., l);
+                (x, x, x)
+            }),
+            Palette::Gradient(stops) => Self::gradient_lut(stops.clone()),
+        };
+        let lut = lut
+            .into_iter()
+            .map(|row| row.into_iter().map(|c| Self::gamma(c, gamma)).collect())
+            .collect();
+        Self {
+            palette: palette.clone(),
+            gamma,
+            color_space,
+            lut,
+        }
+    }
+
+    /// Load the table from the cache, building and caching it on a miss.
+    pub fn cached(palette: &Palette, gamma: f64, color_space: ColorSpace) -> Self {
+        let path = Self::cache_path(palette, gamma, color_space);
+        if let Ok(clut) = Self::load(&path) {
+            if clut.is_for(palette, gamma, color_space) {
+                return clut;
+            }
+        }
+        let clut = Self::new(palette, gamma, color_space);
+        if let Err(e) = clut.save(&path) {
+            warn!("failed to cache color table: {}", e);
+        }
+        if let Err(e) = Self::evict(Path::new(Self::CACHE_DIR), Self::CACHE_FILES) {
+            warn!("failed to clean up the color table cache: {}", e);
+        }
+        clut
+    }
+
+    /// Whether this is the table for the given params.
+    fn is_for(&self, palette: &Palette, gamma: f64, color_space: ColorSpace) -> bool {
+        self.palette == *palette && self.gamma == gamma && self.color_space == color_space
+    }
+
+    /// Remove all but the `keep` most recently written tables in `dir`.
+    fn evict(dir: &Path, keep: usize) -> Result<()> {
+        let mut tables = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with("clut-") && name.ends_with(".bin") {
+                tables.push((entry.metadata()?.modified()?, entry.path()));
+            }
+        }
+        tables.sort_by(|a, b| b.cmp(a));
+        for (_, path) in tables.into_iter().skip(keep) {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn cache_path(palette: &Palette, gamma: f64, color_space: ColorSpace) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        bincode::serialize(&(palette, gamma, color_space))
+            .unwrap_or_default()
+            .hash(&mut hasher);
+        Path::new(Self::CACHE_DIR).join(format!("clut-{:016x}.bin", hasher.finish()))
+    }
+
+    fn load(path: &Path) -> Result<Self> {
+        let f = std::fs::File::open(path)?;
+        Ok(bincode::deserialize_from(std::io::BufReader::new(f))?)
+    }
+
+    /// Write to a temp file and rename it into place so a partial table is
+    /// never loaded.
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("tmp");
+        let f = std::fs::File::create(&tmp)?;
+        bincode::serialize_into(std::io::BufWriter::new(f), self)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Table from RGB stops positioned from 0.0 to 1.0 along the hue cycle,
+    /// scaled down linearly with value.
+    fn gradient_lut(mut stops: Vec<(f64, (u8, u8, u8))>) -> Vec<Vec<(f64, f64, f64)>> {
+        stops.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        Self::build(|h, l| {
+            let c = Self::gradient(&stops, h as f64 / Self::HUES as f64);
+            (l * c.0, l * c.1, l * c.2)
+        })
+    }
+
+    /// Fill a table from a function of the hue index and lightness.
+    fn build<F: Fn(usize, f64) -> (f64, f64, f64)>(color: F) -> Vec<Vec<(f64, f64, f64)>> {
         let mut lut = vec![vec![(0., 0., 0.); Self::VALUES]; Self::HUES];
         for h in 0..Self::HUES {
             for v in 0..Self::VALUES {
-                let c = hsluv_to_rgb((h as f64, 100., 100. * v as f64 / 256.));
-                let c = Self::gamma(c);
-                lut[h][v] = (c.0 as f64, c.1 as f64, c.2 as f64);
+                lut[h][v] = color(h, v as f64 / 256.);
+            }
+        }
+        lut
+    }
+
+    /// Sweep from `lo` to `hi` and back over the hue cycle.
+    fn sweep(h: usize, lo: f64, hi: f64) -> f64 {
+        let t = h as f64 / Self::HUES as f64;
+        let t = 1. - (2. * t - 1.).abs();
+        lo + t * (hi - lo)
+    }
+
+    /// Color at `x` interpolated between sorted stops.
+    fn gradient(stops: &[(f64, (u8, u8, u8))], x: f64) -> (f64, f64, f64) {
+        let rgb = |c: (u8, u8, u8)| (c.0 as f64 / 255., c.1 as f64 / 255., c.2 as f64 / 255.);
+        match stops.iter().position(|&(p, _)| p > x) {
+            None => stops.last().map_or((0., 0., 0.), |&(_, c)| rgb(c)),
+            Some(0) => rgb(stops[0].1),
+            Some(i) => {
+                let (p0, c0) = stops[i - 1];
+                let (p1, c1) = stops[i];
+                let t = (x - p0) / (p1 - p0);
+                let (a, b) = (rgb(c0), rgb(c1));
+                (
+                    a.0 + t * (b.0 - a.0),
+                    a.1 + t * (b.1 - a.1),
+                    a.2 + t * (b.2 - a.2),
+                )
+            }
+        }
+    }
+
+    fn gamma(c: (f64, f64, f64), gamma: f64) -> (f64, f64, f64) {
+        (c.0.powf(gamma), c.1.powf(gamma), c.2.powf(gamma))
+    }
+
+    /// Bilinear lookup, wrapping around the hue cycle and clamping value.
+    pub fn lookup(&self, h: f64, v: f64) -> (f64, f64, f64) {
+        let h = (h * Self::HUES as f64).rem_euclid(Self::HUES as f64);
+        let h0 = h as usize % Self::HUES;
+        let h1 = (h0 + 1) % Self::HUES;
+        let th = h - h.floor();
+
+        let v = f64::max(v * Self::VALUES as f64, 0.);
+        let v0 = usize::min(v as usize, Self::VALUES - 1);
+        let v1 = usize::min(v0 + 1, Self::VALUES - 1);
+        let tv = f64::min(v - v0 as f64, 1.);
+
+        let mix = |a: (f64, f64, f64), b: (f64, f64, f64), t| {
+            (lerp(a.0, b.0, t), lerp(a.1, b.1, t), lerp(a.2, b.2, t))
+        };
+        let lo = mix(self.lut[h0][v0], self.lut[h0][v1], tv);
+        let hi = mix(self.lut[h1][v0], self.lut[h1][v1], tv);
+        mix(lo, hi, th)
+    }
+}
+
+/// Write to pixel `y` of strip `x` of a frame in the logical layout the
+/// output transform takes.
+fn write_pixel(frame: &mut [ARGB8], height: usize, x: usize, y: usize, color: ARGB8) {
+    frame[x * height + y] = color;
+}
+
+/// Map index `i` of a dimension of size `from` onto the nearest index of a
+/// dimension of size `to`, so features can be drawn on any grid size.
+fn resample(i: usize, from: usize, to: usize) -> usize {
+    if from == 0 || to == 0 {
+        return 0;
+    }
+    usize::min(i * to / from, to - 1)
+}
+
+/// The 5 bit global brightness of an APA102 for `alpha` from 0.0 to 1.0,
+/// clamped so a bad `max_alpha` can't spill into the other bits.
+fn alpha_byte(alpha: f64) -> u8 {
+    f64::min(f64::max(31.5 * alpha, 0.), 31.) as u8
+}
+
+/// Exponential moving average, updating `prev` with `val` at weight
+/// `1 - smoothing`.
+fn smooth(prev: &mut f64, val: f64, smoothing: f64) -> f64 {
+    *prev = smoothing * *prev + (1. - smoothing) * val;
+    *prev
+}
+
+/// Raise each peak to its new level, or let it fall by `decay`.
+fn hold_peaks(peaks: &mut Vec<f64>, levels: &[f64], decay: f64) {
+    peaks.resize(levels.len(), 0.);
+    for (p, &l) in peaks.iter_mut().zip(levels) {
+        *p = f64::max(l, *p - decay);
+    }
+}
+
+/// Add a new column to the front of the history, dropping those that no
+/// longer fit in `width`.
+fn push_column<T>(columns: &mut VecDeque<T>, column: T, width: usize) {
+    columns.push_front(column);
+    columns.truncate(width);
+}
+
+/// Split interleaved samples into a buffer for each channel.
+pub fn deinterleave(data: &[f32], channels: usize) -> Vec<Vec<f64>> {
+    let mut out = vec![Vec::with_capacity(data.len() / channels); channels];
+    for frame in data.chunks_exact(channels) {
+        for (c, &x) in frame.iter().enumerate() {
+            out[c].push(x as f64);
+        }
+    }
+    out
+}
+
+/// Samples between each run of the FFT, which is the input block size unless
+/// an overlap of the FFT windows is given.
+fn hop_size(fft_size: usize, overlap: Option<usize>, block_size: usize) -> usize {
+    match overlap {
+        Some(overlap) => usize::max(fft_size / usize::max(overlap, 1), 1),
+        None => block_size,
+    }
+}
+
+/// Analysis of a channel of audio, by the audio crate's analyzer or with the
+/// configured buckets.
+enum Analysis {
+    Analyzer(audio::Analyzer),
+    Buckets(BucketAnalyzer),
+}
+
+impl Analysis {
+    /// Features of the next hop of samples, if there are new ones.
+    fn process(&mut self, data: &mut Vec<f64>) -> Option<audio::frequency_sensor::Features> {
+        match self {
+            Analysis::Analyzer(analyzer) => analyzer.process(data),
+            Analysis::Buckets(analyzer) => Some(analyzer.process(data)),
+        }
+    }
+
+    fn write_debug(&self, out: &mut String) -> std::fmt::Result {
+        match self {
+            Analysis::Analyzer(analyzer) => analyzer.write_debug(out),
+            Analysis::Buckets(analyzer) => {
+                use std::fmt::Write;
+                write!(out, "bucket indices: {:?}", analyzer.indices())
+            }
+        }
+    }
+}
+
+/// Rechunker regroups a stream of sample blocks into blocks of a fixed size.
+pub struct Rechunker {
+    size: usize,
+    buf: Vec<f64>,
+}
+
+impl Rechunker {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            buf: Vec::with_capacity(2 * size),
+        }
+    }
+
+    /// Append samples, returning every complete block.
+    pub fn push(&mut self, data: &[f64]) -> Vec<Vec<f64>> {
+        self.buf.extend_from_slice(data);
+        let blocks: Vec<Vec<f64>> = self
+            .buf
+            .chunks_exact(self.size)
+            .map(|b| b.to_vec())
+            .collect();
+        self.buf.drain(..blocks.len() * self.size);
+        blocks
+    }
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + t * (b - a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Bands {
+        scales: Vec<f64>,
+        energy: Vec<f64>,
+        amplitudes: Vec<f64>,
+    }
+
+    impl Spectrum for Bands {
+        fn scales(&self) -> &[f64] {
+            &self.scales
+        }
+
+        fn energy(&self) -> &[f64] {
+            &self.energy
+        }
+
+        fn amplitudes(&self, _: usize) -> &[f64] {
+            &self.amplitudes
+        }
+    }
+
+    /// Every bin at `amplitude` times its usual level.
+    fn bands(bins: usize, amplitude: f64) -> Bands {
+        Bands {
+            scales: vec![1.; bins],
+            energy: vec![0.; bins],
+            amplitudes: vec![amplitude; bins],
+        }
+    }
+
+    fn opts() -> Opts {
+        Opts {
+            device: None,
+            sample_rate: 44100,
+            sample_block_size: 256,
+            fft_size: 1024,
+            bins: 16,
+            length: 144,
+            freq_min: None,
+            freq_max: None,
+            bucket_scale: None,
+            overlap: None,
+            channels: 1,
+            wav: None,
+            loop_wav: false,
+            synthetic: None,
+            record_audio: None,
+            features_out: None,
+            features_only: false,
+            idle_timeout: None,
+            idle_threshold: 0.05,
+            osc: None,
+            overrides: ParamOverrides {
+                cycle: None,
+                max_alpha: None,
+                palette: None,
+            },
+        }
+    }
+
+    fn visualizer(params: Params) -> Visualizer {
+        let params = Arc::new(RwLock::new(params));
+        Visualizer::new(opts(), params, Arc::new(Metrics::default()))
+    }
+
+    fn max_alpha(frame: &[ARGB8]) -> u8 {
+        frame.iter().map(|p| p.a).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn visualize_picks_up_swapped_params() {
+        let vis = visualizer(Params::defaults());
+        let loud = [bands(4, 3.)];
+        let before = vis.visualize((16, 4), &loud);
+        assert!((1..=alpha_byte(0.125)).contains(&max_alpha(&before)));
+
+        let mut params = Params::defaults();
+        params.max_alpha = 1.;
+        *vis.params.write().unwrap() = params;
+        let after = vis.visualize((16, 4), &loud);
+        assert!(max_alpha(&after) > 4 * max_alpha(&before));
+    }
+
+    #[test]
+    fn invalid_alpha_is_warned_about() {
+        let mut params = Params::defaults();
+        assert!(params.warnings().is_empty());
+        params.set("max_alpha", &[1.5]).unwrap();
+        assert_eq!(
+            params.warnings(),
+            vec!["max_alpha = 1.5 is outside 0.0 to 1.0".to_string()]
+        );
+        assert!(params.validate().is_err());
+    }
+
+    #[test]
+    fn override_flags_replace_only_their_params() {
+        let mut params = Params::defaults();
+        let overrides = ParamOverrides {
+            cycle: Some(0.5),
+            max_alpha: None,
+            palette: None,
+        };
+        overrides.apply(&mut params).unwrap();
+        assert_eq!(params.cycle, 0.5);
+        assert_eq!(params.max_alpha, Params::defaults().max_alpha);
+        assert_eq!(params.palette, Palette::Rainbow);
+    }
+
+    #[test]
+    fn grayscale_palette_is_gray_at_every_hue() {
+        let clut = Clut::new(&Palette::Grayscale, 2., ColorSpace::Hsv);
+        for row in clut.lut.iter() {
+            for &(r, g, b) in row.iter() {
+                assert!(r == g && g == b, "({}, {}, {})", r, g, b);
+            }
+        }
+        let (r, _, _) = clut.lookup(0.3, 0.5);
+        assert!(r > 0.);
+    }
+
+    #[test]
+    fn black_to_white_gradient_is_a_ramp() {
+        let path = std::env::temp_dir().join(format!("palette-{}.csv", std::process::id()));
+        std::fs::write(&path, "# position,r,g,b\n0.0,0,0,0\n\n1.0,255,255,255\n").unwrap();
+        let palette = Palette::load_csv(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            palette,
+            Palette::Gradient(vec![(0., (0, 0, 0)), (1., (255, 255, 255))])
+        );
+
+        let clut = Clut::new(&palette, 1., ColorSpace::Hsv);
+        let ramp: Vec<f64> = clut.lut.iter().map(|row| row[Clut::VALUES - 1].0).collect();
+        assert_eq!(ramp[0], 0.);
+        assert!(ramp.windows(2).all(|w| w[0] < w[1]));
+        assert!(ramp[Clut::HUES - 1] > 0.99 * 255. / 256.);
+    }
+
+    #[test]
+    fn gamma_is_applied_as_an_exponent() {
+        let raw = Clut::new(&Palette::Rainbow, 1., ColorSpace::Hsv);
+        let squared = Clut::new(&Palette::Rainbow, 2., ColorSpace::Hsv);
+        for h in (0..Clut::HUES).step_by(7) {
+            for v in (0..Clut::VALUES).step_by(5) {
+                let c = hsv_to_rgb(h as f64, 1., v as f64 / 256.);
+                assert_eq!(raw.lut[h][v], c);
+                let (r, g, b) = squared.lut[h][v];
+                assert!((r - c.0 * c.0).abs() < 1e-12);
+                assert!((g - c.1 * c.1).abs() < 1e-12);
+                assert!((b - c.2 * c.2).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn cached_table_round_trip() {
+        let path = std::env::temp_dir().join(format!("clut-{}.bin", std::process::id()));
+        let clut = Clut::new(&Palette::Warm, 2.2, ColorSpace::Hsv);
+        clut.save(&path).unwrap();
+        let loaded = Clut::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.is_for(&Palette::Warm, 2.2, ColorSpace::Hsv));
+        assert_eq!(loaded.lut, clut.lut);
+    }
+
+    #[test]
+    fn cache_keeps_a_bounded_number_of_tables() {
+        let dir = std::env::temp_dir().join(format!("ledcache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for i in 0..10 {
+            std::fs::write(dir.join(format!("clut-{:016x}.bin", i)), b"").unwrap();
+        }
+        std::fs::write(dir.join("other.txt"), b"").unwrap();
+        Clut::evict(&dir, 8).unwrap();
+        let count = |prefix: &str| {
+            std::fs::read_dir(&dir)
+                .unwrap()
+                .filter(|e| {
+                    e.as_ref()
+                        .unwrap()
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with(prefix)
+                })
+                .count()
+        };
+        assert_eq!(count("clut-"), 8);
+        assert_eq!(count("other"), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changed_palette_is_built_without_blocking_render() {
+        let vis = visualizer(Params::defaults());
+        let mut params = Params::defaults();
+        params.palette = Palette::Grayscale;
+        params.color_space = ColorSpace::Hsv;
+        *vis.params.write().unwrap() = params;
+        let quiet = [bands(4, 1.)];
+        vis.visualize((16, 4), &quiet);
+        // the old table stays in use until the new one has been built
+        assert_eq!(vis.clut.borrow().palette, Palette::Rainbow);
+        assert!(vis.pending_clut.borrow().is_some());
+        for _ in 0..1000 {
+            vis.visualize((16, 4), &quiet);
+            if vis.pending_clut.borrow().is_none() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(vis.clut.borrow().palette, Palette::Grayscale);
+    }
+
+    fn logistic(x: f64) -> f64 {
+        1. / (1. + f64::exp(-x))
+    }
+
+    #[test]
+    fn sigmoid_is_centered_and_clamps_to_its_range() {
+        assert!((SIGMOID.f(0.) - 0.5).abs() < 1e-3);
+        let s = Sigmoid::with(512, 5.);
+        assert!((s.f(0.) - 0.5).abs() < 1e-3);
+        assert_eq!(s.f(6.), s.f(5.));
+        assert_eq!(s.f(1e9), s.lut[511]);
+        assert_eq!(s.f(-6.), s.f(-5.));
+        assert_eq!(s.f(-1e9), s.lut[0]);
+        assert!((s.f(4.999) - logistic(5.)).abs() < 1e-2);
+        assert!((s.f(-4.999) - logistic(-5.)).abs() < 1e-2);
+    }
+
+    #[test]
+    fn sigmoid_interpolates_between_entries() {
+        // a coarse table, where midpoints are far from the nearest entry
+        let s = Sigmoid::with(64, 8.);
+        let step = 2. * 8. / 64.;
+        for i in 1..63 {
+            let x = -8. + (i as f64 + 0.5) * step;
+            assert!((s.f(x) - logistic(x)).abs() < 2e-3, "f({})", x);
+        }
+    }
+
+    #[test]
+    fn lookup_interpolates_between_entries() {
+        let clut = Clut::new(&Palette::Grayscale, 1., ColorSpace::Hsv);
+        // halfway between value entries 100 and 101
+        let (r, g, b) = clut.lookup(0.25, 100.5 / 256.);
+        assert!((r - 100.5 / 256.).abs() < 1e-9);
+        assert_eq!((r, r), (g, b));
+
+        let clut = Clut::new(&Palette::Rainbow, 1., ColorSpace::Hsv);
+        // halfway between hue entries 30 and 31, where green is rising
+        let (_, g, _) = clut.lookup(30.5 / 360., 0.5);
+        let exact = hsv_to_rgb(30.5, 1., 0.5).1;
+        assert!((g - exact).abs() < 1e-9);
+    }
+
+    #[test]
+    fn deinterleaves_stereo() {
+        let data = [0., 1., 0.25, -1., 0.5, 0.75];
+        let channels = deinterleave(&data, 2);
+        assert_eq!(channels, vec![vec![0., 0.25, 0.5], vec![1., -1., 0.75]]);
+        // a trailing partial frame is dropped
+        assert_eq!(deinterleave(&data[..5], 2)[1], vec![1., -1.]);
+    }
+
+    #[test]
+    fn overlap_sets_the_number_of_frames() {
+        let hop = hop_size(1024, Some(4), 300);
+        assert_eq!(hop, 256);
+        assert_eq!(hop_size(1024, None, 300), 300);
+        // ten callback blocks of 300 samples are eleven hops, with a remainder
+        let mut rechunker = Rechunker::new(hop);
+        let frames: usize = (0..10).map(|_| rechunker.push(&[0.; 300]).len()).sum();
+        assert_eq!(frames, 3000 / 256);
+    }
+
+    #[test]
+    fn matrix_writes_distinct_colors_to_distinct_cells() {
+        let mut params = Params::defaults();
+        params.mode = Mode::Matrix;
+        params.max_alpha = 1.;
+        let vis = visualizer(params);
+        // a level for each bin and frame of history, all different
+        let features = FeatureFrame {
+            frame: 0,
+            channel: 0,
+            scales: vec![1.; 4],
+            energy: vec![0.; 4],
+            amplitudes: vec![vec![1.0, 1.1, 1.2, 1.3], vec![1.4, 1.5, 1.6, 1.7]],
+        };
+        let frame = vis.visualize((4, 2), &[features]);
+        for (i, a) in frame.iter().enumerate() {
+            for b in &frame[i + 1..] {
+                assert_ne!(a, b, "{:?}", frame);
             }
         }
-        Self { lut }
     }
 
-    fn gamma(c: (f64, f64, f64)) -> (f64, f64, f64) {
-        (c.0 * c.0, c.1 * c.1, c.2 * c.2)
+    #[test]
+    fn spectrogram_columns_shift_and_drop_off() {
+        let mut columns = VecDeque::new();
+        for c in 0..5 {
+            push_column(&mut columns, c, 3);
+        }
+        assert_eq!(columns, vec![4, 3, 2]);
+
+        let mut params = Params::defaults();
+        params.mode = Mode::Spectrogram;
+        let vis = visualizer(params);
+        vis.visualize((4, 3), &[bands(4, 2.)]);
+        vis.visualize((4, 3), &[bands(4, 3.)]);
+        let columns = vis.columns.borrow();
+        assert_eq!(columns.len(), 2);
+        // the newest column is first, with the louder values
+        assert!(columns[0]
+            .iter()
+            .zip(columns[1].iter())
+            .all(|(a, b)| a.0 > b.0));
+    }
+
+    #[test]
+    fn mismatched_sizes_resample_without_panicking() {
+        assert_eq!(resample(0, 10, 4), 0);
+        assert_eq!(resample(9, 10, 4), 3);
+        assert_eq!(resample(3, 4, 10), 7);
+        assert_eq!(resample(5, 0, 4), 0);
+        for &mode in &[Mode::Ring, Mode::Matrix, Mode::Spectrogram] {
+            let mut params = Params::defaults();
+            params.mode = mode;
+            let vis = visualizer(params);
+            // more bins than strips, then fewer, each along a grid unlike the history length
+            for &(size, bins) in &[((7, 5), 16), ((50, 20), 3)] {
+                let frame = vis.visualize(size, &[bands(bins, 2.)]);
+                assert_eq!(frame.len(), size.0 * size.1);
+            }
+        }
+    }
+
+    #[test]
+    fn step_input_approaches_exponentially() {
+        let mut prev = 0.;
+        let rising: Vec<f64> = (0..5).map(|_| smooth(&mut prev, 1., 0.5)).collect();
+        assert_eq!(rising, vec![0.5, 0.75, 0.875, 0.9375, 0.96875]);
+        // each step closes the same fraction of the remaining gap
+        let mut prev = 0.;
+        let gaps: Vec<f64> = (0..5).map(|_| 1. - smooth(&mut prev, 1., 0.8)).collect();
+        assert!(gaps.windows(2).all(|w| (w[1] / w[0] - 0.8).abs() < 1e-9));
+    }
+
+    #[test]
+    fn hsv_primaries_are_pure() {
+        let bytes =
+            |(r, g, b): (f64, f64, f64)| ((255. * r) as u8, (255. * g) as u8, (255. * b) as u8);
+        assert_eq!(bytes(ColorSpace::Hsv.to_rgb(0., 1., 1.)), (255, 0, 0));
+        assert_eq!(bytes(ColorSpace::Hsv.to_rgb(120., 1., 1.)), (0, 255, 0));
+        assert_eq!(bytes(ColorSpace::Hsv.to_rgb(240., 1., 1.)), (0, 0, 255));
+        assert_eq!(bytes(ColorSpace::Hsv.to_rgb(360., 1., 1.)), (255, 0, 0));
+        assert_eq!(bytes(ColorSpace::Hsv.to_rgb(0., 0., 0.5)), (127, 127, 127));
+    }
+
+    #[test]
+    fn white_balance_scales_white() {
+        let mut params = Params::defaults();
+        // full lightness whatever the level
+        params.set("lightness_scale", &[0., 1.]).unwrap();
+        params.set("white_balance", &[1., 0.9, 0.8]).unwrap();
+        let vis = visualizer(params.clone());
+        let clut = Clut::new(&Palette::Grayscale, 1., ColorSpace::Hsv);
+        let white = vis.get_hsv(&params, &clut, 0., 0.);
+        // the top of the table is the brightest gray short of full white
+        let (x, _, _) = clut.lookup(0., 1.);
+        let byte = |gain: f64| (255.5 * gain * x) as u8;
+        assert_eq!(
+            (white.r, white.g, white.b),
+            (byte(1.), byte(0.9), byte(0.8))
+        );
+        assert_eq!((white.r, white.g, white.b), (254, 229, 203));
+    }
+
+    #[test]
+    fn inverted_and_normal_sum_to_a_constant() {
+        let mut params = Params::defaults();
+        params.set("lightness_scale", &[1., 0.]).unwrap();
+        let mut inverted = params.clone();
+        inverted.set("invert", &[1.]).unwrap();
+        let vis = visualizer(params.clone());
+        let clut = Clut::new(&Palette::Grayscale, 1., ColorSpace::Hsv);
+        let sums: Vec<i32> = [-2., -0.5, 0., 0.5, 2.]
+            .iter()
+            .map(|&val| {
+                let a = vis.get_hsv(&params, &clut, val, 0.);
+                let b = vis.get_hsv(&inverted, &clut, val, 0.);
+                a.r as i32 + b.r as i32
+            })
+            .collect();
+        // a loud value is as dark inverted as a quiet one is normally
+        assert!(sums.iter().all(|s| (s - sums[0]).abs() <= 2), "{:?}", sums);
+    }
+
+    #[test]
+    fn bands_take_their_configured_hues() {
+        let mut params = Params::defaults();
+        params.set("band_hues", &[0., 120., 240.]).unwrap();
+        // bass, mid and treble thirds of the bins, whatever their energy
+        assert_eq!(params.hue(0, 16, 0.7, 1.), 0.);
+        assert_eq!(params.hue(8, 16, 0.2, 3.), 1. / 3.);
+        assert_eq!(params.hue(15, 16, 0., 0.), 2. / 3.);
+
+        let clut = Clut::new(&Palette::Rainbow, 1., ColorSpace::Hsv);
+        let (r, g, b) = clut.lookup(params.hue(0, 16, 0., 0.), 1.);
+        assert!(r > 0.9 && g < 0.1 && b < 0.1);
+    }
+
+    #[test]
+    fn peaks_hold_a_spike_then_decay() {
+        let mut peaks = Vec::new();
+        hold_peaks(&mut peaks, &[0.2, 0.1], 0.25);
+        assert_eq!(peaks, vec![0.2, 0.1]);
+        hold_peaks(&mut peaks, &[1., 0.1], 0.25);
+        assert_eq!(peaks, vec![1., 0.1]);
+        // the level drops away but the peak falls by the decay each frame
+        let fallen: Vec<f64> = (0..4)
+            .map(|_| {
+                hold_peaks(&mut peaks, &[0.2, 0.1], 0.25);
+                peaks[0]
+            })
+            .collect();
+        assert_eq!(fallen, vec![0.75, 0.5, 0.25, 0.2]);
+    }
+
+    #[test]
+    fn replayed_json_frames_differ() {
+        let path = std::env::temp_dir().join(format!("replay-{}.jsonl", std::process::id()));
+        let lines: Vec<String> = [1., 3.]
+            .iter()
+            .enumerate()
+            .map(|(i, &amplitude)| {
+                let frame = FeatureFrame {
+                    frame: i,
+                    channel: 0,
+                    scales: vec![1.; 4],
+                    energy: vec![0.; 4],
+                    amplitudes: vec![vec![amplitude; 4]],
+                };
+                serde_json::to_string(&frame).unwrap()
+            })
+            .collect();
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        let (display, rx) = Display::with_capacity(2);
+        let vis = visualizer(Params::defaults());
+        vis.replay((8, 2), &path, 1000., &display).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let frames: Vec<Vec<ARGB8>> = rx.try_iter().map(|f| f.pixels).collect();
+        assert_eq!(frames.len(), 2);
+        assert_ne!(frames[0], frames[1]);
+    }
+
+    #[test]
+    fn segment_lights_only_its_pixels() {
+        let mut params = Params::defaults();
+        params.set("segments", &[2., 10., 20.]).unwrap();
+        params.max_alpha = 1.;
+        let vis = visualizer(params);
+        let frame = vis.visualize((30, 2), &[bands(4, 3.)]);
+        for strip in frame.chunks(30) {
+            let lit: Vec<usize> = (0..30).filter(|&i| strip[i].a > 0).collect();
+            assert_eq!(lit, (10..20).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn mirrored_center_is_symmetric() {
+        for &length in &[9, 10] {
+            let positions: Vec<usize> = (0..length)
+                .map(|i| Layout::MirroredCenter.position(i, length, 0.))
+                .collect();
+            let mut reversed = positions.clone();
+            reversed.reverse();
+            assert_eq!(positions, reversed);
+            // the center is the start of the pattern
+            assert_eq!(positions[length / 2], 0);
+        }
+
+        let mut params = Params::defaults();
+        params.layout = Layout::MirroredCenter;
+        let vis = visualizer(params);
+        let features = FeatureFrame {
+            frame: 0,
+            channel: 0,
+            scales: vec![1.; 4],
+            energy: vec![0.; 4],
+            amplitudes: (0..144).map(|i| vec![1. + i as f64 / 50.; 4]).collect(),
+        };
+        let frame = vis.visualize((10, 1), &[features]);
+        let mut reversed = frame.clone();
+        reversed.reverse();
+        assert_eq!(frame, reversed);
+    }
+
+    #[test]
+    fn ring_rotates_with_beats_without_a_hue_shift() {
+        assert_eq!(Layout::Ring.position(0, 16, 0.25), 4);
+        assert_eq!(Layout::Ring.position(14, 16, 0.25), 2);
+
+        let mut params = Params::defaults();
+        params.layout = Layout::Ring;
+        let vis = visualizer(params);
+        // a burst of energy every 20 frames after a slightly uneven floor
+        let energy = |e: f64| Bands {
+            energy: vec![e; 4],
+            ..bands(4, 1.)
+        };
+        for i in 0..60 {
+            let e = match i % 20 {
+                19 => 5.,
+                _ => 1. + (i % 2) as f64 * 0.1,
+            };
+            vis.visualize((16, 1), &[energy(e)]);
+        }
+        assert_eq!(vis.beat_phase.get(), 0.);
+        assert!(vis.ring_turn.get() > 0.);
+    }
+
+    #[test]
+    fn alpha_clamps_to_five_bits() {
+        assert_eq!(alpha_byte(40. / 31.), 31);
+        assert_eq!(alpha_byte(1.), 31);
+        assert_eq!(alpha_byte(0.5), 15);
+        assert_eq!(alpha_byte(-0.5), 0);
+    }
+
+    #[test]
+    fn rgb_brightness_moves_alpha_into_the_color() {
+        let mut hybrid = Params::defaults();
+        hybrid.set("lightness_scale", &[0., 1.]).unwrap();
+        // half brightness whatever the level
+        hybrid.set("alpha_scale", &[0., 100.]).unwrap();
+        hybrid.set("max_alpha", &[0.5]).unwrap();
+        let mut rgb = hybrid.clone();
+        rgb.brightness = Brightness::Rgb;
+        let vis = visualizer(hybrid.clone());
+        let clut = Clut::new(&Palette::Grayscale, 1., ColorSpace::Hsv);
+
+        let h = vis.get_hsv(&hybrid, &clut, 0., 0.);
+        let r = vis.get_hsv(&rgb, &clut, 0., 0.);
+        assert_eq!((h.a, h.r), (15, 254));
+        assert_eq!((r.a, r.r), (31, 127));
+        // both are as bright, to within a step of the 5 bit alpha
+        let level = |p: ARGB8| p.a as f64 / 31. * p.r as f64;
+        assert!((level(h) - level(r)).abs() < 255. / 31.);
+    }
+
+    #[test]
+    fn envelope_time_constants_spread_over_the_bins() {
+        let mut params = Params::defaults();
+        params.set("envelope", &[0.01, 0.5, 0.02, 1.]).unwrap();
+        let envelope = params.envelope.clone().unwrap();
+        assert_eq!(
+            envelope.time_constants(4),
+            vec![(0.01, 0.5), (0.01, 0.5), (0.02, 1.), (0.02, 1.)]
+        );
+        assert!(params.warnings().is_empty());
+        assert!(params.set("envelope", &[0.01]).is_err());
+        params.set("envelope", &[-1., 0.5]).unwrap();
+        assert_eq!(params.warnings().len(), 1);
+        params.set("envelope", &[]).unwrap();
+        assert!(params.envelope.is_none());
+    }
+
+    #[test]
+    fn envelope_holds_brightness_after_the_sound_stops() {
+        let mut params = Params::defaults();
+        params.max_alpha = 1.;
+        let plain = visualizer(params.clone());
+        params.set("envelope", &[0., 1.]).unwrap();
+        let held = visualizer(params);
+        for vis in [&plain, &held].iter() {
+            vis.visualize((16, 1), &[bands(4, 3.)]);
+        }
+        let quiet = [bands(4, 1.)];
+        assert!(
+            max_alpha(&held.visualize((16, 1), &quiet))
+                > max_alpha(&plain.visualize((16, 1), &quiet))
+        );
+    }
+
+    #[test]
+    fn loudness_params_are_set_and_checked() {
+        let mut params = Params::defaults();
+        params.set("loudness", &[0.5, 10., 0.01]).unwrap();
+        assert!(params.warnings().is_empty());
+        params.set("loudness", &[0.5, 10., 0.]).unwrap();
+        assert_eq!(params.warnings().len(), 1);
+        assert!(params.set("loudness", &[0.5]).is_err());
+        params.set("loudness", &[]).unwrap();
+        assert!(params.loudness.is_none());
+    }
+
+    #[test]
+    fn quiet_rooms_are_brought_up_to_the_target() {
+        let mut params = Params::defaults();
+        params.max_alpha = 1.;
+        let plain = visualizer(params.clone());
+        params.set("loudness", &[1., 0., 0.01]).unwrap();
+        let normalized = visualizer(params);
+        let quiet = || Bands {
+            scales: vec![1.; 4],
+            energy: vec![0.1; 4],
+            amplitudes: vec![1.5; 4],
+        };
+        let before = max_alpha(&plain.visualize((16, 1), &[quiet()]));
+        let after = max_alpha(&normalized.visualize((16, 1), &[quiet()]));
+        assert!(after > before);
+        let factor = normalized.metrics.loudness_factor().unwrap();
+        assert!((factor - 10.).abs() < 1e-9);
+        assert_eq!(plain.metrics.loudness_factor(), None);
     }
 
-    fn lookup(&self, h: f64, v: f64) -> (f64, f64, f64) {
-        let h = (h * Self::HUES as f64) as usize % Self::HUES;
-        let v = (v * Self::VALUES as f64) as usize;
-        let v = usize::max(usize::min(v, Self::VALUES - 1), 0);
-        self.lut[h][v]
+    #[test]
+    fn bucket_options_replace_the_analyzer_buckets() {
+        assert_eq!(visualizer(Params::defaults()).buckets(), None);
+        let mut opts = opts();
+        opts.freq_max = Some(250.);
+        opts.bucket_scale = Some(BucketScale::Mel);
+        let params = Arc::new(RwLock::new(Params::defaults()));
+        let vis = Visualizer::new(opts, params, Arc::new(Metrics::default()));
+        assert_eq!(vis.buckets(), Some(((32., 250.), BucketScale::Mel)));
     }
 }
@@ -0,0 +1,196 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::Result;
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+use log::error;
+
+use crate::display::FrameLimiter;
+
+/// WavSource plays a WAV file through the same callback as a live audio
+/// stream, so the visualizer can be run against repeatable input.
+pub struct WavSource {
+    samples: Vec<f32>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl WavSource {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut reader = WavReader::open(path)?;
+        let spec = reader.spec();
+        let samples = match spec.sample_format {
+            SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            SampleFormat::Int => {
+                let scale = (1u32 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|s| s.map(|s| s as f32 / scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        Ok(Self {
+            samples,
+            channels: spec.channels,
+            sample_rate: spec.sample_rate,
+        })
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Interleaved blocks of `block_size` samples per channel.
+    pub fn blocks(&self, block_size: usize) -> std::slice::Chunks<f32> {
+        self.samples.chunks(block_size * self.channels as usize)
+    }
+
+    /// Pass blocks to `handler` in real time at the file's sample rate,
+    /// stopping at the end of the file unless `looping`.
+    pub fn play(
+        self,
+        block_size: usize,
+        looping: bool,
+        handler: Box<dyn Fn(&[f32]) -> () + Send>,
+    ) -> Playback {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        thread::spawn(move || {
+            let mut limiter = FrameLimiter::new(self.sample_rate as f64 / block_size as f64);
+            loop {
+                for block in self.blocks(block_size) {
+                    if stopped.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    handler(block);
+                    limiter.wait();
+                }
+                if !looping {
+                    return;
+                }
+            }
+        });
+        Playback { stop }
+    }
+}
+
+/// Handle to a playing WavSource, which stops playback when dropped.
+pub struct Playback {
+    stop: Arc<AtomicBool>,
+}
+
+impl Playback {
+    /// Handle which sets `stop` when dropped.
+    pub fn new(stop: Arc<AtomicBool>) -> Self {
+        Self { stop }
+    }
+}
+
+impl Drop for Playback {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// WavRecorder writes interleaved input samples to a WAV file.
+pub struct WavRecorder {
+    writer: WavWriter<BufWriter<File>>,
+}
+
+impl WavRecorder {
+    pub fn create<P: AsRef<Path>>(path: P, channels: u16, sample_rate: u32) -> Result<Self> {
+        let spec = WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        Ok(Self {
+            writer: WavWriter::create(path, spec)?,
+        })
+    }
+
+    /// Append a block of samples. The header is updated after every block,
+    /// since the process may be stopped without running destructors.
+    pub fn write(&mut self, data: &[f32]) -> Result<()> {
+        for &x in data {
+            self.writer.write_sample(x)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Wrap a stream handler so each block is also written to `recorder`.
+pub fn tee(
+    recorder: WavRecorder,
+    handler: Box<dyn Fn(&[f32]) -> () + Send>,
+) -> Box<dyn Fn(&[f32]) -> () + Send> {
+    let recorder = Mutex::new(recorder);
+    Box::new(move |data: &[f32]| {
+        if let Err(e) = recorder.lock().unwrap().write(data) {
+            error!("failed to record audio: {}", e);
+        }
+        handler(data);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn recorded_wav_round_trip_plays_back_in_blocks() {
+        let dir = std::env::temp_dir().join(format!("wav-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("stereo.wav");
+        let samples: Vec<f32> = (0..20).map(|i| i as f32 / 20.).collect();
+        let mut recorder = WavRecorder::create(&path, 2, 1000).unwrap();
+        recorder.write(&samples).unwrap();
+        drop(recorder);
+
+        let source = WavSource::from_file(&path).unwrap();
+        assert_eq!((source.channels(), source.sample_rate()), (2, 1000));
+        let (tx, rx) = channel();
+        let _playback = source.play(4, false, Box::new(move |b| tx.send(b.to_vec()).unwrap()));
+        let blocks: Vec<Vec<f32>> = rx.iter().collect();
+        assert_eq!(
+            blocks,
+            vec![
+                samples[..8].to_vec(),
+                samples[8..16].to_vec(),
+                samples[16..].to_vec()
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tee_round_trip_records_what_it_forwards() {
+        let dir = std::env::temp_dir().join(format!("wav-tee-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tee.wav");
+        let (tx, rx) = channel();
+        let recorder = WavRecorder::create(&path, 1, 1000).unwrap();
+        let handler = tee(recorder, Box::new(move |b| tx.send(b.to_vec()).unwrap()));
+        handler(&[0.5, -0.5, 0.25]);
+        handler(&[1., 0.]);
+        drop(handler);
+
+        let forwarded: Vec<f32> = rx.iter().flatten().collect();
+        assert_eq!(forwarded, vec![0.5, -0.5, 0.25, 1., 0.]);
+        let source = WavSource::from_file(&path).unwrap();
+        let recorded: Vec<f32> = source.blocks(8).flatten().copied().collect();
+        assert_eq!(recorded, forwarded);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
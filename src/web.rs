@@ -0,0 +1,146 @@
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::Serialize;
+use tiny_http::{Header, Method, Response, Server};
+
+use crate::config::Shared;
+use crate::visualizer;
+
+#[derive(Serialize)]
+struct Status {
+    fps: f64,
+}
+
+/// Serve the live config and output status as JSON on the given port.
+///
+///   GET  /config  the current config
+///   POST /config  replace the visualizer params
+///   POST /save    write the current config to the config file
+///   GET  /status  current output fps
+pub fn serve(port: u16, shared: Shared, fps: Arc<RwLock<f64>>) -> Result<()> {
+    let server = Server::http(("0.0.0.0", port)).map_err(|e| anyhow!("{}", e))?;
+    thread::spawn(move || {
+        let json: Header = "Content-Type: application/json".parse().unwrap();
+        for mut request in server.incoming_requests() {
+            let mut body = String::new();
+            let (code, body) = match request.as_reader().read_to_string(&mut body) {
+                Ok(_) => handle(request.method(), request.url(), &body, &shared, &fps),
+                Err(e) => (400, error(e)),
+            };
+            let response = Response::from_string(body)
+                .with_status_code(code)
+                .with_header(json.clone());
+            if let Err(e) = request.respond(response) {
+                warn!("failed to respond to http request: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn error<E: std::fmt::Display>(e: E) -> String {
+    serde_json::json!({ "error": e.to_string() }).to_string()
+}
+
+/// Handle a request, returning the status code and JSON body.
+pub fn handle(
+    method: &Method,
+    url: &str,
+    body: &str,
+    shared: &Shared,
+    fps: &RwLock<f64>,
+) -> (u16, String) {
+    let result = match (method, url) {
+        (Method::Get, "/config") => serde_json::to_string(&shared.get()),
+        (Method::Post, "/config") => match serde_json::from_str::<visualizer::Params>(body) {
+            Ok(params) => {
+                if let Err(e) = params.validate() {
+                    return (400, error(e));
+                }
+                *shared.visualizer.write().unwrap() = params;
+                serde_json::to_string(&shared.get())
+            }
+            Err(e) => return (400, error(e)),
+        },
+        (Method::Post, "/save") => match shared.get().save() {
+            Ok(()) => serde_json::to_string(&shared.get()),
+            Err(e) => return (500, error(e)),
+        },
+        (Method::Get, "/status") => serde_json::to_string(&Status {
+            fps: *fps.read().unwrap(),
+        }),
+        _ => return (404, error("not found")),
+    };
+    match result {
+        Ok(body) => (200, body),
+        Err(e) => (500, error(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::Config;
+
+    #[test]
+    fn config_json_round_trip() {
+        let shared = Shared::new(Config::default());
+        let fps = RwLock::new(0.);
+        let (code, body) = handle(&Method::Get, "/config", "", &shared, &fps);
+        assert_eq!(code, 200);
+        let config: Config = serde_json::from_str(&body).unwrap();
+
+        let mut params = config.visualizer;
+        params.set("gamma", &[1.5]).unwrap();
+        let body = serde_json::to_string(&params).unwrap();
+        let (code, body) = handle(&Method::Post, "/config", &body, &shared, &fps);
+        assert_eq!(code, 200);
+        let posted: Config = serde_json::from_str(&body).unwrap();
+        assert_eq!(posted.visualizer.gamma(), 1.5);
+        assert_eq!(shared.visualizer.read().unwrap().gamma(), 1.5);
+    }
+
+    #[test]
+    fn status_json_has_fps() {
+        let shared = Shared::new(Config::default());
+        let fps = RwLock::new(59.5);
+        let (code, body) = handle(&Method::Get, "/status", "", &shared, &fps);
+        assert_eq!(code, 200);
+        let status: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(status["fps"], 59.5);
+    }
+
+    #[test]
+    fn malformed_json_is_rejected() {
+        let shared = Shared::new(Config::default());
+        let fps = RwLock::new(0.);
+        let (code, _) = handle(&Method::Post, "/config", "{", &shared, &fps);
+        assert_eq!(code, 400);
+        assert_eq!(shared.visualizer.read().unwrap().gamma(), 2.);
+    }
+
+    #[test]
+    fn unknown_routes_are_not_found() {
+        let shared = Shared::new(Config::default());
+        let fps = RwLock::new(0.);
+        assert_eq!(handle(&Method::Get, "/nope", "", &shared, &fps).0, 404);
+        assert_eq!(handle(&Method::Delete, "/config", "", &shared, &fps).0, 404);
+    }
+
+    #[test]
+    fn out_of_range_json_params_are_rejected() {
+        let shared = Shared::new(Config::default());
+        let fps = RwLock::new(0.);
+        let mut params = visualizer::Params::defaults();
+        params.set("max_alpha", &[5.]).unwrap();
+        let body = serde_json::to_string(&params).unwrap();
+        let (code, body) = handle(&Method::Post, "/config", &body, &shared, &fps);
+        assert_eq!(code, 400);
+        assert!(body.contains("max_alpha"));
+        assert!(shared.visualizer.read().unwrap().validate().is_ok());
+    }
+}
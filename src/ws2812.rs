@@ -0,0 +1,107 @@
+use crate::apa102::{Apa102Error, Strip, ARGB8};
+
+/// SPI clock which makes one byte on the bus last one 1.25us WS2812 bit period.
+pub const SPI_CLOCK: u32 = 6_400_000;
+
+/// Bus patterns for a 0 bit (~310ns high) and a 1 bit (~780ns high).
+const ZERO: u8 = 0b1100_0000;
+const ONE: u8 = 0b1111_1000;
+
+/// Bytes of low signal needed to latch the strip (>280us).
+const RESET_BYTES: usize = 240;
+
+/// Encode each bit of a color byte, most significant first, as an SPI byte.
+pub fn encode_byte(byte: u8, out: &mut [u8]) {
+    for (i, o) in out[..8].iter_mut().enumerate() {
+        *o = if byte & (0x80 >> i) != 0 { ONE } else { ZERO };
+    }
+}
+
+/// Allocate an encoded buffer for the given number of color bytes.
+pub fn encoded_buffer(bytes: usize) -> Vec<u8> {
+    let mut buffer = vec![0u8; 8 * bytes + RESET_BYTES];
+    for i in 0..bytes {
+        encode_byte(0, &mut buffer[8 * i..]);
+    }
+    buffer
+}
+
+/// Ws2812 LED strip buffer, encoded to be clocked out at `SPI_CLOCK`.
+pub struct Ws2812 {
+    length: usize,
+    buffer: Vec<u8>,
+}
+
+impl Ws2812 {
+    pub fn new(length: u16) -> Self {
+        let length = length as usize;
+        Self {
+            length,
+            buffer: encoded_buffer(3 * length),
+        }
+    }
+
+    pub fn update(&mut self, frame: &[ARGB8]) -> Result<(), Apa102Error> {
+        self.update_from_iter(frame.iter().copied())
+    }
+
+    /// Update from a stream of pixels, so a frame needn't be collected first.
+    pub fn update_from_iter<I: Iterator<Item = ARGB8>>(
+        &mut self,
+        iter: I,
+    ) -> Result<(), Apa102Error> {
+        let mut got = 0;
+        for (i, e) in iter.take(self.length).enumerate() {
+            // there's no global brightness field, so fold it into the color
+            let (r, g, b) = e.scaled_rgb();
+            let idx = 24 * i;
+            encode_byte(g, &mut self.buffer[idx..]);
+            encode_byte(r, &mut self.buffer[idx + 8..]);
+            encode_byte(b, &mut self.buffer[idx + 16..]);
+            got = i + 1;
+        }
+        if got < self.length {
+            return Err(Apa102Error::LengthMismatch {
+                expected: self.length,
+                got,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn get_buffer(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl Strip for Ws2812 {
+    fn get_buffer(&self) -> &[u8] {
+        Ws2812::get_buffer(self)
+    }
+
+    fn update_iter(&mut self, iter: &mut dyn Iterator<Item = ARGB8>) -> Result<(), Apa102Error> {
+        self.update_from_iter(iter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_pixel_encodes_zero_bits() {
+        let mut leds = Ws2812::new(2);
+        leds.update(&[ARGB8::new(31, 0, 0, 0); 2]).unwrap();
+        let buffer = leds.get_buffer();
+        assert_eq!(buffer.len(), 48 + RESET_BYTES);
+        assert!(buffer[..48].iter().all(|&b| b == ZERO));
+        assert!(buffer[48..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn bytes_encode_msb_first() {
+        let mut out = [0; 8];
+        encode_byte(0b1010_0001, &mut out);
+        assert_eq!(out, [ONE, ZERO, ONE, ZERO, ZERO, ZERO, ZERO, ONE]);
+    }
+}
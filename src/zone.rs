@@ -0,0 +1,175 @@
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::apa102::ARGB8;
+use crate::display::{Display, FrameLimiter};
+use crate::pattern::{self, Pattern};
+use crate::visualizer::{Clut, Params};
+
+/// What a zone shows.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Source {
+    Solid((u8, u8, u8)),
+    Breathe { color: (u8, u8, u8), period: f64 },
+    Pattern { pattern: Pattern, speed: f64 },
+}
+
+/// A named, contiguous range of LEDs `start..end` driven independently of
+/// the rest of the strip.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Zone {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+    pub source: Source,
+}
+
+impl Zone {
+    pub fn len(&self) -> usize {
+        self.end.saturating_sub(self.start)
+    }
+
+    /// Render the zone's pixels `t` seconds in.
+    pub fn render(&self, clut: &Clut, t: f64) -> Vec<ARGB8> {
+        match &self.source {
+            Source::Solid((r, g, b)) => vec![ARGB8::new(31, *r, *g, *b); self.len()],
+            Source::Breathe {
+                color: (r, g, b),
+                period,
+            } => {
+                let level = pattern::breathe_level(t, *period);
+                pattern::breathe_frame(ARGB8::new(31, *r, *g, *b), self.len(), level)
+            }
+            Source::Pattern { pattern, speed } => {
+                pattern.render(clut, 1, self.len(), speed * t, 31)
+            }
+        }
+    }
+}
+
+/// Load zones from a YAML list.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Zone>> {
+    let f = std::fs::File::open(path)?;
+    Ok(serde_yaml::from_reader(f)?)
+}
+
+/// Check the zones fit in a strip of `length` without overlapping.
+pub fn validate(zones: &[Zone], length: usize) -> Result<()> {
+    let mut ranges: Vec<&Zone> = zones.iter().collect();
+    ranges.sort_by_key(|z| z.start);
+    for z in &ranges {
+        if z.start >= z.end || z.end > length {
+            return Err(anyhow!(
+                "zone {:?} has invalid range {}..{} for a strip of {}",
+                z.name,
+                z.start,
+                z.end,
+                length
+            ));
+        }
+    }
+    for pair in ranges.windows(2) {
+        if pair[1].start < pair[0].end {
+            return Err(anyhow!(
+                "zones {:?} and {:?} overlap",
+                pair[0].name,
+                pair[1].name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Combine each zone's pixels into one frame of `length`, leaving LEDs
+/// outside every zone off.
+pub fn composite(length: usize, outputs: &[(&Zone, Vec<ARGB8>)]) -> Vec<ARGB8> {
+    let mut frame = vec![ARGB8::new(0, 0, 0, 0); length];
+    for (zone, pixels) in outputs {
+        let end = usize::min(zone.end, length);
+        for (i, pixel) in (zone.start..end).zip(pixels) {
+            frame[i] = *pixel;
+        }
+    }
+    frame
+}
+
+/// Drive every zone at once until the display is closed.
+pub fn run(
+    display: &Display<ARGB8>,
+    zones: &[Zone],
+    params: &Params,
+    length: usize,
+    fps: f64,
+) -> Result<()> {
+    let cluts: Vec<Clut> = zones
+        .iter()
+        .map(|z| {
+            let palette = match &z.source {
+                Source::Pattern { pattern, .. } => pattern.palette(),
+                _ => None,
+            };
+            let palette = palette.unwrap_or_else(|| params.palette().clone());
+            Clut::cached(&palette, params.gamma(), params.color_space())
+        })
+        .collect();
+
+    let mut limiter = FrameLimiter::new(fps);
+    let start = Instant::now();
+    loop {
+        let t = start.elapsed().as_secs_f64();
+        let outputs: Vec<(&Zone, Vec<ARGB8>)> = zones
+            .iter()
+            .zip(&cluts)
+            .map(|(z, clut)| (z, z.render(clut, t)))
+            .collect();
+        display.write_owned(composite(length, &outputs))?;
+        limiter.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::visualizer::{ColorSpace, Palette};
+
+    fn solid(name: &str, start: usize, end: usize, color: (u8, u8, u8)) -> Zone {
+        Zone {
+            name: name.to_string(),
+            start,
+            end,
+            source: Source::Solid(color),
+        }
+    }
+
+    #[test]
+    fn two_solid_zones_composite() {
+        let (red, blue) = (
+            solid("red", 0, 3, (255, 0, 0)),
+            solid("blue", 5, 8, (0, 0, 255)),
+        );
+        let clut = Clut::new(&Palette::Grayscale, 1., ColorSpace::Hsv);
+        let outputs = vec![
+            (&red, red.render(&clut, 0.)),
+            (&blue, blue.render(&clut, 0.)),
+        ];
+        let frame = composite(10, &outputs);
+        let off = ARGB8::new(0, 0, 0, 0);
+        let (r, b) = (ARGB8::new(31, 255, 0, 0), ARGB8::new(31, 0, 0, 255));
+        assert_eq!(frame, vec![r, r, r, off, off, b, b, b, off, off]);
+    }
+
+    #[test]
+    fn overlapping_zones_are_invalid() {
+        let zones = vec![solid("a", 0, 5, (0, 0, 0)), solid("b", 4, 8, (0, 0, 0))];
+        assert!(validate(&zones, 10).is_err());
+        assert!(validate(&zones[..1], 4).is_err());
+        assert!(validate(
+            &[solid("a", 0, 5, (0, 0, 0)), solid("b", 5, 8, (0, 0, 0))],
+            8
+        )
+        .is_ok());
+    }
+}